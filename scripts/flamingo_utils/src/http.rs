@@ -0,0 +1,60 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use reqwest::{Client, ClientBuilder};
+
+const INSECURE_WARNINGS: [&str; 2] = [
+    "!!! TLS certificate verification is DISABLED by --insecure-skip-tls-verify !!!",
+    "!!! Files are downloaded without verifying who serves them, only use this with trusted internal mirrors !!!",
+];
+
+/// Builds the http client, which accepts invalid certificates if
+/// insecure. That is only meant for internal mirrors with self signed
+/// certificates, so it is warned about loudly through warn.
+pub fn build_client(
+    builder: ClientBuilder,
+    insecure: bool,
+    warn: impl Fn(&str),
+) -> Result<Client, String> {
+    if insecure {
+        INSECURE_WARNINGS.iter().for_each(|warning| warn(warning));
+    }
+    builder
+        .danger_accept_invalid_certs(insecure)
+        .build()
+        .map_err(|err| format!("Failed to build http client: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn build(insecure: bool) -> Vec<String> {
+        let warnings = RefCell::new(Vec::new());
+        build_client(Client::builder(), insecure, |warning| {
+            warnings.borrow_mut().push(warning.to_owned())
+        })
+        .unwrap();
+        warnings.into_inner()
+    }
+
+    #[test]
+    fn insecure_clients_are_warned_about() {
+        assert_eq!(build(true), INSECURE_WARNINGS);
+        assert!(build(false).is_empty());
+    }
+}
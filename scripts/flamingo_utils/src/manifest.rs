@@ -0,0 +1,113 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use xml::common::Position;
+use xmltree::{Element, ParseError};
+
+/// Parses xml, reporting the line and column of the problem on failure.
+/// The parser error for an unescaped ampersand is rather cryptic, so it
+/// is explained instead when the parser failed right at or after one.
+pub fn parse_xml(bytes: &[u8]) -> Result<Element, String> {
+    Element::parse(bytes).map_err(|err| match err {
+        ParseError::MalformedXml(err) => {
+            let position = err.position();
+            let (line, column) = (position.row as usize + 1, position.column as usize + 1);
+            match find_unescaped_ampersand(&String::from_utf8_lossy(bytes)) {
+                Some((amp_line, amp_column)) if amp_line == line && amp_column <= column => {
+                    format!(
+                        "line {amp_line}, column {amp_column}: unescaped '&', use &amp; instead"
+                    )
+                }
+                _ => format!("line {line}, column {column}: {}", err.msg()),
+            }
+        }
+        other => other.to_string(),
+    })
+}
+
+/// Returns the line and column of the first ampersand outside of
+/// comments and CDATA that does not start an entity reference.
+fn find_unescaped_ampersand(text: &str) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    while let Some(next_char) = text[offset..].chars().next() {
+        let rest = &text[offset..];
+        if let Some(terminator) = [("<!--", "-->"), ("<![CDATA[", "]]>")]
+            .iter()
+            .find(|(start, _)| rest.starts_with(start))
+            .map(|(_, end)| *end)
+        {
+            offset += rest
+                .find(terminator)
+                .map_or(rest.len(), |end| end + terminator.len());
+            continue;
+        }
+        if next_char == '&' && !is_entity_reference(rest) {
+            let preceding = &text[..offset];
+            let line = preceding.matches('\n').count() + 1;
+            let column = preceding
+                .rsplit('\n')
+                .next()
+                .map_or(0, |line| line.chars().count())
+                + 1;
+            return Some((line, column));
+        }
+        offset += next_char.len_utf8();
+    }
+    None
+}
+
+fn is_entity_reference(text: &str) -> bool {
+    text[1..].split_once(';').is_some_and(|(name, _)| {
+        if let Some(hex) = name.strip_prefix("#x") {
+            !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+        } else if let Some(decimal) = name.strip_prefix('#') {
+            !decimal.is_empty() && decimal.chars().all(|c| c.is_ascii_digit())
+        } else {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescaped_ampersand_is_located() {
+        let xml = "<manifest>\n    <project name=\"a&b\" path=\"a\" />\n</manifest>";
+        assert_eq!(
+            parse_xml(xml.as_bytes()).unwrap_err(),
+            "line 2, column 21: unescaped '&', use &amp; instead"
+        );
+    }
+
+    #[test]
+    fn escaped_ampersands_and_comments_are_fine() {
+        let xml = "<manifest>\n    <!-- a & b -->\n    <project name=\"a&amp;b&#38;c&#x26;\" />\n</manifest>";
+        let manifest = parse_xml(xml.as_bytes()).unwrap();
+        let project = manifest.get_child("project").unwrap();
+        assert_eq!(project.attributes["name"], "a&b&c&");
+    }
+
+    #[test]
+    fn earlier_errors_are_not_masked_by_an_ampersand() {
+        let xml = "<manifest>\n    <remote></project>\n    <project name=\"a&b\" />\n</manifest>";
+        assert_eq!(
+            parse_xml(xml.as_bytes()).unwrap_err(),
+            "line 2, column 22: Unexpected closing tag: project != remote"
+        );
+    }
+}
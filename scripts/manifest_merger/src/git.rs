@@ -15,12 +15,73 @@
  */
 
 use git2::{
-    Cred, Error, ErrorCode, IndexAddOption, PushOptions, Remote, RemoteCallbacks, Repository,
-    RepositoryState,
+    build::CheckoutBuilder, BranchType, Commit, Cred, Direction, Error, ErrorClass, ErrorCode,
+    IndexAddOption, Oid, PushOptions, Remote, RemoteCallbacks, Repository, RepositoryOpenFlags,
+    Signature, Tree,
 };
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 const FLAMINGO_REMOTE: &str = "flamingo";
-const FLAMINGO_BRANCH: &str = "A13";
+pub const FLAMINGO_REMOTE_URL: &str = "ssh://git@github.com/Flamingo-OS";
+pub const FLAMINGO_BRANCH: &str = "A13";
+
+const GPG: &str = "gpg";
+const ENV_GNUPGHOME: &str = "GNUPGHOME";
+
+/// Signs commits with gpg, using the default key
+/// of gpg if no key is specified.
+#[derive(Clone)]
+pub struct Signer {
+    pub key: Option<String>,
+    /// Home dir of gpg, the one gpg picks itself if not specified
+    pub gpg_home: Option<PathBuf>,
+}
+
+impl Signer {
+    /// Creates an armored detached signature of the content.
+    fn sign(&self, content: &str) -> Result<String, Error> {
+        let mut command = Command::new(GPG);
+        command.args(["--batch", "--detach-sign", "--armor"]);
+        if let Some(key) = self.key.as_ref() {
+            command.args(["--local-user", key]);
+        }
+        if let Some(gpg_home) = self.gpg_home.as_ref() {
+            command.env(ENV_GNUPGHOME, gpg_home);
+        }
+        let sign_error = |message: String| {
+            Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Callback,
+                format!("Failed to sign commit with {GPG}: {message}"),
+            )
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| sign_error(err.to_string()))?;
+        child
+            .stdin
+            .take()
+            .ok_or(sign_error(String::from("stdin is not available")))?
+            .write_all(content.as_bytes())
+            .map_err(|err| sign_error(err.to_string()))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|err| sign_error(err.to_string()))?;
+        if !output.status.success() {
+            return Err(sign_error(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ));
+        }
+        String::from_utf8(output.stdout).map_err(|err| sign_error(err.to_string()))
+    }
+}
 
 pub fn get_or_create_remote<'a>(
     repo: &'a Repository,
@@ -39,38 +100,383 @@ pub fn get_or_create_remote<'a>(
     }
 }
 
-pub fn add_and_commit(repository: &Repository, pathspec: &str, message: &str) -> Result<(), Error> {
+/// Opens the manifest repository at path, which may be a symlink or a
+/// worktree of a repository elsewhere as some repo setups have it.
+/// Parent directories are not searched so that a missing manifest
+/// repository is not mistaken for the repository containing it.
+pub fn open_manifest_repo(path: &str) -> Result<Repository, Error> {
+    let open_error = |err: String| {
+        Error::from_str(&format!(
+            "Failed to open manifest repository at {path}: {err}, is the source initialized with repo init?"
+        ))
+    };
+    let resolved_path = fs::canonicalize(path).map_err(|err| open_error(err.to_string()))?;
+    Repository::open_ext(
+        resolved_path,
+        RepositoryOpenFlags::NO_SEARCH,
+        &[] as &[&OsStr],
+    )
+    .map_err(|err| open_error(err.message().to_owned()))
+}
+
+/// Creates a new repository at path with revision fetched from the
+/// remote checked out on the flamingo branch. The flamingo remote is
+/// added with flamingo_url, as repo sync would have, so that the
+/// merge can be pushed.
+pub fn clone_at_revision(
+    path: &str,
+    remote_name: &str,
+    url: &str,
+    revision: &str,
+    flamingo_url: &str,
+) -> Result<Repository, Error> {
+    let repo = Repository::init(path)?;
+    repo.remote(FLAMINGO_REMOTE, flamingo_url)?;
+    {
+        let mut remote = repo.remote(remote_name, url)?;
+        remote.fetch(&[format!("{revision}:{revision}")], None, None)?;
+        let commit = repo.find_reference(revision)?.peel_to_commit()?;
+        repo.branch(FLAMINGO_BRANCH, &commit, false)?;
+    }
+    repo.set_head(&format!("refs/heads/{FLAMINGO_BRANCH}"))?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    Ok(repo)
+}
+
+/// Checks out the local branch with the given name, creating
+/// it from the current HEAD if it does not exist yet.
+pub fn checkout_branch(repository: &Repository, name: &str) -> Result<(), Error> {
+    let branch = match repository.find_branch(name, BranchType::Local) {
+        Ok(branch) => branch,
+        Err(err) if err.code() == ErrorCode::NotFound => {
+            let head_commit = repository.head()?.peel_to_commit()?;
+            repository.branch(name, &head_commit, false)?
+        }
+        Err(err) => return Err(err),
+    };
+    let reference = branch.into_reference();
+    let tree = reference.peel_to_tree()?;
+    repository.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    repository.set_head(&format!("refs/heads/{name}"))
+}
+
+/// Attaches a detached HEAD to the local branch with the given name,
+/// creating the branch at HEAD if it does not exist yet. Fails if the
+/// branch exists but points to another commit.
+pub fn attach_head(repository: &Repository, name: &str) -> Result<(), Error> {
+    let head_commit = repository.head()?.peel_to_commit()?;
+    match repository.find_branch(name, BranchType::Local) {
+        Ok(branch) if branch.get().target() != Some(head_commit.id()) => {
+            return Err(Error::new(
+                ErrorCode::Exists,
+                ErrorClass::Reference,
+                format!("Branch {name} already exists and does not point to the detached HEAD"),
+            ));
+        }
+        Ok(_) => {}
+        Err(err) if err.code() == ErrorCode::NotFound => {
+            repository.branch(name, &head_commit, false)?;
+        }
+        Err(err) => return Err(err),
+    }
+    repository.set_head(&format!("refs/heads/{name}"))
+}
+
+pub fn add_and_commit(
+    repository: &Repository,
+    pathspec: &str,
+    message: &str,
+    signer: Option<&Signer>,
+) -> Result<(), Error> {
     let mut index = repository.index()?;
-    index.add_all(&[pathspec], IndexAddOption::DEFAULT, None)?;
+    index.add_all([pathspec], IndexAddOption::DEFAULT, None)?;
     let oid = index.write_tree()?;
     index.write()?;
-    if repository.state() == RepositoryState::Clean {
+    let parent_commit = repository.head()?.peel_to_commit()?;
+    // Nothing to commit if none of the files changed
+    if parent_commit.tree_id() == oid {
         return Ok(());
     }
     let signature = repository.signature()?;
-    let parent_commit = repository.head()?.peel_to_commit()?;
     let tree = repository.find_tree(oid)?;
-    repository
-        .commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            &message,
-            &tree,
-            &[&parent_commit],
+    commit(
+        repository,
+        &signature,
+        message,
+        &tree,
+        &[&parent_commit],
+        signer,
+    )
+    .map(|_| ())
+}
+
+/// Creates a commit and moves HEAD to it, signing
+/// the commit if a signer is given.
+pub fn commit(
+    repository: &Repository,
+    signature: &Signature,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+    signer: Option<&Signer>,
+) -> Result<Oid, Error> {
+    let signer = match signer {
+        Some(signer) => signer,
+        None => {
+            return repository.commit(Some("HEAD"), signature, signature, message, tree, parents)
+        }
+    };
+    let buffer = repository.commit_create_buffer(signature, signature, message, tree, parents)?;
+    let content = buffer
+        .as_str()
+        .ok_or(Error::from_str("Commit content is not valid utf-8"))?;
+    let gpg_signature = signer.sign(content)?;
+    let oid = repository.commit_signed(content, &gpg_signature, None)?;
+    repository.head()?.set_target(oid, message)?;
+    Ok(oid)
+}
+
+pub fn push(repository: &Repository, branch: &str) -> Result<(), Error> {
+    let repo_path = repository
+        .workdir()
+        .unwrap_or_else(|| repository.path())
+        .display();
+    let target_ref = format!("refs/heads/{branch}");
+    let refspec = format!("HEAD:{target_ref}");
+    let mut remote = repository.find_remote(FLAMINGO_REMOTE).map_err(|err| {
+        Error::new(
+            err.code(),
+            err.class(),
+            format!(
+                "Repository {repo_path} does not have a remote named {FLAMINGO_REMOTE}: {}",
+                err.message()
+            ),
         )
-        .map(|_| ())
+    })?;
+    let mut callbacks = get_remote_callbacks();
+    // Rejected references (ex: non fast-forward) are only reported
+    // through this callback, push itself succeeds in that case.
+    callbacks.push_update_reference(|reference, status| match status {
+        Some(message) => Err(Error::from_str(&format!(
+            "Remote rejected {reference}: {message}"
+        ))),
+        None => Ok(()),
+    });
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(callbacks);
+    remote
+        .push(&[&refspec], Some(&mut push_options))
+        .map_err(|err| {
+            Error::new(
+                err.code(),
+                err.class(),
+                format!(
+                    "Failed to push {refspec} to remote {FLAMINGO_REMOTE} of {repo_path}: {}",
+                    err.message()
+                ),
+            )
+        })?;
+
+    // Some remotes can reject the update silently, so make sure
+    // that the remote reference actually points to what was pushed.
+    check_remote_ref(repository, &mut remote, &target_ref)
+}
+
+/// Fails if the reference of the flamingo remote does not point to HEAD.
+fn check_remote_ref(
+    repository: &Repository,
+    remote: &mut Remote,
+    target_ref: &str,
+) -> Result<(), Error> {
+    let repo_path = repository
+        .workdir()
+        .unwrap_or_else(|| repository.path())
+        .display();
+    let head_oid = repository.head()?.peel_to_commit()?.id();
+    let connection = remote.connect_auth(Direction::Fetch, Some(get_remote_callbacks()), None)?;
+    let remote_oid = connection
+        .list()?
+        .iter()
+        .find(|remote_head| remote_head.name() == target_ref)
+        .map(|remote_head| remote_head.oid());
+    match remote_oid {
+        Some(oid) if oid == head_oid => Ok(()),
+        other => Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Reference,
+            format!(
+                "Pushed {head_oid} to {target_ref} of remote {FLAMINGO_REMOTE} of {repo_path}, but remote has {}",
+                other.map_or(String::from("no such reference"), |oid| oid.to_string())
+            ),
+        )),
+    }
 }
 
-pub fn push(repository: &Repository) -> Result<(), Error> {
+/// Names of the tags of the remote repository at url, without refs/tags/.
+pub fn list_remote_tags(url: &str) -> Result<Vec<String>, Error> {
+    let mut remote = Remote::create_detached(url)?;
+    let connection = remote.connect_auth(Direction::Fetch, Some(get_remote_callbacks()), None)?;
+    let tags = connection
+        .list()?
+        .iter()
+        .filter_map(|remote_head| remote_head.name().strip_prefix("refs/tags/"))
+        // Annotated tags are listed a second time, peeled
+        .filter(|tag| !tag.ends_with("^{}"))
+        .map(|tag| tag.to_owned())
+        .collect();
+    Ok(tags)
+}
+
+fn get_remote_callbacks<'a>() -> RemoteCallbacks<'a> {
     let mut callbacks = RemoteCallbacks::new();
     callbacks.credentials(|_, username_from_url, _| {
-        Cred::ssh_key_from_agent(&username_from_url.unwrap())
+        Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
     });
-    let mut push_options = PushOptions::new();
-    push_options.remote_callbacks(callbacks);
-    repository.find_remote(FLAMINGO_REMOTE)?.push(
-        &[format!("HEAD:refs/heads/{FLAMINGO_BRANCH}")],
-        Some(&mut push_options),
-    )
+    callbacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    #[test]
+    fn push_without_the_flamingo_remote_fails() {
+        let dir = TempDir::new().unwrap();
+        let repo = testing::init_repo(dir.path());
+        testing::commit_file(&repo, "README", "flamingo", "Initial commit");
+
+        let err = push(&repo, FLAMINGO_BRANCH).unwrap_err();
+
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(
+            err.message().starts_with(&format!(
+                "Repository {} does not have a remote named flamingo: ",
+                repo.workdir().unwrap().display()
+            )),
+            "{}",
+            err.message()
+        );
+    }
+
+    #[test]
+    fn remote_ref_not_pointing_to_head_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let repo_path = dir.path().join("repo");
+        let remote_path = dir.path().join("flamingo.git");
+        let repo = testing::init_repo(&repo_path);
+        testing::commit_file(&repo, "README", "flamingo", "Initial commit");
+        testing::bare_clone(&repo_path, &remote_path);
+        repo.remote(FLAMINGO_REMOTE, remote_path.to_str().unwrap())
+            .unwrap();
+        let pushed = testing::commit_file(&repo, "README", "merged", "Merge");
+
+        push(&repo, FLAMINGO_BRANCH).unwrap();
+
+        // A remote that silently kept the previous commit
+        let head = testing::commit_file(&repo, "README", "not pushed", "Update README");
+        let target_ref = format!("refs/heads/{FLAMINGO_BRANCH}");
+        let mut remote = repo.find_remote(FLAMINGO_REMOTE).unwrap();
+        let err = check_remote_ref(&repo, &mut remote, &target_ref).unwrap_err();
+        assert_eq!(
+            err.message(),
+            format!(
+                "Pushed {head} to {target_ref} of remote flamingo of {}, but remote has {pushed}",
+                repo.workdir().unwrap().display()
+            )
+        );
+        let err = check_remote_ref(&repo, &mut remote, "refs/heads/A14").unwrap_err();
+        assert!(err.message().ends_with("but remote has no such reference"));
+    }
+
+    /// Stops the gpg-agent that gpg started for the home dir.
+    struct GpgAgent<'a>(&'a Path);
+
+    impl Drop for GpgAgent<'_> {
+        fn drop(&mut self) {
+            let _ = Command::new("gpgconf")
+                .env(ENV_GNUPGHOME, self.0)
+                .args(["--kill", "gpg-agent"])
+                .status();
+        }
+    }
+
+    #[test]
+    fn signed_commits_carry_a_verifiable_signature() {
+        if Command::new(GPG).arg("--version").output().is_err() {
+            eprintln!(
+                "Skipping signed_commits_carry_a_verifiable_signature, {GPG} is not installed"
+            );
+            return;
+        }
+        let dir = TempDir::new().unwrap();
+        let gnupg_home = dir.path().join("gnupg");
+        fs::create_dir(&gnupg_home).unwrap();
+        let _agent = GpgAgent(&gnupg_home);
+        let gpg = || {
+            let mut command = Command::new(GPG);
+            command
+                .env(ENV_GNUPGHOME, &gnupg_home)
+                .stderr(Stdio::null());
+            command
+        };
+        let key = "Flamingo Test <test@flamingo-os.org>";
+        let status = gpg()
+            .args(["--batch", "--passphrase", "", "--quick-gen-key", key])
+            .args(["ed25519", "sign", "never"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let repo = testing::init_repo(&dir.path().join("repo"));
+        testing::commit_file(&repo, "README", "flamingo", "Initial commit");
+        let signer = Signer {
+            key: Some(key.to_owned()),
+            gpg_home: Some(gnupg_home.to_owned()),
+        };
+
+        fs::write(repo.workdir().unwrap().join("README"), "merged").unwrap();
+        add_and_commit(&repo, "README", "Merge", Some(&signer)).unwrap();
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let (gpg_signature, signed_data) = repo.extract_signature(&head.id(), None).unwrap();
+        let gpg_signature = gpg_signature.as_str().unwrap();
+        assert!(gpg_signature.starts_with("-----BEGIN PGP SIGNATURE-----"));
+        let signature_file = dir.path().join("commit.asc");
+        let data_file = dir.path().join("commit");
+        fs::write(&signature_file, gpg_signature).unwrap();
+        fs::write(&data_file, &*signed_data).unwrap();
+        let status = gpg()
+            .arg("--verify")
+            .args([&signature_file, &data_file])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn symlinked_manifest_dir_opens() {
+        let dir = TempDir::new().unwrap();
+        let manifests = dir.path().join("manifests.git-worktree");
+        let repo = testing::init_repo(&manifests);
+        let head = testing::commit_file(&repo, "default.xml", "<manifest />", "Initial commit");
+        let repo_dir = dir.path().join(".repo");
+        fs::create_dir(&repo_dir).unwrap();
+        let link = repo_dir.join("manifests");
+        std::os::unix::fs::symlink(&manifests, &link).unwrap();
+
+        let opened = open_manifest_repo(link.to_str().unwrap()).unwrap();
+        assert_eq!(opened.head().unwrap().target(), Some(head));
+
+        let missing = dir.path().join("missing");
+        let err = open_manifest_repo(missing.to_str().unwrap()).err().unwrap();
+        assert!(err.message().starts_with(&format!(
+            "Failed to open manifest repository at {}: ",
+            missing.display()
+        )));
+        assert!(err
+            .message()
+            .ends_with("is the source initialized with repo init?"));
+    }
 }
@@ -24,3 +24,14 @@ macro_rules! error {
         }
     };
 }
+
+#[macro_export]
+macro_rules! warn {
+    ( $( $arg:expr ),* ) => {
+        {
+            let fmt_str = format!("Warning: {}", format!($($arg),*));
+            let colored_str = colored::Colorize::yellow(fmt_str.as_str());
+            eprintln!("{colored_str}");
+        }
+    };
+}
@@ -15,25 +15,33 @@
  */
 
 use clap::Parser;
+use flamingo_utils::http;
 use git2::{Error, Repository};
 use manifest::Manifest;
-use merge::merge_aosp;
+use merge::{merge_aosp, MergeConfig, MergeSummary};
 use regex::Regex;
 use reqwest::Client;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fs;
 use std::option::Option;
+use std::path::{Component, Path, PathBuf};
 
 mod git;
 #[macro_use]
 mod macros;
 mod manifest;
 mod merge;
+#[cfg(test)]
+mod testing;
 
 const FLAMINGO_VENDOR: &str = "vendor/flamingo";
 const VERSION_FILE: &str = "target/product/version.mk";
 const MAJOR_VERSION_STR: &str = "FLAMINGO_VERSION_MAJOR";
 const MINOR_VERSION_STR: &str = "FLAMINGO_VERSION_MINOR";
 
+const TAG_PLACEHOLDER: &str = "{tag}";
+
 const MANIFEST_REMOTE_NAME: &str = "flamingo";
 const MANIFEST_REMOTE_URL: &str = "ssh://git@github.com/Flamingo-OS/manifest";
 
@@ -43,9 +51,15 @@ struct Args {
     #[arg(long, default_value_t = String::from("./"))]
     source_dir: String,
 
-    /// Location of the manifest dir
-    #[arg(short, long, default_value_t = String::from("./.repo/manifests"))]
-    mainfest_dir: String,
+    /// Location of the manifest dir. --mainfest-dir is
+    /// a deprecated alias kept for existing scripts
+    #[arg(
+        short,
+        long,
+        alias = "mainfest-dir",
+        default_value_t = String::from("./.repo/manifests")
+    )]
+    manifest_dir: String,
 
     /// CLO system tag that should be merged across the rom
     #[arg(short, long)]
@@ -55,10 +69,47 @@ struct Args {
     #[arg(short, long)]
     vendor_tag: Option<String>,
 
+    /// Use the newest tag of the CLO system manifest matching this glob,
+    /// expanded with --tag-template. * matches anything, ? one character
+    #[arg(long, conflicts_with_all = ["system_tag", "system_tag_file"])]
+    system_tag_glob: Option<String>,
+
+    /// Use the newest tag of the CLO vendor manifest matching this glob,
+    /// expanded with --tag-template. * matches anything, ? one character
+    #[arg(long, conflicts_with_all = ["vendor_tag", "vendor_tag_file"])]
+    vendor_tag_glob: Option<String>,
+
+    /// File to read the system tag from, used if -s is not passed
+    #[arg(long)]
+    system_tag_file: Option<String>,
+
+    /// File to read the vendor tag from, used if -v is not passed
+    #[arg(long)]
+    vendor_tag_file: Option<String>,
+
+    /// Host the CLO manifests are downloaded from
+    #[arg(long, default_value_t = String::from(manifest::DEFAULT_CLO_HOST))]
+    clo_manifest_host: String,
+
+    /// Host of the CLO repos that are merged
+    #[arg(long, default_value_t = String::from(manifest::DEFAULT_CLO_HOST))]
+    clo_remote_host: String,
+
+    /// Template the system and vendor tags are expanded with before
+    /// being used as CLO tags, {tag} is replaced by the passed tag.
+    /// For example LA.QSSI.13.0.r1-{tag}
+    #[arg(long, default_value_t = String::from(TAG_PLACEHOLDER))]
+    tag_template: String,
+
     /// Number of threads to use.
     #[arg(short, long, default_value_t = num_cpus::get())]
     threads: usize,
 
+    /// Maximum number of repos to merge in at the same time, useful
+    /// to limit memory usage with huge repos. Defaults to --threads
+    #[arg(long)]
+    max_parallel_repos: Option<usize>,
+
     /// Whether to push the changes to the remote
     #[arg(short, long, default_value_t = false)]
     push: bool,
@@ -69,33 +120,266 @@ struct Args {
 
     #[arg(long)]
     aosp: bool,
+
+    /// Consider untracked files when checking whether a repo has
+    /// changes after merging, and commit them along with the merge
+    #[arg(long, default_value_t = false)]
+    include_untracked: bool,
+
+    /// Clone repos that are missing in source from upstream at the
+    /// target revision before merging. Slow, missing repos are
+    /// skipped by default
+    #[arg(long, default_value_t = false)]
+    prefetch: bool,
+
+    /// Command to run in every repo after the merge is committed.
+    /// Path of the repo and the tag are passed as arguments and as
+    /// MERGE_REPO_PATH and MERGE_TAG environment variables
+    #[arg(long)]
+    post_merge_hook: Option<String>,
+
+    /// Stop merging remaining repos if the post merge hook fails
+    #[arg(long, default_value_t = false, requires = "post_merge_hook")]
+    abort_on_hook_failure: bool,
+
+    /// Exit with an error instead of warning when there are no repos
+    /// to merge, which usually means the manifest dir or tags are wrong.
+    /// Also fails the unrelated repos found by --merge-base-check
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Exit with an error if any repo was left with conflicts
+    #[arg(long, default_value_t = false)]
+    fail_on_conflict: bool,
+
+    /// File to write the paths of conflicted repos to, one per line
+    #[arg(long)]
+    conflicts_file: Option<String>,
+
+    /// Branch to merge onto in every repo. It is created from the
+    /// current HEAD if missing, and is the branch that gets pushed
+    #[arg(long)]
+    merge_onto: Option<String>,
+
+    /// Sign the created commits with gpg
+    #[arg(long, default_value_t = false)]
+    sign: bool,
+
+    /// Key to sign the commits with. The default key of gpg is used
+    /// if not specified
+    #[arg(long, requires = "sign")]
+    signing_key: Option<String>,
+
+    /// Commit the merged changes with HEAD as the only
+    /// parent instead of creating a merge commit
+    #[arg(long, default_value_t = false)]
+    squash: bool,
+
+    /// Merge in repos with a detached HEAD by attaching it to the
+    /// flamingo branch first. Such repos are skipped by default
+    #[arg(long, default_value_t = false)]
+    attach_detached_head: bool,
+
+    /// Only print warnings, errors and the final summary. Progress of a
+    /// repo is still printed when merging it in fails
+    #[arg(long, default_value_t = false)]
+    quiet_on_success: bool,
+
+    /// Warn about repos that share no history with the upstream repo they
+    /// are merged with, which usually means the remote mapping is wrong.
+    /// Such repos fail to merge with --strict
+    #[arg(long, default_value_t = false)]
+    merge_base_check: bool,
+
+    /// List the conflicted files of every repo that has conflicts
+    /// instead of only how many there are
+    #[arg(long, default_value_t = false)]
+    list_conflicted_files: bool,
+
+    /// Number of times to retry merging in a repo that is locked
+    #[arg(long, default_value_t = 2)]
+    merge_retries: u32,
+
+    /// Do not update the revisions in default.xml, only
+    /// the upstream manifests and the repos are updated
+    #[arg(long, default_value_t = false)]
+    skip_default_update: bool,
+
+    /// File mapping paths in the flamingo manifest to the path or name
+    /// of the repo in the CLO manifest, for repos that were moved or
+    /// renamed. Every line has a path and its mapping separated by
+    /// whitespace, lines starting with # are ignored
+    #[arg(long)]
+    path_map: Option<String>,
+
+    /// Template of the merge commit messages. {tag} is replaced by the
+    /// merged tag, {url} by the url it is merged from and {path} by the
+    /// path of the repo
+    #[arg(long)]
+    message_template: Option<String>,
+
+    /// File mapping repo paths to the template of their merge commit
+    /// message, overriding --message-template. Every line has a path and
+    /// its template separated by whitespace, lines starting with # are
+    /// ignored
+    #[arg(long)]
+    message_map: Option<String>,
+
+    /// Only merge the projects of the flamingo manifest that are in this
+    /// group. Can be passed multiple times to merge projects in any of them
+    #[arg(long)]
+    group: Vec<String>,
+
+    /// Print the current HEAD and the target revision of
+    /// every repo that would be merged, and exit
+    #[arg(long, default_value_t = false)]
+    plan: bool,
+
+    /// Do not verify TLS certificates when downloading the CLO manifests.
+    /// Only for internal mirrors with self signed certificates, INSECURE
+    #[arg(long, default_value_t = false)]
+    insecure_skip_tls_verify: bool,
+
+    /// Download and transform the upstream manifests, write them
+    /// to this dir instead of the manifest dir, and exit
+    #[arg(long)]
+    dump_transformed: Option<String>,
+
+    /// Remove the CLO remotes that merging added to the repos of the
+    /// flamingo manifest, except the ones of the passed tags, and exit
+    #[arg(long, default_value_t = false)]
+    prune_remotes: bool,
+
+    /// Only print the remotes --prune-remotes would remove
+    #[arg(long, default_value_t = false, requires = "prune_remotes")]
+    dry_run: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    // Only runs that commit to the manifest repo need it to exist
+    args.manifest_dir = if args.plan || args.dump_transformed.is_some() {
+        normalize_dir(&args.manifest_dir)
+    } else {
+        canonicalize_dir(&args.manifest_dir)?
+    };
+
+    let system_tag = get_tag(&args.system_tag, &args.system_tag_file)?;
+    let vendor_tag = get_tag(&args.vendor_tag, &args.vendor_tag_file)?;
+
+    if args.prune_remotes {
+        let needed_remotes: Vec<String> = [
+            (
+                "system",
+                system_tag.is_some() || args.system_tag_glob.is_some(),
+            ),
+            (
+                "vendor",
+                vendor_tag.is_some() || args.vendor_tag_glob.is_some(),
+            ),
+        ]
+        .iter()
+        .filter(|(_, needed)| *needed)
+        .map(|(name, _)| Manifest::new(&args.manifest_dir, name, None).get_remote_name())
+        .collect();
+        return merge::prune_remotes(
+            &args.source_dir,
+            Manifest::new(&args.manifest_dir, "flamingo", None),
+            &needed_remotes,
+            args.dry_run,
+        );
+    }
 
-    if !args.system_tag.is_some() && !args.vendor_tag.is_some() {
+    if system_tag.is_none()
+        && vendor_tag.is_none()
+        && args.system_tag_glob.is_none()
+        && args.vendor_tag_glob.is_none()
+    {
         return Err(String::from(
-            "No tags specified. Specify atleast one of -s or -v",
+            "No tags specified. Specify atleast one of -s, -v, their tag files or tag globs",
         ));
     }
 
-    let system_manifest = args
-        .system_tag
-        .as_ref()
-        .map(|tag| Manifest::new(&args.mainfest_dir, "system", Some(tag.to_owned())));
-    let vendor_manifest = args
-        .vendor_tag
-        .as_ref()
-        .map(|tag| Manifest::new(&args.mainfest_dir, "vendor", Some(tag.to_owned())));
+    if args.max_parallel_repos == Some(0) {
+        return Err(String::from("--max-parallel-repos must be at least 1"));
+    }
 
-    if args.aosp && system_manifest.is_some() {
-        merge_aosp(&args.source_dir, &system_manifest, args.threads, args.push)?;
-        return Ok(());
+    if !args.tag_template.contains(TAG_PLACEHOLDER) {
+        return Err(format!(
+            "--tag-template {} does not contain {TAG_PLACEHOLDER}",
+            args.tag_template
+        ));
+    }
+    let system_tag = resolve_tag(&args, "system", system_tag, &args.system_tag_glob)?;
+    let vendor_tag = resolve_tag(&args, "vendor", vendor_tag, &args.vendor_tag_glob)?;
+
+    let system_manifest = get_manifest(&args, "system", &system_tag);
+    let vendor_manifest = get_manifest(&args, "vendor", &vendor_tag);
+
+    let signer = args.sign.then(|| git::Signer {
+        key: args.signing_key.clone(),
+        gpg_home: None,
+    });
+    let merge_config = MergeConfig {
+        push: args.push,
+        include_untracked: args.include_untracked,
+        prefetch: args.prefetch,
+        post_merge_hook: args.post_merge_hook.clone(),
+        abort_on_hook_failure: args.abort_on_hook_failure,
+        merge_onto: args.merge_onto.clone(),
+        signer: signer.clone(),
+        squash: args.squash,
+        attach_detached_head: args.attach_detached_head,
+        max_parallel_repos: args.max_parallel_repos.unwrap_or(args.threads),
+        strict: args.strict,
+        retries: args.merge_retries,
+        merge_base_check: args.merge_base_check,
+        list_conflicted_files: args.list_conflicted_files,
+        quiet_on_success: args.quiet_on_success,
+        groups: args.group.clone(),
+        message_template: args.message_template.clone(),
+        message_map: match args.message_map.as_ref() {
+            Some(file) => merge::read_message_map(file)?,
+            None => HashMap::with_capacity(0),
+        },
+    };
+
+    let path_map = match args.path_map.as_ref() {
+        Some(file) => merge::read_path_map(file)?,
+        None => HashMap::with_capacity(0),
+    };
+
+    let client = http::build_client(
+        Client::builder(),
+        args.insecure_skip_tls_verify,
+        |warning| warn!("{warning}"),
+    )?;
+
+    if let Some(dir) = args.dump_transformed.as_ref() {
+        return dump_transformed(&client, &system_manifest, &vendor_manifest, dir).await;
+    }
+
+    if args.plan {
+        return plan(
+            &client,
+            &args,
+            &system_manifest,
+            &vendor_manifest,
+            &path_map,
+        )
+        .await;
     }
 
-    let client = Client::new();
+    if args.aosp && system_manifest.is_some() {
+        let summary = merge_aosp(
+            &args.source_dir,
+            &system_manifest,
+            args.threads,
+            &merge_config,
+        )?;
+        return report_conflicts(&summary, &args);
+    }
 
     let (system_update, vendor_update) = futures::join!(
         manifest::update(&client, &system_manifest),
@@ -104,62 +388,281 @@ async fn main() -> Result<(), String> {
     system_update?;
     vendor_update?;
 
-    let default_manifest = Manifest::new(&args.mainfest_dir, "default", None);
-    manifest::update_default(
-        default_manifest,
-        &system_manifest,
-        &vendor_manifest,
-        args.push,
-    )?;
+    update_default_manifest(&args, &system_manifest, &vendor_manifest, signer.as_ref())?;
 
-    let flamingo_manifest = Manifest::new(&args.mainfest_dir, "flamingo", None);
-    merge::merge_upstream(
+    let flamingo_manifest = Manifest::new(&args.manifest_dir, "flamingo", None);
+    let summary = merge::merge_upstream(
         &args.source_dir,
         flamingo_manifest,
         &system_manifest,
         &vendor_manifest,
+        &path_map,
         args.threads,
-        args.push,
+        &merge_config,
     )?;
+    report_conflicts(&summary, &args)?;
 
-    if args.set_version.is_some() {
-        let (major, minor) = args
-            .set_version
-            .unwrap()
+    if let Some(version) = args.set_version {
+        let (major, minor) = version
             .split_once('.')
-            .map(|(major, minor)| major.parse::<usize>().ok().zip(minor.parse::<usize>().ok()))
-            .flatten()
+            .and_then(|(major, minor)| major.parse::<usize>().ok().zip(minor.parse::<usize>().ok()))
             .ok_or(String::from("--set-version value is malformed"))?;
-        set_version(major, minor, &args.source_dir, args.push)?;
+        set_version(major, minor, &args.source_dir, args.push, signer.as_ref())?;
     }
 
     update_manifest(
-        &args.mainfest_dir,
-        &args.system_tag,
-        &args.vendor_tag,
+        &args.manifest_dir,
+        &system_tag,
+        &vendor_tag,
         args.push,
+        signer.as_ref(),
     )
     .map_err(|err| format!("Failed to update manifest: {err}"))
 }
 
+/// Points the CLO remotes of default.xml to the new tags unless
+/// --skip-default-update is passed. Returns whether it was updated.
+fn update_default_manifest(
+    args: &Args,
+    system_manifest: &Option<Manifest>,
+    vendor_manifest: &Option<Manifest>,
+    signer: Option<&git::Signer>,
+) -> Result<bool, String> {
+    if args.skip_default_update {
+        return Ok(false);
+    }
+    let default_manifest = Manifest::new(&args.manifest_dir, "default", None);
+    manifest::update_default(
+        default_manifest,
+        system_manifest,
+        vendor_manifest,
+        args.push,
+        signer,
+    )?;
+    Ok(true)
+}
+
+/// Strips trailing slashes, `.` components and the `..` components
+/// that can be resolved from the dir without touching the file system.
+fn normalize_dir(dir: &str) -> String {
+    let mut path = PathBuf::new();
+    for component in Path::new(dir).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(path.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                path.pop();
+            }
+            component => path.push(component),
+        }
+    }
+    match path.to_str() {
+        Some("") => String::from("."),
+        Some(path) => path.to_owned(),
+        None => dir.to_owned(),
+    }
+}
+
+/// Resolves the dir to an absolute path without trailing slashes,
+/// `..` components or symlinks, failing if it does not exist.
+fn canonicalize_dir(dir: &str) -> Result<String, String> {
+    let path = fs::canonicalize(dir).map_err(|err| format!("Invalid dir {dir}: {err}"))?;
+    if !path.is_dir() {
+        return Err(format!("{dir} is not a dir"));
+    }
+    path.to_str()
+        .map(|path| path.to_owned())
+        .ok_or(format!("{dir} is not valid UTF-8"))
+}
+
+/// Returns the tag passed inline, or the one read from the file
+/// with surrounding whitespace trimmed if it was not.
+fn get_tag(tag: &Option<String>, tag_file: &Option<String>) -> Result<Option<String>, String> {
+    if tag.is_some() {
+        return Ok(tag.clone());
+    }
+    tag_file
+        .as_ref()
+        .map(|file| {
+            let content = fs::read_to_string(file)
+                .map_err(|err| format!("Failed to read tag file {file}: {err}"))?;
+            match content.trim() {
+                "" => Err(format!("Tag file {file} is empty")),
+                tag => Ok(tag.to_owned()),
+            }
+        })
+        .transpose()
+}
+
+/// CLO manifest at the tag, if there is one.
+fn get_manifest(args: &Args, name: &str, tag: &Option<String>) -> Option<Manifest> {
+    tag.as_ref().map(|tag| {
+        Manifest::new(&args.manifest_dir, name, Some(tag.to_owned()))
+            .with_clo_hosts(&args.clo_manifest_host, &args.clo_remote_host)
+    })
+}
+
+/// Writes the transformed upstream manifests to dir and nothing else.
+async fn dump_transformed(
+    client: &Client,
+    system_manifest: &Option<Manifest>,
+    vendor_manifest: &Option<Manifest>,
+    dir: &str,
+) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|err| format!("Failed to create {dir}: {err}"))?;
+    let (system_dump, vendor_dump) = futures::join!(
+        manifest::dump(client, system_manifest, dir),
+        manifest::dump(client, vendor_manifest, dir)
+    );
+    system_dump?;
+    vendor_dump
+}
+
+/// Expands the tag with the tag template. If there is no tag the newest
+/// tag of the CLO manifest repo matching the expanded glob is used.
+fn resolve_tag(
+    args: &Args,
+    manifest_name: &str,
+    tag: Option<String>,
+    glob: &Option<String>,
+) -> Result<Option<String>, String> {
+    let expand = |tag: &str| args.tag_template.replace(TAG_PLACEHOLDER, tag);
+    let glob = match (tag, glob) {
+        (Some(tag), _) => return Ok(Some(expand(&tag))),
+        (None, Some(glob)) => expand(glob),
+        (None, None) => return Ok(None),
+    };
+    let url = Manifest::new(&args.manifest_dir, manifest_name, None)
+        .with_clo_hosts(&args.clo_manifest_host, &args.clo_remote_host)
+        .get_manifest_repo_url();
+    let tags = git::list_remote_tags(&url)
+        .map_err(|err| format!("Failed to list tags of {url}: {err}"))?;
+    let tag = find_newest_tag(&tags, &glob)?.ok_or(format!("No tag of {url} matches {glob}"))?;
+    println!("Resolved {manifest_name} tag {glob} to {tag}");
+    Ok(Some(tag))
+}
+
+/// Newest of the tags matching the glob, where * matches any number
+/// of characters and ? a single one. Tags are compared by version,
+/// so numbers in them are compared by value.
+fn find_newest_tag(tags: &[String], glob: &str) -> Result<Option<String>, String> {
+    let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    let regex = Regex::new(&format!("^{pattern}$"))
+        .map_err(|err| format!("Invalid tag glob {glob}: {err}"))?;
+    Ok(tags
+        .iter()
+        .filter(|tag| regex.is_match(tag))
+        .max_by(|a, b| compare_versions(a, b))
+        .cloned())
+}
+
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let chunks = |version: &str| -> Vec<(bool, String)> {
+        let mut chunks: Vec<(bool, String)> = Vec::new();
+        for c in version.chars() {
+            let is_digit = c.is_ascii_digit();
+            match chunks.last_mut() {
+                Some((last_is_digit, chunk)) if *last_is_digit == is_digit => chunk.push(c),
+                _ => chunks.push((is_digit, c.to_string())),
+            }
+        }
+        chunks
+    };
+    chunks(a)
+        .iter()
+        .zip(chunks(b).iter())
+        .map(|((a_is_digit, a), (b_is_digit, b))| {
+            if *a_is_digit && *b_is_digit {
+                let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+                a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+            } else {
+                a.cmp(b)
+            }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+/// Prints the merge plan using freshly downloaded upstream
+/// manifests, without touching any of the repos.
+async fn plan(
+    client: &Client,
+    args: &Args,
+    system_manifest: &Option<Manifest>,
+    vendor_manifest: &Option<Manifest>,
+    path_map: &HashMap<String, String>,
+) -> Result<(), String> {
+    if args.aosp && system_manifest.is_some() {
+        return merge::plan_aosp(&args.source_dir, system_manifest);
+    }
+    let (system_element, vendor_element) = futures::join!(
+        manifest::fetch(client, system_manifest),
+        manifest::fetch(client, vendor_manifest)
+    );
+    let system_repos = system_element?.map(|element| manifest::get_element_repos(&element));
+    let vendor_repos = vendor_element?.map(|element| manifest::get_element_repos(&element));
+    let flamingo_manifest = Manifest::new(&args.manifest_dir, "flamingo", None);
+    merge::plan_upstream(
+        &args.source_dir,
+        flamingo_manifest,
+        system_manifest.as_ref().zip(system_repos.as_ref()),
+        vendor_manifest.as_ref().zip(vendor_repos.as_ref()),
+        path_map,
+        &args.group,
+    )
+}
+
+/// Writes the conflicted repos to the conflicts file if requested, and
+/// fails if the merge was aborted or if there are conflicts and the run
+/// should fail on them.
+fn report_conflicts(summary: &MergeSummary, args: &Args) -> Result<(), String> {
+    if let Some(file) = args.conflicts_file.as_ref() {
+        let content: String = summary
+            .conflicted
+            .iter()
+            .map(|repo| format!("{}\n", repo.name))
+            .collect();
+        fs::write(file, content)
+            .map_err(|err| format!("Failed to write conflicts file {file}: {err}"))?;
+    }
+    if summary.aborted {
+        return Err(String::from("Merge aborted since a post merge hook failed"));
+    }
+    if args.fail_on_conflict && !summary.conflicted.is_empty() {
+        return Err(format!(
+            "{} repos have conflicts: {}",
+            summary.conflicted.len(),
+            summary
+                .conflicted
+                .iter()
+                .map(|repo| repo.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ));
+    }
+    Ok(())
+}
+
 fn update_manifest(
-    mainfest_dir: &str,
+    manifest_dir: &str,
     system_tag: &Option<String>,
     vendor_tag: &Option<String>,
     push: bool,
+    signer: Option<&git::Signer>,
 ) -> Result<(), Error> {
-    let repo = Repository::open(mainfest_dir)?;
+    let repo = git::open_manifest_repo(manifest_dir)?;
     git::get_or_create_remote(&repo, MANIFEST_REMOTE_NAME, MANIFEST_REMOTE_URL)?;
-    let mut message = format!("manifest: upstream with clo\n");
+    let mut message = String::from("manifest: upstream with clo\n");
     if let Some(tag) = system_tag {
         message = format!("{message}\n* system tag: {tag}");
     }
     if let Some(tag) = vendor_tag {
         message = format!("{message}\n* vendor tag: {tag}");
     }
-    git::add_and_commit(&repo, ".", &message)?;
+    git::add_and_commit(&repo, ".", &message, signer)?;
     if push {
-        git::push(&repo)
+        git::push(&repo, git::FLAMINGO_BRANCH)
     } else {
         Ok(())
     }
@@ -170,24 +673,37 @@ fn set_version(
     minor_version: usize,
     source: &str,
     push: bool,
+    signer: Option<&git::Signer>,
 ) -> Result<(), String> {
     let file = format!("{source}/{FLAMINGO_VENDOR}/{VERSION_FILE}");
     let version_file_content =
         fs::read_to_string(&file).map_err(|err| format!("Failed to read version file: {err}"))?;
 
-    let regex = Regex::new(r"FLAMINGO_VERSION_MAJOR\s:=\s\d+").unwrap();
-    let version_file_content = regex.replace(
+    let major_regex = Regex::new(r"FLAMINGO_VERSION_MAJOR\s:=\s(\d+)").unwrap();
+    let minor_regex = Regex::new(r"FLAMINGO_VERSION_MINOR\s:=\s(\d+)").unwrap();
+    let current_version = |regex: &Regex, name: &str| {
+        regex
+            .captures(&version_file_content)
+            .and_then(|captures| captures[1].parse::<usize>().ok())
+            .ok_or(format!("{name} is not set in {file}"))
+    };
+    let current_major_version = current_version(&major_regex, MAJOR_VERSION_STR)?;
+    let current_minor_version = current_version(&minor_regex, MINOR_VERSION_STR)?;
+    if (current_major_version, current_minor_version) == (major_version, minor_version) {
+        println!("Version already at {major_version}.{minor_version}");
+        return Ok(());
+    }
+
+    let version_file_content = major_regex.replace(
         &version_file_content,
         format!("{} := {}", MAJOR_VERSION_STR, major_version),
     );
-
-    let regex = Regex::new(r"FLAMINGO_VERSION_MINOR\s:=\s\d+").unwrap();
-    let version_file_content = regex.replace(
+    let version_file_content = minor_regex.replace(
         &version_file_content,
         format!("{} := {}", MINOR_VERSION_STR, minor_version),
     );
 
-    fs::write(file, version_file_content.to_string())
+    fs::write(&file, version_file_content.to_string())
         .map_err(|err| format!("Failed to set version: {err}"))?;
 
     let repo_path = format!("{source}/{FLAMINGO_VENDOR}");
@@ -197,11 +713,248 @@ fn set_version(
         "flamingo: version: update to {}.{}",
         major_version, minor_version
     );
-    git::add_and_commit(&repo, VERSION_FILE, &message)
+    git::add_and_commit(&repo, VERSION_FILE, &message, signer)
         .map_err(|err| format!("Failed to commit version change: {err}"))?;
     if push {
-        git::push(&repo).map_err(|err| format!("Failed to push {FLAMINGO_VENDOR} repo: {err}"))
+        git::push(&repo, git::FLAMINGO_BRANCH)
+            .map_err(|err| format!("Failed to push {FLAMINGO_VENDOR} repo: {err}"))
     } else {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::ConflictedRepo;
+    use crate::testing;
+    use tempfile::TempDir;
+
+    #[test]
+    fn conflicts_are_written_and_fail_the_run() {
+        let dir = TempDir::new().unwrap();
+        let conflicts_file = dir.path().join("conflicts");
+        let args = Args::parse_from([
+            "manifest_merger",
+            "-s",
+            "1",
+            "--fail-on-conflict",
+            "--conflicts-file",
+            conflicts_file.to_str().unwrap(),
+        ]);
+        let summary = MergeSummary {
+            merged: 2,
+            conflicted: vec![ConflictedRepo {
+                name: String::from("build/make"),
+                files: vec![String::from("README"), String::from("core/Makefile")],
+            }],
+            ..Default::default()
+        };
+
+        let err = report_conflicts(&summary, &args).unwrap_err();
+
+        assert_eq!(err, "1 repos have conflicts: build/make");
+        assert_eq!(fs::read_to_string(&conflicts_file).unwrap(), "build/make\n");
+
+        let args = Args::parse_from([
+            "manifest_merger",
+            "-s",
+            "1",
+            "--conflicts-file",
+            conflicts_file.to_str().unwrap(),
+        ]);
+        assert!(report_conflicts(&summary, &args).is_ok());
+    }
+
+    #[test]
+    fn tags_are_expanded_with_the_tag_template() {
+        let args = Args::parse_from([
+            "manifest_merger",
+            "-s",
+            "1",
+            "--tag-template",
+            "LA.QSSI.13.0.r1-{tag}-qssi.0",
+        ]);
+
+        let tag = resolve_tag(&args, "system", Some(String::from("09800")), &None).unwrap();
+        let manifest = Manifest::new(&args.manifest_dir, "system", tag);
+
+        assert_eq!(
+            manifest.get_url().unwrap(),
+            "https://git.codelinaro.org/clo/la/la/system/manifest/-/raw/LA.QSSI.13.0.r1-09800-qssi.0/LA.QSSI.13.0.r1-09800-qssi.0.xml"
+        );
+        assert_eq!(
+            manifest.get_revision().unwrap(),
+            "refs/tags/LA.QSSI.13.0.r1-09800-qssi.0"
+        );
+    }
+
+    #[test]
+    fn default_manifest_is_left_alone_with_skip_default_update() {
+        let dir = TempDir::new().unwrap();
+        let manifest_dir = dir.path().to_str().unwrap();
+        let default_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+  <remote name="clo_system" fetch="https://git.codelinaro.org/clo/la" revision="refs/tags/LA.QSSI.13.0.r1-09800-qssi.0" />
+</manifest>"#;
+        let repo = testing::init_repo(dir.path());
+        testing::commit_file(&repo, "default.xml", default_xml, "Initial commit");
+        let system_manifest = Some(Manifest::new(
+            manifest_dir,
+            "system",
+            Some(String::from("LA.QSSI.13.0.r1-10000-qssi.0")),
+        ));
+        let parse_args = |extra: &[&str]| {
+            let mut argv = vec!["manifest_merger", "-s", "1", "-m", manifest_dir];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+
+        let args = parse_args(&["--skip-default-update"]);
+        assert!(!update_default_manifest(&args, &system_manifest, &None, None).unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("default.xml")).unwrap(),
+            default_xml
+        );
+
+        let args = parse_args(&[]);
+        assert!(update_default_manifest(&args, &system_manifest, &None, None).unwrap());
+        assert!(fs::read_to_string(dir.path().join("default.xml"))
+            .unwrap()
+            .contains(r#"revision="refs/tags/LA.QSSI.13.0.r1-10000-qssi.0""#));
+    }
+
+    #[test]
+    fn setting_the_current_version_creates_no_commit() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().to_str().unwrap();
+        let repo = testing::init_repo(&dir.path().join(FLAMINGO_VENDOR));
+        let head = testing::commit_file(
+            &repo,
+            VERSION_FILE,
+            "FLAMINGO_VERSION_MAJOR := 1\nFLAMINGO_VERSION_MINOR := 2\n",
+            "Initial commit",
+        );
+        let head_id = || repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        set_version(1, 2, source, false, None).unwrap();
+        assert_eq!(head_id(), head);
+
+        set_version(1, 3, source, false, None).unwrap();
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), head);
+        assert_eq!(commit.message(), Some("flamingo: version: update to 1.3"));
+        assert_eq!(
+            fs::read_to_string(dir.path().join(FLAMINGO_VENDOR).join(VERSION_FILE)).unwrap(),
+            "FLAMINGO_VERSION_MAJOR := 1\nFLAMINGO_VERSION_MINOR := 3\n"
+        );
+    }
+
+    #[test]
+    fn tags_are_read_from_tag_files() {
+        let dir = TempDir::new().unwrap();
+        let system_tag_file = dir.path().join("system_tag");
+        let vendor_tag_file = dir.path().join("vendor_tag");
+        fs::write(&system_tag_file, "  LA.QSSI.13.0.r1-09800-qssi.0\n").unwrap();
+        fs::write(&vendor_tag_file, "LA.UM.11.2.1.r1-04100-sdm660.0\n").unwrap();
+        let args = Args::parse_from([
+            "manifest_merger",
+            "--system-tag-file",
+            system_tag_file.to_str().unwrap(),
+            "--vendor-tag-file",
+            vendor_tag_file.to_str().unwrap(),
+        ]);
+
+        let system_tag = get_tag(&args.system_tag, &args.system_tag_file).unwrap();
+        let vendor_tag = get_tag(&args.vendor_tag, &args.vendor_tag_file).unwrap();
+        let system_manifest = get_manifest(&args, "system", &system_tag).unwrap();
+        let vendor_manifest = get_manifest(&args, "vendor", &vendor_tag).unwrap();
+
+        assert_eq!(
+            system_manifest.get_revision().unwrap(),
+            "refs/tags/LA.QSSI.13.0.r1-09800-qssi.0"
+        );
+        assert_eq!(
+            vendor_manifest.get_revision().unwrap(),
+            "refs/tags/LA.UM.11.2.1.r1-04100-sdm660.0"
+        );
+
+        let inline_tag = Some(String::from("LA.QSSI.13.0.r1-10000-qssi.0"));
+        assert_eq!(
+            get_tag(&inline_tag, &args.system_tag_file).unwrap(),
+            inline_tag
+        );
+
+        fs::write(&system_tag_file, " \n").unwrap();
+        assert_eq!(
+            get_tag(&None, &args.system_tag_file).unwrap_err(),
+            format!("Tag file {} is empty", system_tag_file.to_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn manifest_dirs_are_normalized() {
+        for (dir, normalized) in [
+            ("./.repo/manifests", ".repo/manifests"),
+            (".repo/manifests/", ".repo/manifests"),
+            ("/src/.repo//manifests/", "/src/.repo/manifests"),
+            ("/src/out/../.repo/./manifests", "/src/.repo/manifests"),
+            ("../.repo/manifests", "../.repo/manifests"),
+            ("/src/..", "/"),
+            ("./", "."),
+        ] {
+            assert_eq!(normalize_dir(dir), normalized, "{dir}");
+        }
+    }
+
+    #[test]
+    fn manifest_dirs_are_canonicalized_for_git() {
+        let dir = TempDir::new().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let manifests = root.join(".repo").join("manifests");
+        fs::create_dir_all(&manifests).unwrap();
+        fs::create_dir(root.join("out")).unwrap();
+        std::os::unix::fs::symlink(&manifests, root.join("manifests")).unwrap();
+        let expected = manifests.to_str().unwrap();
+
+        for dir in [
+            format!("{expected}/"),
+            format!("{}/out/../.repo/manifests", root.to_str().unwrap()),
+            format!("{}/manifests", root.to_str().unwrap()),
+        ] {
+            assert_eq!(canonicalize_dir(&dir).unwrap(), expected, "{dir}");
+        }
+
+        let missing = format!("{}/missing", root.to_str().unwrap());
+        assert!(canonicalize_dir(&missing)
+            .unwrap_err()
+            .starts_with(&format!("Invalid dir {missing}: ")));
+    }
+
+    #[test]
+    fn newest_tag_matching_the_glob_is_chosen() {
+        let tags: Vec<String> = [
+            "LA.UM.9.12.r1-13800-SMxx50.0",
+            "LA.UM.11.2.1.r1-04100-sdm660.0",
+            "LA.UM.11.2.1.r1-10200-sdm660.0",
+            "LA.UM.11.2.1.r1-9900-sdm660.0",
+            "LA.UM.11.2.1.r2-20000-sdm660.0",
+            "LA.QSSI.13.0.r1-99999-qssi.0",
+        ]
+        .iter()
+        .map(|tag| tag.to_string())
+        .collect();
+
+        assert_eq!(
+            find_newest_tag(&tags, "LA.UM.*.r1-*").unwrap().unwrap(),
+            "LA.UM.11.2.1.r1-10200-sdm660.0"
+        );
+        assert_eq!(
+            find_newest_tag(&tags, "LA.UM.9.12.r1-?????-SMxx50.0")
+                .unwrap()
+                .unwrap(),
+            "LA.UM.9.12.r1-13800-SMxx50.0"
+        );
+        assert_eq!(find_newest_tag(&tags, "LA.AU.*").unwrap(), None);
+    }
+}
@@ -17,7 +17,7 @@
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 
-use git2::Repository;
+use flamingo_utils::manifest::parse_xml;
 use reqwest::Client;
 use std::collections::HashSet;
 use std::io::{BufReader, Read};
@@ -35,13 +35,47 @@ const ATTR_PATH: &str = "path";
 const ATTR_REMOTE: &str = "remote";
 const ATTR_REVISION: &str = "revision";
 const ATTR_CLONE_DEPTH: &str = "clone-depth";
+const ATTR_GROUPS: &str = "groups";
+
+/// Groups repo puts every project in, in addition to the declared ones.
+/// Projects are in the default group unless they are in notdefault.
+const GROUP_ALL: &str = "all";
+const GROUP_DEFAULT: &str = "default";
+const GROUP_NOT_DEFAULT: &str = "notdefault";
 
 const XML_INDENT: &str = "    ";
 
+pub const DEFAULT_CLO_HOST: &str = "git.codelinaro.org";
+const CLO_PATH: &str = "clo/la";
+pub const CLO_REMOTE_PREFIX: &str = "clo_";
+
+/// A project in a manifest, keyed by its path.
+pub struct Project {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+impl Project {
+    /// Whether the project is in the group, taking the
+    /// implicit all and default groups into account.
+    pub fn is_in_group(&self, group: &str) -> bool {
+        match group {
+            GROUP_ALL => true,
+            GROUP_DEFAULT => !self.groups.iter().any(|group| group == GROUP_NOT_DEFAULT),
+            _ => self
+                .groups
+                .iter()
+                .any(|project_group| project_group == group),
+        }
+    }
+}
+
 pub struct Manifest {
     name: String,
     path: String,
     tag: Option<String>,
+    manifest_host: String,
+    remote_host: String,
 }
 
 impl Manifest {
@@ -50,9 +84,20 @@ impl Manifest {
             name: name.to_owned(),
             path: format!("{dir}/{name}.xml"),
             tag,
+            manifest_host: DEFAULT_CLO_HOST.to_owned(),
+            remote_host: DEFAULT_CLO_HOST.to_owned(),
         }
     }
 
+    /// Uses the given hosts for the manifest download and the remote
+    /// url instead of the default CLO host. A host may include a scheme,
+    /// https is assumed otherwise.
+    pub fn with_clo_hosts(mut self, manifest_host: &str, remote_host: &str) -> Self {
+        self.manifest_host = manifest_host.trim_end_matches('/').to_owned();
+        self.remote_host = remote_host.trim_end_matches('/').to_owned();
+        self
+    }
+
     pub fn get_name(&self) -> String {
         format!("{}.xml", self.name)
     }
@@ -60,26 +105,37 @@ impl Manifest {
     pub fn get_url(&self) -> Option<String> {
         self.tag.as_ref().map(|tag| {
             format!(
-                "https://git.codelinaro.org/clo/la/la/{0}/manifest/-/raw/{1}/{1}.xml",
-                self.name, tag
+                "{0}/{CLO_PATH}/la/{1}/manifest/-/raw/{2}/{2}.xml",
+                get_base_url(&self.manifest_host),
+                self.name,
+                tag
             )
         })
     }
 
+    /// Url of the repository the manifest is downloaded from.
+    pub fn get_manifest_repo_url(&self) -> String {
+        format!(
+            "{}/{CLO_PATH}/la/{}/manifest",
+            get_base_url(&self.manifest_host),
+            self.name
+        )
+    }
+
     pub fn get_remote_name(&self) -> String {
-        format!("clo_{}", self.name)
+        format!("{CLO_REMOTE_PREFIX}{}", self.name)
     }
 
     pub fn get_remote_url(&self) -> String {
-        String::from("https://git.codelinaro.org/clo/la")
+        format!("{}/{CLO_PATH}", get_base_url(&self.remote_host))
     }
 
     pub fn get_aosp_remote_name(&self) -> String {
-        format!("aosp")
+        String::from("aosp")
     }
 
     pub fn get_aosp_remote_url(&self) -> String {
-        format!("https://android.googlesource.com")
+        String::from("https://android.googlesource.com")
     }
 
     pub fn get_revision(&self) -> Option<String> {
@@ -113,18 +169,57 @@ impl Manifest {
     }
 }
 
+fn get_base_url(host: &str) -> String {
+    if host.contains("://") {
+        host.to_owned()
+    } else {
+        format!("https://{host}")
+    }
+}
+
+/// Downloads the manifest without writing it to disk.
+pub async fn fetch(
+    client: &Client,
+    manifest: &Option<Manifest>,
+) -> Result<Option<Element>, String> {
+    match manifest {
+        Some(manifest) => download_manifest(client, manifest)
+            .await
+            .map(Some)
+            .map_err(|err| format!("Failed to get manifest: {}", err)),
+        None => Ok(None),
+    }
+}
+
 pub async fn update(client: &Client, manifest: &Option<Manifest>) -> Result<(), String> {
     let manifest = match manifest {
         Some(manifest) => manifest,
         None => return Ok(()),
     };
-    let xml_manifest = download_manifest(&client, manifest)
+    let xml_manifest = download_manifest(client, manifest)
         .await
         .map_err(|err| format!("Failed to get manifest: {}", err))?;
+    write_element(&xml_manifest, manifest.get_truncated_file()?)
+}
+
+/// Downloads and transforms the manifest like [`update`] does, but
+/// writes it to dir instead of the manifest dir.
+pub async fn dump(client: &Client, manifest: &Option<Manifest>, dir: &str) -> Result<(), String> {
+    let xml_manifest = match fetch(client, manifest).await? {
+        Some(xml_manifest) => xml_manifest,
+        None => return Ok(()),
+    };
+    let path = format!("{dir}/{}.xml", manifest.as_ref().unwrap().name);
+    let file = File::create(&path).map_err(|err| format!("Failed to create {path}: {err}"))?;
+    write_element(&xml_manifest, file)?;
+    println!("Transformed manifest written to {path}");
+    Ok(())
+}
+
+fn write_element(xml_manifest: &Element, file: File) -> Result<(), String> {
     let config = EmitterConfig::new()
         .indent_string(XML_INDENT)
         .perform_indent(true);
-    let file = manifest.get_truncated_file()?;
     xml_manifest
         .write_with_config(file, config)
         .map_err(|err| format!("failed to write manifest: {}", err))
@@ -151,7 +246,7 @@ async fn download_manifest(client: &Client, manifest: &Manifest) -> Result<Eleme
         .await
         .map_err(|err| format!("Failed to get response body: {err}"))?;
     let xml_manifest =
-        Element::parse(&bytes[..]).map_err(|err| format!("Failed to parse manifest: {err}"))?;
+        parse_xml(&bytes[..]).map_err(|err| format!("Failed to parse manifest: {err}"))?;
     Ok(transform_manifest(
         xml_manifest,
         &manifest.get_remote_name(),
@@ -236,33 +331,54 @@ fn read_manifest(manifest: &Manifest) -> Result<Element, String> {
     let bytes_read = reader
         .read_to_end(&mut bytes)
         .map_err(|err| format!("Failed to read {}: {err}", manifest.get_name()))?;
-    Element::parse(&bytes[..bytes_read])
+    parse_xml(&bytes[..bytes_read])
         .map_err(|err| format!("Failed to parse {}: {err}", manifest.get_name()))
 }
 
-pub fn get_repos(manifest: &Manifest) -> Result<HashMap<String, String>, String> {
-    read_manifest(manifest).map(|manifest| {
-        manifest
-            .children
-            .iter()
-            .filter_map(|node| node.as_element())
-            .filter_map(|element| {
-                let attrs = &element.attributes;
-                let mapper = |attr: &String| attr.to_owned();
-                attrs
-                    .get(ATTR_PATH)
-                    .map(mapper)
-                    .zip(attrs.get(ATTR_NAME).map(mapper))
-            })
-            .collect()
-    })
+pub fn get_repos(manifest: &Manifest) -> Result<HashMap<String, Project>, String> {
+    read_manifest(manifest).map(|manifest| get_element_repos(&manifest))
+}
+
+/// Maps the path of every project in the manifest to its name and groups.
+pub fn get_element_repos(manifest: &Element) -> HashMap<String, Project> {
+    manifest
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter_map(|element| {
+            let attrs = &element.attributes;
+            let groups = attrs
+                .get(ATTR_GROUPS)
+                .map(|groups| {
+                    groups
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|group| !group.is_empty())
+                        .map(|group| group.to_owned())
+                        .collect()
+                })
+                .unwrap_or_default();
+            attrs
+                .get(ATTR_PATH)
+                .zip(attrs.get(ATTR_NAME))
+                .map(|(path, name)| {
+                    (
+                        path.to_owned(),
+                        Project {
+                            name: name.to_owned(),
+                            groups,
+                        },
+                    )
+                })
+        })
+        .collect()
 }
 
 pub fn update_default(
     default_manifest: Manifest,
     system_manifest: &Option<Manifest>,
     vendor_manifest: &Option<Manifest>,
-    push: bool
+    push: bool,
+    signer: Option<&git::Signer>,
 ) -> Result<(), String> {
     let mut xml_manifest = read_manifest(&default_manifest)
         .map_err(|err| format!("Failed to parse {}: {err}", default_manifest.get_name()))?;
@@ -274,7 +390,7 @@ pub fn update_default(
         .map(|element| &mut element.attributes)
         .for_each(|attrs| {
             let remote_name = attrs.get(ATTR_NAME).map(|name_str| name_str.to_owned());
-            if remote_name == None {
+            if remote_name.is_none() {
                 error!(
                     "Remote element attributes {:?} does not have key {ATTR_NAME}",
                     attrs
@@ -288,17 +404,15 @@ pub fn update_default(
                     if system_manifest.is_some() {
                         let system_manifest = system_manifest.as_ref().unwrap();
                         if remote_name == system_manifest.get_remote_name() {
-                            let system_revision = system_manifest.get_revision();
-                            if system_revision.is_some() {
-                                *revision = system_revision.unwrap();
+                            if let Some(system_revision) = system_manifest.get_revision() {
+                                *revision = system_revision;
                             }
                         }
                     } else if vendor_manifest.is_some() {
                         let vendor_manifest = vendor_manifest.as_ref().unwrap();
                         if remote_name == vendor_manifest.get_remote_name() {
-                            let vendor_revision = vendor_manifest.get_revision();
-                            if vendor_revision.is_some() {
-                                *revision = vendor_revision.unwrap();
+                            if let Some(vendor_revision) = vendor_manifest.get_revision() {
+                                *revision = vendor_revision;
                             }
                         }
                     }
@@ -311,27 +425,155 @@ pub fn update_default(
     xml_manifest
         .write_with_config(file, config)
         .map_err(|err| format!("failed to write manifest: {}", err))?;
-    let repo = Repository::open(default_manifest.get_repo_path())
-        .map_err(|err| format!("Failed to open manifest repository: {err}"))?;
+    let repo = git::open_manifest_repo(&default_manifest.get_repo_path())
+        .map_err(|err| err.message().to_owned())?;
     if system_manifest.as_ref().is_some() {
         let msg = format!(
             "system: Update default manifest to {}",
             system_manifest.as_ref().unwrap().get_revision().unwrap()
         );
         println!("Committing: {}", msg);
-        git::add_and_commit(&repo, "*", &msg)
+        git::add_and_commit(&repo, "*", &msg, signer)
             .map_err(|err| format!("Failed to commit version change: {err}"))?;
     } else {
         let msg = format!(
             "vendor: Update default manifest to {}",
             vendor_manifest.as_ref().unwrap().get_revision().unwrap()
         );
-        git::add_and_commit(&repo, "*", &msg)
+        git::add_and_commit(&repo, "*", &msg, signer)
             .map_err(|err| format!("Failed to commit version change: {err}"))?;
     }
     if push {
-        git::push(&repo).map_err(|err| format!("Failed to push manifest repo: {err}"))
+        git::push(&repo, git::FLAMINGO_BRANCH)
+            .map_err(|err| format!("Failed to push manifest repo: {err}"))
     } else {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MockClo};
+    use std::fs;
+    use tempfile::TempDir;
+
+    const TAG: &str = "LA.QSSI.13.0.r1-09800-qssi.0";
+
+    #[tokio::test]
+    async fn download_manifest_transforms_the_clo_manifest() {
+        let clo = MockClo::start().await;
+        clo.mock_manifest("system", TAG, testing::CLO_MANIFEST)
+            .await;
+        let manifest = Manifest::new("/nonexistent", "system", Some(TAG.to_owned()))
+            .with_clo_hosts(&clo.host(), &clo.host());
+
+        let xml = download_manifest(&Client::new(), &manifest).await.unwrap();
+
+        let projects: Vec<Vec<(&str, &str)>> = xml
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .map(|element| {
+                element
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            projects,
+            [
+                vec![
+                    ("name", "platform/build"),
+                    ("path", "build/make"),
+                    ("remote", "clo_system"),
+                ],
+                vec![
+                    ("name", "platform/external/zlib"),
+                    ("path", "external/zlib"),
+                    ("remote", "clo_system"),
+                    ("clone-depth", "1"),
+                ],
+                vec![
+                    ("name", "platform/prebuilts/clang"),
+                    ("path", "prebuilts/clang"),
+                    ("clone-depth", "1"),
+                    ("remote", "clo_system"),
+                ],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn download_manifest_fails_for_a_missing_tag() {
+        let clo = MockClo::start().await;
+        clo.mock_manifest("system", TAG, testing::CLO_MANIFEST)
+            .await;
+        let manifest = Manifest::new("/nonexistent", "vendor", Some(TAG.to_owned()))
+            .with_clo_hosts(&clo.host(), &clo.host());
+
+        let err = fetch(&Client::new(), &Some(manifest)).await.unwrap_err();
+
+        assert_eq!(
+            err,
+            format!(
+                "Failed to get manifest: GET request to {}/clo/la/la/vendor/manifest/-/raw/{TAG}/{TAG}.xml failed. Status code = 404",
+                clo.host()
+            )
+        );
+    }
+
+    #[test]
+    fn urls_use_the_overridden_clo_hosts() {
+        let manifest = Manifest::new("/nonexistent", "system", Some(TAG.to_owned()))
+            .with_clo_hosts("clo-mirror.example.com", "http://clo.internal:8080/");
+
+        assert_eq!(
+            manifest.get_url().unwrap(),
+            format!(
+                "https://clo-mirror.example.com/clo/la/la/system/manifest/-/raw/{TAG}/{TAG}.xml"
+            )
+        );
+        assert_eq!(
+            manifest.get_manifest_repo_url(),
+            "https://clo-mirror.example.com/clo/la/la/system/manifest"
+        );
+        assert_eq!(manifest.get_remote_url(), "http://clo.internal:8080/clo/la");
+    }
+
+    #[tokio::test]
+    async fn dump_writes_the_transformed_manifest_to_the_dir() {
+        let clo = MockClo::start().await;
+        clo.mock_manifest("system", TAG, testing::CLO_MANIFEST)
+            .await;
+        let manifest_dir = TempDir::new().unwrap();
+        let dump_dir = TempDir::new().unwrap();
+        let manifest = Manifest::new(
+            manifest_dir.path().to_str().unwrap(),
+            "system",
+            Some(TAG.to_owned()),
+        )
+        .with_clo_hosts(&clo.host(), &clo.host());
+
+        dump(
+            &Client::new(),
+            &Some(manifest),
+            dump_dir.path().to_str().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dump_dir.path().join("system.xml")).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+    <project name="platform/build" path="build/make" remote="clo_system" />
+    <project name="platform/external/zlib" path="external/zlib" remote="clo_system" clone-depth="1" />
+    <project name="platform/prebuilts/clang" path="prebuilts/clang" clone-depth="1" remote="clo_system" />
+</manifest>"#
+        );
+        assert!(!manifest_dir.path().join("system.xml").exists());
+    }
+}
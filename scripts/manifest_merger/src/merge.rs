@@ -16,22 +16,144 @@
 
 use crate::{
     git,
-    manifest::{self, Manifest},
+    manifest::{self, Manifest, Project},
 };
 use git2::{
-    build::CheckoutBuilder, Error, IndexAddOption, MergeOptions, Repository, StatusOptions,
+    build::CheckoutBuilder, Error, ErrorCode, Index, IndexAddOption, MergeOptions, Oid, Repository,
+    StatusOptions,
 };
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Stdout, Write};
 use std::option::Option;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Condvar, Mutex,
+};
+use std::thread;
+use std::time::Duration;
 use threadpool::ThreadPool;
 
+const POST_MERGE_HOOK: &str = "post-merge-hook";
+const ENV_HOOK_REPO_PATH: &str = "MERGE_REPO_PATH";
+const ENV_HOOK_TAG: &str = "MERGE_TAG";
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Placeholders in merge message templates, replaced by the merged
+/// tag, the url it was fetched from and the path of the repo.
+const MESSAGE_TAG: &str = "{tag}";
+const MESSAGE_URL: &str = "{url}";
+const MESSAGE_PATH: &str = "{path}";
+
+/// Options that apply to the merge in every repo.
+#[derive(Clone, Default)]
+pub struct MergeConfig {
+    /// Whether to push the merge to the remote
+    pub push: bool,
+    /// Whether untracked files are taken into account when
+    /// checking if a repo is up-to-date and committed along
+    /// with the merge
+    pub include_untracked: bool,
+    /// Whether repos missing in source should be cloned
+    /// from upstream instead of being skipped
+    pub prefetch: bool,
+    /// Command to run in the repo after a merge is committed
+    pub post_merge_hook: Option<String>,
+    /// Whether a failing post merge hook should stop the run
+    pub abort_on_hook_failure: bool,
+    /// Branch to merge onto and push to instead of the checked out one
+    pub merge_onto: Option<String>,
+    /// Signs the merge commits if set
+    pub signer: Option<git::Signer>,
+    /// Whether the merge is committed with HEAD as the only parent
+    pub squash: bool,
+    /// Whether a detached HEAD is attached to the flamingo
+    /// branch before merging instead of skipping the repo
+    pub attach_detached_head: bool,
+    /// Maximum number of repos merged in at the same time
+    pub max_parallel_repos: usize,
+    /// Whether having nothing to merge, or a repo without common
+    /// history found by the merge base check, is an error
+    pub strict: bool,
+    /// Whether repos are checked for sharing history with upstream before
+    /// merging. Unrelated repos are warned about, or fail if strict
+    pub merge_base_check: bool,
+    /// Whether the conflicted files are listed when a repo has conflicts
+    pub list_conflicted_files: bool,
+    /// Number of times the merge is retried when the repo is locked
+    pub retries: u32,
+    /// Template of the merge commit message for repos not in the
+    /// message map, the default message is used if None
+    pub message_template: Option<String>,
+    /// Maps repo paths to the template of their merge commit message
+    pub message_map: HashMap<String, String>,
+    /// Groups of the flamingo manifest to merge the projects of,
+    /// all projects are merged if empty
+    pub groups: Vec<String>,
+    /// Whether the progress of repos that merge successfully is
+    /// left out, it is still printed for the ones that fail
+    pub quiet_on_success: bool,
+}
+
+/// Outcome of merging in all the repos.
+#[derive(Debug, Default)]
+pub struct MergeSummary {
+    /// Number of repos that were merged in or already up-to-date
+    pub merged: usize,
+    /// Names of the repos that were left as they are, like ones with a detached HEAD
+    pub skipped: Vec<String>,
+    /// Repos that were left with conflicts
+    pub conflicted: Vec<ConflictedRepo>,
+    /// Names of the repos that failed to merge for any other reason
+    pub failed: Vec<String>,
+    /// Names of the repos that do not exist in source and were not cloned
+    pub missing: Vec<String>,
+    /// Whether the remaining repos were skipped since a post merge hook failed
+    pub aborted: bool,
+}
+
+/// Repo the merge left with conflicts.
+#[derive(Debug, PartialEq)]
+pub struct ConflictedRepo {
+    pub name: String,
+    /// Paths of the conflicted files in the repo, sorted
+    pub files: Vec<String>,
+}
+
+impl ConflictedRepo {
+    /// Name of the repo followed by its conflicted files.
+    pub fn describe(&self) -> String {
+        format!("{} ({})", self.name, self.files.join(", "))
+    }
+}
+
+/// How merging in a repo ended, when git did not fail.
+#[derive(Debug)]
+enum MergeOutcome {
+    /// The repo was merged in or already up-to-date
+    Merged,
+    /// The repo does not exist in source and prefetching is disabled
+    Missing,
+    /// The repo was left as it is for this reason
+    Skipped(String),
+    /// The merge left conflicts in these files
+    Conflicted(Vec<String>),
+    /// The merge was committed but the post merge hook failed, so it was not pushed
+    HookFailed(String),
+}
+
 struct MergeData {
     remote_name: String,
     remote_url: String,
+    /// Url of the flamingo remote, added to the repo if it is prefetched
+    flamingo_url: String,
     repo_path: String,
     repo_name: String,
     revision: String,
-    push: bool,
+    config: MergeConfig,
 }
 
 pub fn merge_upstream(
@@ -39,120 +161,603 @@ pub fn merge_upstream(
     flamingo_manifest: Manifest,
     system_manifest: &Option<Manifest>,
     vendor_manifest: &Option<Manifest>,
+    path_map: &HashMap<String, String>,
+    thread_count: usize,
+    config: &MergeConfig,
+) -> Result<MergeSummary, String> {
+    let flamingo_repos = filter_groups(manifest::get_repos(&flamingo_manifest)?, &config.groups);
+    if flamingo_repos.is_empty() {
+        report_nothing_to_merge(
+            &format!(
+                "No projects found in {}, is the manifest dir or group correct?",
+                flamingo_manifest.get_name()
+            ),
+            config,
+        )?;
+    }
+    let system_repos = read_repos(system_manifest)?;
+    let vendor_repos = read_repos(vendor_manifest)?;
+    let merge_datas = get_upstream_merge_datas(
+        source,
+        &flamingo_repos,
+        system_manifest.as_ref().zip(system_repos.as_ref()),
+        vendor_manifest.as_ref().zip(vendor_repos.as_ref()),
+        path_map,
+        config,
+    );
+    if merge_datas.is_empty() && !flamingo_repos.is_empty() {
+        report_nothing_to_merge(
+            &format!(
+                "None of the projects in {} are in the upstream manifests, are the tags correct?",
+                flamingo_manifest.get_name()
+            ),
+            config,
+        )?;
+    }
+    run_merges(merge_datas, thread_count, config.max_parallel_repos)
+}
+
+pub fn merge_aosp(
+    source: &str,
+    system_manifest: &Option<Manifest>,
     thread_count: usize,
-    push: bool,
+    config: &MergeConfig,
+) -> Result<MergeSummary, String> {
+    let system_repos = read_repos(system_manifest)?;
+    let merge_datas = get_aosp_merge_datas(
+        source,
+        system_manifest.as_ref().zip(system_repos.as_ref()),
+        config,
+    );
+    if merge_datas.is_empty() {
+        report_nothing_to_merge("No projects to merge from the system manifest", config)?;
+    }
+    run_merges(merge_datas, thread_count, config.max_parallel_repos)
+}
+
+/// Prints the current HEAD and the target revision of every repo
+/// that would be merged by [`merge_upstream`]. Repos of the upstream
+/// manifests are passed in since the manifests are not updated on disk.
+pub fn plan_upstream(
+    source: &str,
+    flamingo_manifest: Manifest,
+    system: Option<(&Manifest, &HashMap<String, Project>)>,
+    vendor: Option<(&Manifest, &HashMap<String, Project>)>,
+    path_map: &HashMap<String, String>,
+    groups: &[String],
 ) -> Result<(), String> {
-    let flamingo_repos = manifest::get_repos(&flamingo_manifest)?;
-    let system_repos = system_manifest
-        .as_ref()
-        .map_or(Ok(HashMap::with_capacity(0)), |manifest| {
-            manifest::get_repos(manifest)
-        })?;
-    let vendor_repos = vendor_manifest
-        .as_ref()
-        .map_or(Ok(HashMap::with_capacity(0)), |manifest| {
-            manifest::get_repos(manifest)
-        })?;
+    let flamingo_repos = filter_groups(manifest::get_repos(&flamingo_manifest)?, groups);
+    let merge_datas = get_upstream_merge_datas(
+        source,
+        &flamingo_repos,
+        system,
+        vendor,
+        path_map,
+        &MergeConfig::default(),
+    );
+    print_plan(merge_datas);
+    Ok(())
+}
 
-    let thread_pool = ThreadPool::new(thread_count);
+/// Prints the current HEAD and the target revision of every
+/// repo that would be merged by [`merge_aosp`].
+pub fn plan_aosp(source: &str, system_manifest: &Option<Manifest>) -> Result<(), String> {
+    let system_repos = read_repos(system_manifest)?;
+    let merge_datas = get_aosp_merge_datas(
+        source,
+        system_manifest.as_ref().zip(system_repos.as_ref()),
+        &MergeConfig::default(),
+    );
+    print_plan(merge_datas);
+    Ok(())
+}
+
+/// Removes the CLO remotes other than the needed ones from the repos
+/// of the flamingo manifest, or only prints them if dry_run is set.
+pub fn prune_remotes(
+    source: &str,
+    flamingo_manifest: Manifest,
+    needed_remotes: &[String],
+    dry_run: bool,
+) -> Result<(), String> {
+    let mut paths: Vec<String> = manifest::get_repos(&flamingo_manifest)?
+        .into_keys()
+        .collect();
+    paths.sort();
+    let mut pruned_count = 0;
+    for path in paths {
+        let repo_path = format!("{source}/{path}");
+        if !Path::new(&repo_path).exists() {
+            continue;
+        }
+        let repo =
+            Repository::open(&repo_path).map_err(|err| format!("Failed to open {path}: {err}"))?;
+        let remotes = repo
+            .remotes()
+            .map_err(|err| format!("Failed to list remotes of {path}: {err}"))?;
+        let stale_remotes = remotes
+            .iter()
+            .flatten()
+            .filter(|remote| remote.starts_with(manifest::CLO_REMOTE_PREFIX))
+            .filter(|remote| !needed_remotes.iter().any(|needed| needed == remote));
+        for remote in stale_remotes {
+            pruned_count += 1;
+            if dry_run {
+                println!("Would remove remote {remote} from {path}");
+                continue;
+            }
+            println!("Removing remote {remote} from {path}");
+            repo.remote_delete(remote)
+                .map_err(|err| format!("Failed to remove remote {remote} from {path}: {err}"))?;
+        }
+    }
+    if dry_run {
+        println!("{pruned_count} remotes would be removed");
+    } else {
+        println!("Removed {pruned_count} remotes");
+    }
+    Ok(())
+}
+
+/// Warns that nothing will be merged, or fails in strict mode.
+fn report_nothing_to_merge(message: &str, config: &MergeConfig) -> Result<(), String> {
+    if config.strict {
+        return Err(String::from(message));
+    }
+    warn!("{message}");
+    Ok(())
+}
+
+fn read_repos(manifest: &Option<Manifest>) -> Result<Option<HashMap<String, Project>>, String> {
+    manifest.as_ref().map(manifest::get_repos).transpose()
+}
+
+/// Keeps the projects that are in any of the groups,
+/// or all of them if no groups are given.
+fn filter_groups(repos: HashMap<String, Project>, groups: &[String]) -> HashMap<String, Project> {
+    if groups.is_empty() {
+        return repos;
+    }
+    repos
+        .into_iter()
+        .filter(|(_, project)| groups.iter().any(|group| project.is_in_group(group)))
+        .collect()
+}
+
+/// Reads the path map file. Every line maps a path in the flamingo
+/// manifest to the path or name of the repo in the CLO manifest,
+/// separated by whitespace. Empty lines and lines starting with #
+/// are ignored.
+pub fn read_path_map(file: &str) -> Result<HashMap<String, String>, String> {
+    let content =
+        fs::read_to_string(file).map_err(|err| format!("Failed to read path map {file}: {err}"))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [path, upstream] => Ok((path.to_owned(), upstream.to_owned())),
+                _ => Err(format!(
+                    "Line {line_number} of path map {file} is malformed, expected <path> <upstream path or name>"
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Reads the message map file. Every line has the path of a repo
+/// followed by the template of its merge commit message, separated
+/// by whitespace. Empty lines and lines starting with # are ignored.
+pub fn read_message_map(file: &str) -> Result<HashMap<String, String>, String> {
+    let content = fs::read_to_string(file)
+        .map_err(|err| format!("Failed to read message map {file}: {err}"))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            match line.split_once(char::is_whitespace) {
+                Some((path, template)) if !template.trim().is_empty() => {
+                    Ok((path.to_owned(), template.trim().to_owned()))
+                }
+                _ => Err(format!(
+                    "Line {line_number} of message map {file} is malformed, expected <path> <message template>"
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Repos in the flamingo manifest are merged with the system manifest
+/// if they are in it, and with the vendor manifest otherwise. Paths in
+/// the path map are looked up by their mapped path or name instead.
+fn get_upstream_merge_datas(
+    source: &str,
+    flamingo_repos: &HashMap<String, Project>,
+    system: Option<(&Manifest, &HashMap<String, Project>)>,
+    vendor: Option<(&Manifest, &HashMap<String, Project>)>,
+    path_map: &HashMap<String, String>,
+    config: &MergeConfig,
+) -> Vec<MergeData> {
     flamingo_repos
-        .iter()
-        .filter_map(|(path, _)| {
-            if system_manifest.is_some() && system_repos.contains_key(path) {
-                let system_manifest = system_manifest.as_ref().unwrap();
-                Some(MergeData {
-                    remote_name: system_manifest.get_remote_name(),
-                    remote_url: format!(
-                        "{}/{}",
-                        system_manifest.get_remote_url(),
-                        system_repos[path]
-                    ),
-                    repo_path: format!("{}/{}", source, path),
-                    repo_name: path.to_owned(),
-                    revision: system_manifest.get_revision().unwrap(),
-                    push,
+        .keys()
+        .filter_map(|path| {
+            let upstream = path_map.get(path).unwrap_or(path);
+            [system, vendor]
+                .into_iter()
+                .flatten()
+                .find_map(|(manifest, repos)| {
+                    repos
+                        .get(upstream)
+                        .or_else(|| repos.values().find(|project| project.name == *upstream))
+                        .map(|project| (manifest, &project.name))
                 })
-            } else if vendor_manifest.is_some() && vendor_repos.contains_key(path) {
-                let vendor_manifest = vendor_manifest.as_ref().unwrap();
-                Some(MergeData {
-                    remote_name: vendor_manifest.get_remote_name(),
-                    remote_url: format!(
+                .map(|(manifest, name)| MergeData {
+                    remote_name: manifest.get_remote_name(),
+                    remote_url: format!("{}/{}", manifest.get_remote_url(), name),
+                    flamingo_url: format!(
                         "{}/{}",
-                        vendor_manifest.get_remote_url(),
-                        vendor_repos[path]
+                        git::FLAMINGO_REMOTE_URL,
+                        flamingo_repos[path].name
                     ),
                     repo_path: format!("{}/{}", source, path),
                     repo_name: path.to_owned(),
-                    revision: vendor_manifest.get_revision().unwrap(),
-                    push,
+                    revision: manifest.get_revision().unwrap(),
+                    config: config.clone(),
                 })
-            } else {
-                None
-            }
         })
-        .for_each(|merge_data| {
-            thread_pool.execute(|| {
-                let repo_name = merge_data.repo_name.to_owned();
-                if let Err(err) = merge_in_repo(merge_data) {
-                    error!("failed to merge in {repo_name}: {err}");
-                }
-            })
-        });
-    thread_pool.join();
-    Ok(())
+        .collect()
 }
 
-pub fn merge_aosp(
+fn get_aosp_merge_datas(
     source: &str,
-    system_manifest: &Option<Manifest>,
-    thread_count: usize,
-    push: bool,
-) -> Result<(), String> {
-    let system_repos = system_manifest
-        .as_ref()
-        .map_or(Ok(HashMap::with_capacity(0)), |manifest| {
-            manifest::get_repos(manifest)
-        })?;
-    let thread_pool = ThreadPool::new(thread_count);
-    system_repos
-        .iter()
-        .for_each(|(path, _)| {
-            let system_manifest = system_manifest.as_ref().unwrap();
-            if path.contains("external/") || path.contains("prebuilts/") {
+    system: Option<(&Manifest, &HashMap<String, Project>)>,
+    config: &MergeConfig,
+) -> Vec<MergeData> {
+    let (system_manifest, system_repos) = match system {
+        Some(system) => system,
+        None => return Vec::with_capacity(0),
+    };
+    let mut merge_datas = Vec::with_capacity(system_repos.len());
+    system_repos.iter().for_each(|(path, project)| {
+        if path.contains("external/") || path.contains("prebuilts/") {
+            if !config.quiet_on_success {
                 println!("Skipping {}", path);
-                return; // Skip external and prebuilts
             }
-            let merge_data = MergeData {
-                remote_name: system_manifest.get_aosp_remote_name(),
-                remote_url: format!(
-                    "{}/{}",
-                    system_manifest.get_aosp_remote_url(),
-                    system_repos[path]
-                ),
-                repo_path: format!("{}/{}", source, path),
-                repo_name: path.to_owned(),
-                revision: system_manifest.get_revision().unwrap(),
-                push,
+            return; // Skip external and prebuilts
+        }
+        merge_datas.push(MergeData {
+            remote_name: system_manifest.get_aosp_remote_name(),
+            remote_url: format!("{}/{}", system_manifest.get_aosp_remote_url(), project.name),
+            flamingo_url: format!("{}/{}", git::FLAMINGO_REMOTE_URL, path.replace('/', "_")),
+            repo_path: format!("{}/{}", source, path),
+            repo_name: path.to_owned(),
+            revision: system_manifest.get_revision().unwrap(),
+            config: config.clone(),
+        });
+    });
+    merge_datas
+}
+
+fn print_plan(merge_datas: Vec<MergeData>) {
+    println!("Merge plan:");
+    get_plan(merge_datas)
+        .iter()
+        .for_each(|line| println!("{line}"));
+}
+
+/// Lines with the short id of the current HEAD of each repo next
+/// to the revision it would be merged with, sorted by path.
+fn get_plan(mut merge_datas: Vec<MergeData>) -> Vec<String> {
+    merge_datas.sort_by(|a, b| a.repo_name.cmp(&b.repo_name));
+    merge_datas
+        .iter()
+        .map(|merge_data| {
+            let head = if Path::new(&merge_data.repo_path).exists() {
+                get_head_short_id(&merge_data.repo_path)
+                    .unwrap_or_else(|err| format!("unknown ({})", err.message()))
+            } else {
+                String::from("missing")
             };
-            thread_pool.execute(|| {
-                let repo_name = merge_data.repo_name.to_owned();
-                if let Err(err) = merge_in_repo(merge_data) {
+            format!(
+                "{}: {head} -> {}",
+                merge_data.repo_name, merge_data.revision
+            )
+        })
+        .collect()
+}
+
+fn get_head_short_id(repo_path: &str) -> Result<String, Error> {
+    let repo = Repository::open(repo_path)?;
+    let head_commit = repo.head()?.peel_to_commit()?;
+    let short_id = head_commit.as_object().short_id()?;
+    Ok(short_id.as_str().unwrap_or_default().to_owned())
+}
+
+/// Merges in all the repos using a pool of thread_count threads.
+/// Remaining merges are skipped once a post merge hook fails and
+/// the config asks to abort on hook failures, which is marked in
+/// the summary.
+fn run_merges(
+    merge_datas: Vec<MergeData>,
+    thread_count: usize,
+    max_parallel_repos: usize,
+) -> Result<MergeSummary, String> {
+    run_merges_with(
+        merge_datas,
+        thread_count,
+        max_parallel_repos,
+        merge_with_retries,
+    )
+}
+
+/// Same as [`run_merges`], merging in every repo with the function.
+/// At most max_parallel_repos merges run at the same time, however
+/// many threads the pool has.
+fn run_merges_with(
+    merge_datas: Vec<MergeData>,
+    thread_count: usize,
+    max_parallel_repos: usize,
+    merge: fn(&MergeData, &mut RepoLog) -> Result<MergeOutcome, Error>,
+) -> Result<MergeSummary, String> {
+    let thread_pool = ThreadPool::new(thread_count);
+    let semaphore = Arc::new(Semaphore::new(max_parallel_repos));
+    let aborted = Arc::new(AtomicBool::new(false));
+    let summary = Arc::new(Mutex::new(MergeSummary::default()));
+    merge_datas.into_iter().for_each(|merge_data| {
+        let aborted = Arc::clone(&aborted);
+        let summary = Arc::clone(&summary);
+        let semaphore = Arc::clone(&semaphore);
+        thread_pool.execute(move || {
+            let _permit = semaphore.acquire();
+            if aborted.load(Ordering::SeqCst) {
+                return;
+            }
+            let repo_name = merge_data.repo_name.to_owned();
+            let config = &merge_data.config;
+            let mut log = RepoLog::new(config.quiet_on_success);
+            match merge(&merge_data, &mut log) {
+                Ok(MergeOutcome::Merged) => summary.lock().unwrap().merged += 1,
+                Ok(MergeOutcome::Missing) => summary.lock().unwrap().missing.push(repo_name),
+                Ok(MergeOutcome::Skipped(reason)) => {
+                    warn!("{reason}");
+                    summary.lock().unwrap().skipped.push(repo_name);
+                }
+                Ok(MergeOutcome::Conflicted(files)) => {
+                    log.flush();
+                    error!(
+                        "failed to merge in {repo_name}: {}",
+                        describe_conflicts(&repo_name, &files, config.list_conflicted_files)
+                    );
+                    summary.lock().unwrap().conflicted.push(ConflictedRepo {
+                        name: repo_name,
+                        files,
+                    });
+                }
+                Ok(MergeOutcome::HookFailed(message)) => {
+                    log.flush();
+                    error!("failed to merge in {repo_name}: {message}");
+                    if config.abort_on_hook_failure {
+                        aborted.store(true, Ordering::SeqCst);
+                    }
+                    summary.lock().unwrap().failed.push(repo_name);
+                }
+                Err(err) => {
+                    log.flush();
                     error!("failed to merge in {repo_name}: {err}");
+                    summary.lock().unwrap().failed.push(repo_name);
                 }
-            })
-        });
+            }
+        })
+    });
     thread_pool.join();
-    Ok(())
+    let mut summary = std::mem::take(&mut *summary.lock().unwrap());
+    summary.aborted = aborted.load(Ordering::SeqCst);
+    summary.conflicted.sort_by(|a, b| a.name.cmp(&b.name));
+    summary.failed.sort();
+    summary.missing.sort();
+    summary.skipped.sort();
+    println!(
+        "Done with {} repos, {} have conflicts, {} failed, {} were skipped and {} are missing in source",
+        summary.merged,
+        summary.conflicted.len(),
+        summary.failed.len(),
+        summary.skipped.len(),
+        summary.missing.len()
+    );
+    if !summary.conflicted.is_empty() {
+        println!(
+            "Repos with conflicts: {}",
+            summary
+                .conflicted
+                .iter()
+                .map(|repo| repo.describe())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+    if !summary.skipped.is_empty() {
+        println!("Skipped repos: {}", summary.skipped.join(", "));
+    }
+    if !summary.missing.is_empty() {
+        println!(
+            "Skipped repos missing in source, use --prefetch to clone them: {}",
+            summary.missing.join(", ")
+        );
+    }
+    Ok(summary)
+}
+
+/// Counting semaphore bounding how many repos are merged in at the
+/// same time. Merging holds a permit until it is dropped.
+struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
 }
 
-fn merge_in_repo(merge_data: MergeData) -> Result<(), Error> {
-    println!("Merging in {}", &merge_data.repo_name);
-    let repo = Repository::open(&merge_data.repo_path)?;
+struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until a permit is available and takes it.
+    fn acquire(&self) -> Permit<'_> {
+        let mut permits = self
+            .released
+            .wait_while(self.permits.lock().unwrap(), |permits| *permits == 0)
+            .unwrap();
+        *permits -= 1;
+        Permit { semaphore: self }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// Progress of merging in a single repo. It is printed right away,
+/// unless quiet in which case it is held back and only printed
+/// if the merge fails.
+struct RepoLog<W: Write = Stdout> {
+    quiet: bool,
+    lines: Vec<String>,
+    output: W,
+}
+
+impl RepoLog {
+    fn new(quiet: bool) -> Self {
+        Self::with_output(quiet, io::stdout())
+    }
+}
+
+impl<W: Write> RepoLog<W> {
+    fn with_output(quiet: bool, output: W) -> Self {
+        Self {
+            quiet,
+            lines: Vec::new(),
+            output,
+        }
+    }
+
+    fn info(&mut self, line: String) {
+        if self.quiet {
+            self.lines.push(line);
+        } else {
+            self.print(&line);
+        }
+    }
+
+    fn flush(&mut self) {
+        let lines: Vec<String> = self.lines.drain(..).collect();
+        lines.iter().for_each(|line| self.print(line));
+    }
+
+    fn print(&mut self, line: &str) {
+        // Progress is best effort, a closed stdout should not fail the merge
+        let _ = writeln!(self.output, "{line}");
+    }
+}
+
+/// Retries the merge when it fails because of a lock held on the
+/// repo (ex: index.lock), any other error is returned right away.
+fn merge_with_retries(merge_data: &MergeData, log: &mut RepoLog) -> Result<MergeOutcome, Error> {
+    retry_when_locked(merge_data, || merge_in_repo(merge_data, log))
+}
+
+/// Runs the merge again, up to the configured number of retries,
+/// as long as it fails because the repo is locked.
+fn retry_when_locked(
+    merge_data: &MergeData,
+    mut merge: impl FnMut() -> Result<MergeOutcome, Error>,
+) -> Result<MergeOutcome, Error> {
+    let mut attempt = 0;
+    loop {
+        match merge() {
+            Err(err) if err.code() == ErrorCode::Locked && attempt < merge_data.config.retries => {
+                attempt += 1;
+                warn!(
+                    "{} is locked, retrying merge ({attempt}/{}): {}",
+                    merge_data.repo_name,
+                    merge_data.config.retries,
+                    err.message()
+                );
+                if let Ok(repo) = Repository::open(&merge_data.repo_path) {
+                    let _ = repo.cleanup_state();
+                }
+                thread::sleep(RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn merge_in_repo(
+    merge_data: &MergeData,
+    log: &mut RepoLog<impl Write>,
+) -> Result<MergeOutcome, Error> {
+    let repo = if Path::new(&merge_data.repo_path).exists() {
+        Repository::open(&merge_data.repo_path)?
+    } else if merge_data.config.prefetch {
+        log.info(format!(
+            "Cloning {} from {}",
+            &merge_data.repo_name, &merge_data.remote_url
+        ));
+        git::clone_at_revision(
+            &merge_data.repo_path,
+            &merge_data.remote_name,
+            &merge_data.remote_url,
+            &merge_data.revision,
+            &merge_data.flamingo_url,
+        )?
+    } else {
+        log.info(format!(
+            "Skipping {} since it does not exist in source, use --prefetch to clone it",
+            &merge_data.repo_name
+        ));
+        return Ok(MergeOutcome::Missing);
+    };
+    if let Some(branch) = merge_data.config.merge_onto.as_ref() {
+        git::checkout_branch(&repo, branch)?;
+    }
+    if repo.head_detached()? {
+        if !merge_data.config.attach_detached_head {
+            return Ok(MergeOutcome::Skipped(format!(
+                "Skipping {} since HEAD is detached, use --attach-detached-head to merge on {} instead",
+                &merge_data.repo_name,
+                git::FLAMINGO_BRANCH
+            )));
+        }
+        git::attach_head(&repo, git::FLAMINGO_BRANCH)?;
+    }
+    log.info(format!("Merging in {}", &merge_data.repo_name));
     let mut remote =
         git::get_or_create_remote(&repo, &merge_data.remote_name, &merge_data.remote_url)?;
     remote.fetch(&[&merge_data.revision], None, None)?;
     let reference = repo.find_reference(&merge_data.revision)?;
     let annotated_commit = repo.reference_to_annotated_commit(&reference)?;
+    if merge_data.config.merge_base_check && !has_merge_base(&repo, annotated_commit.id())? {
+        let message = format!(
+            "{} has no common history with {}, is the remote mapping correct?",
+            &merge_data.repo_name, &merge_data.remote_url
+        );
+        if merge_data.config.strict {
+            return Err(Error::from_str(&message));
+        }
+        warn!("{message}");
+    }
     repo.merge(
         &[&annotated_commit],
         Some(&mut MergeOptions::default()),
@@ -160,17 +765,23 @@ fn merge_in_repo(merge_data: MergeData) -> Result<(), Error> {
     )?;
     let mut index = repo.index()?;
     if index.has_conflicts() {
-        return Err(Error::from_str(&format!(
-            "Repo {} has conflicts",
-            &merge_data.repo_name
-        )));
+        return Ok(MergeOutcome::Conflicted(get_conflicted_files(&index)?));
+    }
+    if merge_data.config.include_untracked {
+        index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)?;
+    } else {
+        index.update_all(["*"].iter(), None)?;
     }
-    index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)?;
     let oid = index.write_tree()?;
-    let statuses = repo.statuses(Some(&mut StatusOptions::default()))?;
+    let statuses = repo.statuses(Some(
+        StatusOptions::new()
+            .include_untracked(merge_data.config.include_untracked)
+            .include_ignored(false),
+    ))?;
     if statuses.is_empty() {
-        println!("{} is already up-to-date", &merge_data.repo_name);
-        return repo.cleanup_state();
+        log.info(format!("{} is already up-to-date", &merge_data.repo_name));
+        repo.cleanup_state()?;
+        return Ok(MergeOutcome::Merged);
     }
     let signature = repo.signature()?;
     let parent_commit = repo.head()?.peel_to_commit()?;
@@ -182,19 +793,859 @@ fn merge_in_repo(merge_data: MergeData) -> Result<(), Error> {
             "Malformed revision {}",
             merge_data.revision
         )))?;
-    let message = format!("Merge tag '{tag}' of {} into HEAD", remote.url().unwrap());
-    repo.commit(
-        Some("HEAD"),
-        &signature,
+    let upstream_commit = reference.peel_to_commit()?;
+    let url = remote.url().unwrap();
+    let message_template = merge_data
+        .config
+        .message_map
+        .get(&merge_data.repo_name)
+        .or(merge_data.config.message_template.as_ref());
+    let (message, parents) = if merge_data.config.squash {
+        (
+            format!("Squash tag '{tag}' of {url} into HEAD"),
+            vec![&parent_commit],
+        )
+    } else {
+        (
+            format!("Merge tag '{tag}' of {url} into HEAD"),
+            vec![&parent_commit, &upstream_commit],
+        )
+    };
+    let message = match message_template {
+        Some(template) => template
+            .replace(MESSAGE_TAG, tag)
+            .replace(MESSAGE_URL, url)
+            .replace(MESSAGE_PATH, &merge_data.repo_name),
+        None => message,
+    };
+    git::commit(
+        &repo,
         &signature,
         &message,
         &tree,
-        &[&parent_commit],
+        &parents,
+        merge_data.config.signer.as_ref(),
     )?;
     repo.cleanup_state()?;
-    if merge_data.push {
-        git::push(&repo)
+    if let Some(hook) = merge_data.config.post_merge_hook.as_ref() {
+        let repo_path = repo.workdir().unwrap_or_else(|| repo.path());
+        if let Err(message) = run_post_merge_hook(hook, repo_path, tag) {
+            return Ok(MergeOutcome::HookFailed(message));
+        }
+    }
+    if merge_data.config.push {
+        let branch = merge_data
+            .config
+            .merge_onto
+            .as_deref()
+            .unwrap_or(git::FLAMINGO_BRANCH);
+        git::push(&repo, branch)?;
+    }
+    Ok(MergeOutcome::Merged)
+}
+
+/// Message reporting the conflicts of the repo, listing
+/// the conflicted files or only how many there are.
+fn describe_conflicts(repo_name: &str, files: &[String], list_files: bool) -> String {
+    if list_files {
+        format!(
+            "Repo {repo_name} has conflicts in:\n{}",
+            files
+                .iter()
+                .map(|file| format!("    {file}"))
+                .collect::<Vec<String>>()
+                .join("\n")
+        )
     } else {
+        format!("Repo {repo_name} has conflicts in {} files", files.len())
+    }
+}
+
+/// Paths of the conflicted files in the index, sorted.
+fn get_conflicted_files(index: &Index) -> Result<Vec<String>, Error> {
+    let mut files = index
+        .conflicts()?
+        .map(|conflict| {
+            conflict.map(|conflict| {
+                conflict
+                    .our
+                    .or(conflict.their)
+                    .or(conflict.ancestor)
+                    .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+                    .unwrap_or_default()
+            })
+        })
+        .collect::<Result<Vec<String>, Error>>()?;
+    files.sort();
+    Ok(files)
+}
+
+/// Whether HEAD and the commit share any history.
+fn has_merge_base(repo: &Repository, oid: Oid) -> Result<bool, Error> {
+    let head_oid = repo.head()?.peel_to_commit()?.id();
+    match repo.merge_base(head_oid, oid) {
+        Ok(_) => Ok(true),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Runs the hook with sh in the repo directory. Path of the repo and
+/// the merged tag are passed as arguments as well as environment
+/// variables.
+fn run_post_merge_hook(hook: &str, repo_path: &Path, tag: &str) -> Result<(), String> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{hook} \"$@\""))
+        .arg(POST_MERGE_HOOK)
+        .arg(repo_path)
+        .arg(tag)
+        .current_dir(repo_path)
+        .env(ENV_HOOK_REPO_PATH, repo_path)
+        .env(ENV_HOOK_TAG, tag)
+        .status()
+        .map_err(|err| format!("Failed to run post merge hook: {err}"))?;
+    if status.success() {
         Ok(())
+    } else {
+        Err(format!("Post merge hook exited with {status}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::AtomicUsize;
+    use tempfile::TempDir;
+
+    const TAG: &str = "LA.QSSI.13.0.r1-1";
+    const FLAMINGO_URL: &str = "ssh://git@github.com/Flamingo-OS/build_make";
+
+    /// Data to merge the tag of the upstream repository into the repo.
+    fn merge_data(repo_path: &Path, upstream_path: &Path, config: MergeConfig) -> MergeData {
+        MergeData {
+            remote_name: String::from("clo_system"),
+            remote_url: upstream_path.to_str().unwrap().to_owned(),
+            flamingo_url: String::from(FLAMINGO_URL),
+            repo_path: repo_path.to_str().unwrap().to_owned(),
+            repo_name: String::from("build/make"),
+            revision: format!("refs/tags/{TAG}"),
+            config,
+        }
+    }
+
+    /// Commits a new file to the upstream repository
+    /// and moves the tag to it, returning the commit.
+    fn advance_upstream(upstream: &Repository, name: &str) -> Oid {
+        let oid = testing::commit_file(upstream, name, name, &format!("Add {name}"));
+        upstream
+            .reference(&format!("refs/tags/{TAG}"), oid, true, "tag")
+            .unwrap();
+        oid
+    }
+
+    fn head_id(repo: &Repository) -> Oid {
+        repo.head().unwrap().peel_to_commit().unwrap().id()
+    }
+
+    #[test]
+    fn untracked_files_do_not_count_as_changes() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        testing::upstream_repo(&upstream_path, TAG);
+        let repo = testing::clone_repo(&upstream_path, &repo_path);
+        fs::write(repo_path.join("untracked"), "untracked").unwrap();
+        let head = head_id(&repo);
+
+        let data = merge_data(&repo_path, &upstream_path, MergeConfig::default());
+        merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        assert_eq!(head_id(&repo), head);
+
+        let config = MergeConfig {
+            include_untracked: true,
+            ..Default::default()
+        };
+        let data = merge_data(&repo_path, &upstream_path, config);
+        merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_ne!(commit.id(), head);
+        assert!(commit.tree().unwrap().get_name("untracked").is_some());
+    }
+
+    #[test]
+    fn missing_repos_are_cloned_with_prefetch() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream.git");
+        let repo_path = dir.path().join("repo");
+        let commit = {
+            let upstream = testing::upstream_repo(&dir.path().join("work"), TAG);
+            testing::bare_clone(upstream.workdir().unwrap(), &upstream_path);
+            head_id(&upstream)
+        };
+
+        let data = merge_data(&repo_path, &upstream_path, MergeConfig::default());
+        let outcome = merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Missing));
+        assert!(!repo_path.exists());
+
+        let config = MergeConfig {
+            prefetch: true,
+            ..Default::default()
+        };
+        let data = merge_data(&repo_path, &upstream_path, config);
+        let outcome = merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Merged));
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(head_id(&repo), commit);
+        assert_eq!(repo.head().unwrap().shorthand(), Some(git::FLAMINGO_BRANCH));
+        let flamingo_remote = repo.find_remote("flamingo").unwrap();
+        assert_eq!(flamingo_remote.url(), Some(FLAMINGO_URL));
+        assert!(repo.find_remote("clo_system").is_ok());
+    }
+
+    #[test]
+    fn post_merge_hook_runs_in_the_repo() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        testing::clone_repo(&upstream_path, &repo_path);
+        advance_upstream(&upstream, "NEW");
+        let output = dir.path().join("hook_output");
+        let hook = dir.path().join("hook.sh");
+        fs::write(
+            &hook,
+            format!(
+                "{{ pwd; echo \"$1\"; echo \"$2\"; echo \"$MERGE_TAG\"; }} > {}",
+                output.display()
+            ),
+        )
+        .unwrap();
+
+        let config = MergeConfig {
+            post_merge_hook: Some(format!("sh {}", hook.display())),
+            ..Default::default()
+        };
+        let data = merge_data(&repo_path, &upstream_path, config);
+        let outcome = merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+
+        assert!(matches!(outcome, MergeOutcome::Merged));
+        let output = fs::read_to_string(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                repo_path.to_str().unwrap(),
+                &format!("{}/", repo_path.display()),
+                TAG,
+                TAG
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_post_merge_hook_aborts_the_run() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let repo_paths = ["first", "second"].map(|name| dir.path().join(name));
+        repo_paths
+            .iter()
+            .for_each(|repo_path| drop(testing::clone_repo(&upstream_path, repo_path)));
+        advance_upstream(&upstream, "NEW");
+
+        let config = MergeConfig {
+            post_merge_hook: Some(String::from("false")),
+            abort_on_hook_failure: true,
+            ..Default::default()
+        };
+        let merge_datas = repo_paths
+            .iter()
+            .map(|repo_path| merge_data(repo_path, &upstream_path, config.clone()))
+            .collect();
+        let summary = run_merges(merge_datas, 1, 1).unwrap();
+
+        assert!(summary.aborted);
+        assert_eq!(summary.failed, ["build/make"]);
+        assert_eq!(summary.merged, 0);
+    }
+
+    #[test]
+    fn conflicted_repos_are_collected() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let repo = testing::clone_repo(&upstream_path, &repo_path);
+        testing::commit_file(&repo, "README", "flamingo", "Update README");
+        advance_upstream(&upstream, "README");
+
+        let data = merge_data(&repo_path, &upstream_path, MergeConfig::default());
+        let summary = run_merges(vec![data], 1, 1).unwrap();
+
+        assert_eq!(
+            summary.conflicted,
+            [ConflictedRepo {
+                name: String::from("build/make"),
+                files: vec![String::from("README")],
+            }]
+        );
+        assert!(summary.failed.is_empty());
+        assert_eq!(summary.merged, 0);
+    }
+
+    #[test]
+    fn merge_onto_creates_the_branch_and_pushes_it() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        let flamingo_path = dir.path().join("flamingo.git");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let repo = testing::clone_repo(&upstream_path, &repo_path);
+        let flamingo = testing::bare_clone(&upstream_path, &flamingo_path);
+        repo.remote("flamingo", flamingo_path.to_str().unwrap())
+            .unwrap();
+        let head = head_id(&repo);
+        let upstream_commit = advance_upstream(&upstream, "NEW");
+
+        let config = MergeConfig {
+            merge_onto: Some(String::from("integration")),
+            push: true,
+            ..Default::default()
+        };
+        let data = merge_data(&repo_path, &upstream_path, config);
+        merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+
+        assert_eq!(repo.head().unwrap().shorthand(), Some("integration"));
+        let merge_commit = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            merge_commit.parent_ids().collect::<Vec<Oid>>(),
+            [head, upstream_commit]
+        );
+        let flamingo_branch = repo
+            .find_branch(git::FLAMINGO_BRANCH, git2::BranchType::Local)
+            .unwrap();
+        assert_eq!(flamingo_branch.get().target(), Some(head));
+        let pushed = flamingo.find_reference("refs/heads/integration").unwrap();
+        assert_eq!(pushed.target(), Some(merge_commit.id()));
+    }
+
+    #[test]
+    fn plan_lists_current_head_and_target_revision() {
+        let dir = TempDir::new().unwrap();
+        let repo_path = dir.path().join("build/make");
+        let repo = testing::init_repo(&repo_path);
+        let head = testing::commit_file(&repo, "README", "flamingo", "Initial commit");
+        let short_id = repo.find_object(head, None).unwrap().short_id().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let present = merge_data(&repo_path, &upstream_path, MergeConfig::default());
+        let mut missing = merge_data(
+            &dir.path().join("build/soong"),
+            &upstream_path,
+            MergeConfig::default(),
+        );
+        missing.repo_name = String::from("build/soong");
+
+        let plan = get_plan(vec![missing, present]);
+
+        assert_eq!(
+            plan,
+            vec![
+                format!(
+                    "build/make: {} -> refs/tags/{TAG}",
+                    short_id.as_str().unwrap()
+                ),
+                format!("build/soong: missing -> refs/tags/{TAG}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_failing_on_a_locked_index_are_retried() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let repo = testing::clone_repo(&upstream_path, &repo_path);
+        let target = advance_upstream(&upstream, "Android.mk");
+        let config = MergeConfig {
+            retries: 1,
+            ..Default::default()
+        };
+        let data = merge_data(&repo_path, &upstream_path, config);
+        let lock = repo.path().join("index.lock");
+
+        let mut errors = Vec::new();
+        let outcome = retry_when_locked(&data, || {
+            let locked = errors.is_empty();
+            if locked {
+                fs::write(&lock, "").unwrap();
+            }
+            let result = merge_in_repo(&data, &mut RepoLog::new(true));
+            if locked {
+                fs::remove_file(&lock).unwrap();
+                errors.push(result.as_ref().err().map(|err| err.code()));
+            }
+            result
+        })
+        .unwrap();
+
+        assert_eq!(errors, vec![Some(ErrorCode::Locked)]);
+        assert!(matches!(outcome, MergeOutcome::Merged));
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert!(head.parent_ids().any(|parent| parent == target) || head.id() == target);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let dir = TempDir::new().unwrap();
+        let config = MergeConfig {
+            retries: 3,
+            ..Default::default()
+        };
+        let data = merge_data(
+            &dir.path().join("repo"),
+            &dir.path().join("upstream"),
+            config,
+        );
+
+        let mut attempts = 0;
+        let err = retry_when_locked(&data, || {
+            attempts += 1;
+            Err(Error::from_str("Merge conflict"))
+        })
+        .unwrap_err();
+
+        assert_eq!(attempts, 1);
+        assert_eq!(err.message(), "Merge conflict");
+    }
+
+    #[test]
+    fn mapped_paths_use_the_upstream_name() {
+        let dir = TempDir::new().unwrap();
+        let path_map_file = dir.path().join("path_map");
+        fs::write(
+            &path_map_file,
+            "# Moved in CLO\nvendor/qcom/opensource/audio-hal/primary-hal hardware/qcom/audio\n",
+        )
+        .unwrap();
+        let path_map = read_path_map(path_map_file.to_str().unwrap()).unwrap();
+        let project = |name: &str| Project {
+            name: name.to_owned(),
+            groups: Vec::new(),
+        };
+        let flamingo_repos = HashMap::from([
+            (
+                String::from("vendor/qcom/opensource/audio-hal/primary-hal"),
+                project("vendor_qcom_opensource_audio-hal_primary-hal"),
+            ),
+            (String::from("build/make"), project("build_make")),
+        ]);
+        let system_repos = HashMap::from([
+            (
+                String::from("hardware/qcom/audio"),
+                project("platform/hardware/qcom/audio"),
+            ),
+            (String::from("build/make"), project("platform/build")),
+        ]);
+        let system = Manifest::new("/nonexistent", "system", Some(TAG.to_owned()));
+
+        let mut merge_datas = get_upstream_merge_datas(
+            "/source",
+            &flamingo_repos,
+            Some((&system, &system_repos)),
+            None,
+            &path_map,
+            &MergeConfig::default(),
+        );
+        merge_datas.sort_by(|a, b| a.repo_name.cmp(&b.repo_name));
+
+        let remote_urls: Vec<(&str, &str)> = merge_datas
+            .iter()
+            .map(|data| (data.repo_path.as_str(), data.remote_url.as_str()))
+            .collect();
+        assert_eq!(
+            remote_urls,
+            vec![
+                (
+                    "/source/build/make",
+                    "https://git.codelinaro.org/clo/la/platform/build"
+                ),
+                (
+                    "/source/vendor/qcom/opensource/audio-hal/primary-hal",
+                    "https://git.codelinaro.org/clo/la/platform/hardware/qcom/audio"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn repos_with_a_detached_head_are_skipped() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let repo = testing::clone_repo(&upstream_path, &repo_path);
+        let detached = head_id(&repo);
+        repo.set_head_detached(detached).unwrap();
+        let target = advance_upstream(&upstream, "Android.mk");
+
+        let data = merge_data(&repo_path, &upstream_path, MergeConfig::default());
+        let outcome = merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        match outcome {
+            MergeOutcome::Skipped(reason) => assert_eq!(
+                reason,
+                "Skipping build/make since HEAD is detached, use --attach-detached-head to merge on A13 instead"
+            ),
+            outcome => panic!("Expected the repo to be skipped, got {outcome:?}"),
+        }
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(head_id(&repo), detached);
+
+        let config = MergeConfig {
+            attach_detached_head: true,
+            ..Default::default()
+        };
+        let data = merge_data(&repo_path, &upstream_path, config);
+        let outcome = merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Merged));
+        assert!(!repo.head_detached().unwrap());
+        assert_eq!(repo.head().unwrap().shorthand(), Some(git::FLAMINGO_BRANCH));
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert!(head.id() == target || head.parent_ids().any(|parent| parent == target));
+    }
+
+    #[test]
+    fn squashed_merges_have_a_single_parent() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let merged_path = dir.path().join("merged");
+        let squashed_path = dir.path().join("squashed");
+        let merged = testing::clone_repo(&upstream_path, &merged_path);
+        let squashed = testing::clone_repo(&upstream_path, &squashed_path);
+        for repo in [&merged, &squashed] {
+            testing::commit_file(repo, "flamingo.mk", "flamingo", "Add flamingo.mk");
+        }
+        advance_upstream(&upstream, "Android.mk");
+        let local_head = head_id(&squashed);
+
+        let data = merge_data(&merged_path, &upstream_path, MergeConfig::default());
+        merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+        let config = MergeConfig {
+            squash: true,
+            ..Default::default()
+        };
+        let data = merge_data(&squashed_path, &upstream_path, config);
+        merge_in_repo(&data, &mut RepoLog::new(true)).unwrap();
+
+        let merge_commit = merged.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(merge_commit.parent_count(), 2);
+        let squash_commit = squashed.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            squash_commit.parent_ids().collect::<Vec<Oid>>(),
+            [local_head]
+        );
+        assert!(squash_commit
+            .message()
+            .unwrap()
+            .starts_with(&format!("Squash tag '{TAG}' of ")));
+        let tree = squash_commit.tree().unwrap();
+        assert!(tree.get_name("Android.mk").is_some());
+        assert!(tree.get_name("flamingo.mk").is_some());
+    }
+
+    #[test]
+    fn merges_are_limited_to_max_parallel_repos() {
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+        fn counting_merge(_: &MergeData, _: &mut RepoLog) -> Result<MergeOutcome, Error> {
+            let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            Ok(MergeOutcome::Merged)
+        }
+        let merge_datas = (0..16)
+            .map(|index| {
+                let path = Path::new("/nonexistent").join(index.to_string());
+                merge_data(&path, &path, MergeConfig::default())
+            })
+            .collect();
+
+        let summary = run_merges_with(merge_datas, 8, 3, counting_merge).unwrap();
+
+        assert_eq!(summary.merged, 16);
+        assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn empty_flamingo_manifest_fails_in_strict_mode() {
+        let dir = TempDir::new().unwrap();
+        let manifest_dir = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("flamingo.xml"), "<manifest></manifest>").unwrap();
+        let merge = |strict: bool| {
+            let config = MergeConfig {
+                strict,
+                max_parallel_repos: 1,
+                ..Default::default()
+            };
+            merge_upstream(
+                manifest_dir,
+                Manifest::new(manifest_dir, "flamingo", None),
+                &None,
+                &None,
+                &HashMap::new(),
+                1,
+                &config,
+            )
+        };
+
+        let summary = merge(false).unwrap();
+        assert_eq!(summary.merged, 0);
+        assert!(summary.failed.is_empty());
+
+        assert_eq!(
+            merge(true).unwrap_err(),
+            "No projects found in flamingo.xml, is the manifest dir or group correct?"
+        );
+    }
+
+    #[test]
+    fn progress_is_only_printed_for_failed_merges_when_quiet() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let merged_path = dir.path().join("merged");
+        let conflicted_path = dir.path().join("conflicted");
+        testing::clone_repo(&upstream_path, &merged_path);
+        let conflicted = testing::clone_repo(&upstream_path, &conflicted_path);
+        testing::commit_file(&conflicted, "README", "flamingo", "Update README");
+        advance_upstream(&upstream, "README");
+        let config = MergeConfig {
+            quiet_on_success: true,
+            ..Default::default()
+        };
+
+        let data = merge_data(&merged_path, &upstream_path, config.clone());
+        let mut log = RepoLog::with_output(true, Vec::new());
+        let outcome = merge_in_repo(&data, &mut log).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Merged));
+        assert!(log.output.is_empty());
+
+        let data = merge_data(&conflicted_path, &upstream_path, config);
+        let mut log = RepoLog::with_output(true, Vec::new());
+        let files = match merge_in_repo(&data, &mut log).unwrap() {
+            MergeOutcome::Conflicted(files) => files,
+            outcome => panic!("Expected conflicts, got {outcome:?}"),
+        };
+        assert!(log.output.is_empty());
+        log.flush();
+        assert_eq!(
+            String::from_utf8(log.output).unwrap(),
+            "Merging in build/make\n"
+        );
+        assert_eq!(
+            describe_conflicts(&data.repo_name, &files, false),
+            "Repo build/make has conflicts in 1 files"
+        );
+    }
+
+    #[test]
+    fn only_repos_in_the_groups_are_merged() {
+        let dir = TempDir::new().unwrap();
+        let manifest_dir = dir.path().to_str().unwrap();
+        let source = dir.path().join("source");
+        let projects = r#"
+            <project name="build_make" path="build/make" />
+            <project name="hardware_qcom_audio" path="hardware/qcom/audio" groups="pdk,qcom" />
+            <project name="device_qcom_sepolicy" path="device/qcom/sepolicy" groups="qcom notdefault" />"#;
+        fs::write(
+            dir.path().join("flamingo.xml"),
+            format!("<manifest>{projects}</manifest>"),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("system.xml"),
+            format!("<manifest>{projects}</manifest>"),
+        )
+        .unwrap();
+        let system = Some(Manifest::new(manifest_dir, "system", Some(TAG.to_owned())));
+        // None of the repos exist in source, so all the merged ones are missing
+        let merged_repos = |groups: &[&str]| {
+            let config = MergeConfig {
+                groups: groups.iter().map(|group| group.to_string()).collect(),
+                max_parallel_repos: 1,
+                ..Default::default()
+            };
+            merge_upstream(
+                source.to_str().unwrap(),
+                Manifest::new(manifest_dir, "flamingo", None),
+                &system,
+                &None,
+                &HashMap::new(),
+                1,
+                &config,
+            )
+            .unwrap()
+            .missing
+        };
+
+        assert_eq!(
+            merged_repos(&["qcom"]),
+            ["device/qcom/sepolicy", "hardware/qcom/audio"]
+        );
+        assert_eq!(
+            merged_repos(&["default"]),
+            ["build/make", "hardware/qcom/audio"]
+        );
+        assert_eq!(merged_repos(&["pdk", "notdefault"]).len(), 2);
+        assert_eq!(merged_repos(&[]).len(), 3);
+    }
+
+    #[test]
+    fn message_map_overrides_the_message_of_its_repos() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let message_map = dir.path().join("message_map");
+        fs::write(
+            &message_map,
+            "# kernel repos need the ANDROID: prefix\nkernel/msm ANDROID: Merge {tag} into {path}\n",
+        )
+        .unwrap();
+        let config = MergeConfig {
+            message_template: Some(String::from("Merge {tag} into {path}")),
+            message_map: read_message_map(message_map.to_str().unwrap()).unwrap(),
+            ..Default::default()
+        };
+        let kernel_path = dir.path().join("kernel");
+        let build_path = dir.path().join("build");
+        let kernel = testing::clone_repo(&upstream_path, &kernel_path);
+        let build = testing::clone_repo(&upstream_path, &build_path);
+        for repo in [&kernel, &build] {
+            testing::commit_file(repo, "flamingo.mk", "flamingo", "Add flamingo.mk");
+        }
+        advance_upstream(&upstream, "Android.mk");
+
+        let mut kernel_data = merge_data(&kernel_path, &upstream_path, config.clone());
+        kernel_data.repo_name = String::from("kernel/msm");
+        merge_in_repo(&kernel_data, &mut RepoLog::new(true)).unwrap();
+        let build_data = merge_data(&build_path, &upstream_path, config);
+        merge_in_repo(&build_data, &mut RepoLog::new(true)).unwrap();
+
+        let message = |repo: &Repository| {
+            let commit = repo.head().unwrap().peel_to_commit().unwrap();
+            commit.message().unwrap().to_owned()
+        };
+        assert_eq!(
+            message(&kernel),
+            format!("ANDROID: Merge {TAG} into kernel/msm")
+        );
+        assert_eq!(message(&build), format!("Merge {TAG} into build/make"));
+    }
+
+    #[test]
+    fn unrelated_histories_fail_the_merge_in_strict_mode() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        testing::upstream_repo(&upstream_path, TAG);
+        let repo_path = dir.path().join("repo");
+        let merge = |strict: bool| {
+            let repo = testing::init_repo(&repo_path);
+            testing::commit_file(&repo, "flamingo.mk", "flamingo", "Add flamingo.mk");
+            let config = MergeConfig {
+                merge_base_check: true,
+                strict,
+                ..Default::default()
+            };
+            let data = merge_data(&repo_path, &upstream_path, config);
+            merge_in_repo(&data, &mut RepoLog::new(true))
+        };
+
+        let err = merge(true).unwrap_err();
+        assert_eq!(
+            err.message(),
+            format!(
+                "build/make has no common history with {}, is the remote mapping correct?",
+                upstream_path.to_str().unwrap()
+            )
+        );
+
+        fs::remove_dir_all(&repo_path).unwrap();
+        assert!(matches!(merge(false).unwrap(), MergeOutcome::Merged));
+    }
+
+    #[test]
+    fn every_conflicted_file_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let upstream_path = dir.path().join("upstream");
+        let repo_path = dir.path().join("repo");
+        let upstream = testing::upstream_repo(&upstream_path, TAG);
+        let repo = testing::clone_repo(&upstream_path, &repo_path);
+        for file in ["README", "core/Makefile", "Android.bp"] {
+            testing::commit_file(&repo, file, "flamingo", &format!("Update {file}"));
+            advance_upstream(&upstream, file);
+        }
+        testing::commit_file(&repo, "flamingo.mk", "flamingo", "Add flamingo.mk");
+
+        let data = merge_data(&repo_path, &upstream_path, MergeConfig::default());
+        let summary = run_merges(vec![data], 1, 1).unwrap();
+
+        assert_eq!(summary.conflicted.len(), 1);
+        let conflicted = &summary.conflicted[0];
+        assert_eq!(conflicted.files, ["Android.bp", "README", "core/Makefile"]);
+        assert_eq!(
+            conflicted.describe(),
+            "build/make (Android.bp, README, core/Makefile)"
+        );
+        assert_eq!(
+            describe_conflicts(&conflicted.name, &conflicted.files, true),
+            "Repo build/make has conflicts in:\n    Android.bp\n    README\n    core/Makefile"
+        );
+    }
+
+    #[test]
+    fn stale_clo_remotes_are_pruned() {
+        let dir = TempDir::new().unwrap();
+        let manifest_dir = dir.path().to_str().unwrap();
+        let source = dir.path().join("source");
+        fs::write(
+            dir.path().join("flamingo.xml"),
+            r#"<manifest><project name="build_make" path="build/make" /></manifest>"#,
+        )
+        .unwrap();
+        let repo = testing::init_repo(&source.join("build/make"));
+        for remote in ["origin", "clo_system", "clo_vendor", "clo_old"] {
+            repo.remote(remote, "https://git.codelinaro.org/clo/la/platform/build")
+                .unwrap();
+        }
+        let needed_remotes = [String::from("clo_system")];
+        let prune = |dry_run: bool| {
+            prune_remotes(
+                source.to_str().unwrap(),
+                Manifest::new(manifest_dir, "flamingo", None),
+                &needed_remotes,
+                dry_run,
+            )
+            .unwrap();
+            let mut remotes: Vec<String> = repo
+                .remotes()
+                .unwrap()
+                .iter()
+                .flatten()
+                .map(|remote| remote.to_owned())
+                .collect();
+            remotes.sort();
+            remotes
+        };
+
+        assert_eq!(
+            prune(true),
+            ["clo_old", "clo_system", "clo_vendor", "origin"]
+        );
+        assert_eq!(prune(false), ["clo_system", "origin"]);
     }
 }
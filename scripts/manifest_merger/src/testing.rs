@@ -0,0 +1,141 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use git2::{build::RepoBuilder, Oid, Repository, RepositoryInitOptions, Signature};
+use std::{fs, path::Path};
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Manifest as CLO serves it, with the elements and
+/// attributes the transform is expected to drop.
+pub const CLO_MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+    <remote name="caf" fetch=".." />
+    <default revision="refs/tags/LA.QSSI.13.0.r1-1" remote="caf" />
+    <project name="platform/build" path="build/make" revision="abc" groups="pdk" />
+    <project name="platform/external/zlib" path="external/zlib" />
+    <project name="platform/prebuilts/clang" path="prebuilts/clang" clone-depth="2" />
+</manifest>
+"#;
+
+/// Mock of the CLO host manifests are downloaded from. Anything
+/// it was not told about responds with 404.
+pub struct MockClo {
+    pub server: MockServer,
+}
+
+impl MockClo {
+    pub async fn start() -> Self {
+        Self {
+            server: MockServer::start().await,
+        }
+    }
+
+    /// Host to pass to [`crate::manifest::Manifest::with_clo_hosts`].
+    pub fn host(&self) -> String {
+        self.server.uri()
+    }
+
+    /// Serves the content as the manifest named name at the tag.
+    pub async fn mock_manifest(&self, name: &str, tag: &str, content: &str) {
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "/clo/la/la/{name}/manifest/-/raw/{tag}/{tag}.xml"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_string(content))
+            .mount(&self.server)
+            .await;
+    }
+}
+
+/// Initializes a repository at path with HEAD on the flamingo
+/// branch and a committer, without any commits.
+pub fn init_repo(path: &Path) -> Repository {
+    let repo = Repository::init_opts(
+        path,
+        RepositoryInitOptions::new().initial_head(crate::git::FLAMINGO_BRANCH),
+    )
+    .unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Flamingo").unwrap();
+    config
+        .set_str("user.email", "flamingo@example.com")
+        .unwrap();
+    repo
+}
+
+/// Writes the file to the work tree of the repository and commits it on
+/// top of HEAD, returning the commit.
+pub fn commit_file(repo: &Repository, name: &str, content: &str, message: &str) -> Oid {
+    let file = repo.workdir().unwrap().join(name);
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(file, content).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(name)).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Flamingo", "flamingo@example.com").unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parent.iter().collect::<Vec<_>>(),
+    )
+    .unwrap()
+}
+
+/// Creates a repository at path with a single commit tagged with the
+/// tag, standing in for the upstream repository merges fetch from.
+pub fn upstream_repo(path: &Path, tag: &str) -> Repository {
+    let repo = init_repo(path);
+    let oid = commit_file(&repo, "README", "upstream", "Initial commit");
+    repo.reference(&format!("refs/tags/{tag}"), oid, false, "tag")
+        .unwrap();
+    repo
+}
+
+/// Clones the repository at url to path, checked out on the flamingo
+/// branch and with a committer. Tags are deleted, as the checkout of a
+/// flamingo repo has none of the upstream tags before merging them.
+pub fn clone_repo(url: &Path, path: &Path) -> Repository {
+    let repo = Repository::clone(url.to_str().unwrap(), path).unwrap();
+    for tag in repo.tag_names(None).unwrap().iter().flatten() {
+        repo.find_reference(&format!("refs/tags/{tag}"))
+            .unwrap()
+            .delete()
+            .unwrap();
+    }
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Flamingo").unwrap();
+    config
+        .set_str("user.email", "flamingo@example.com")
+        .unwrap();
+    repo
+}
+
+/// Clones the repository at url to a bare repository at path,
+/// standing in for a remote that is pushed to or fetched from.
+pub fn bare_clone(url: &Path, path: &Path) -> Repository {
+    RepoBuilder::new()
+        .bare(true)
+        .clone(url.to_str().unwrap(), path)
+        .unwrap()
+}
@@ -0,0 +1,239 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use json::{object, JsonValue};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const CACHE_DIR_NAME: &str = "roomservice";
+const DEPENDENCIES_DIR_NAME: &str = "dependencies";
+const REPOS_DIR_NAME: &str = "repos";
+const CACHE_FILE_EXT: &str = "json";
+
+const KEY_ETAG: &str = "etag";
+const KEY_FETCHED_AT: &str = "fetched_at";
+const KEY_BODY: &str = "body";
+const KEY_REPOS: &str = "repos";
+
+/// A dependency file as it was last fetched. Body is None if
+/// the repository did not have a dependency file.
+pub struct CachedFile {
+    pub etag: Option<String>,
+    pub body: Option<String>,
+    fetched_at: u64,
+}
+
+/// On disk cache of data fetched from the network, located in
+/// $XDG_CACHE_HOME/roomservice or ~/.cache/roomservice.
+/// Entries older than the ttl are ignored.
+pub struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration) -> Result<Self, String> {
+        let cache_home = env::var("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map_err(|_| String::from("Neither XDG_CACHE_HOME nor HOME is set"))?;
+        Ok(Self::with_dir(cache_home.join(CACHE_DIR_NAME), ttl))
+    }
+
+    pub fn with_dir(dir: PathBuf, ttl: Duration) -> Self {
+        Self { dir, ttl }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the cached dependency file of the repository at branch,
+    /// if there is one that has not expired.
+    pub fn get_dependency_file(
+        &self,
+        repo_name: &str,
+        branch: &str,
+        file_name: &str,
+    ) -> Option<CachedFile> {
+        let path = self.get_dependency_file_path(repo_name, branch, file_name)?;
+        let content = fs::read_to_string(path).ok()?;
+        let json = json::parse(&content).ok()?;
+        let cached_file = CachedFile {
+            etag: json[KEY_ETAG].as_str().map(|etag| etag.to_owned()),
+            body: json[KEY_BODY].as_str().map(|body| body.to_owned()),
+            fetched_at: json[KEY_FETCHED_AT].as_u64()?,
+        };
+        if now().saturating_sub(cached_file.fetched_at) > self.ttl.as_secs() {
+            return None;
+        }
+        Some(cached_file)
+    }
+
+    pub fn put_dependency_file(
+        &self,
+        repo_name: &str,
+        branch: &str,
+        file_name: &str,
+        etag: Option<&str>,
+        body: Option<&str>,
+    ) -> Result<(), String> {
+        let json = object! {
+            etag: etag,
+            body: body,
+            fetched_at: now(),
+        };
+        let path = self
+            .get_dependency_file_path(repo_name, branch, file_name)
+            .ok_or(format!(
+                "Not caching the dependency file of {repo_name} at {branch}, it does not map to a cache file"
+            ))?;
+        self.write(&path, &json)
+    }
+
+    /// Returns the cached names of all repositories of the organization,
+    /// if they have not expired.
+    pub fn get_repos(&self, org: &str) -> Option<Vec<String>> {
+        let content = fs::read_to_string(self.get_repos_path(org)?).ok()?;
+        let json = json::parse(&content).ok()?;
+        let fetched_at = json[KEY_FETCHED_AT].as_u64()?;
+        if now().saturating_sub(fetched_at) > self.ttl.as_secs() {
+            return None;
+        }
+        if !json[KEY_REPOS].is_array() {
+            return None;
+        }
+        json[KEY_REPOS]
+            .members()
+            .map(|name| name.as_str().map(|name| name.to_owned()))
+            .collect()
+    }
+
+    pub fn put_repos(&self, org: &str, repos: &[String]) -> Result<(), String> {
+        let json = object! {
+            repos: repos,
+            fetched_at: now(),
+        };
+        let path = self.get_repos_path(org).ok_or(format!(
+            "Not caching the repositories of {org}, it does not map to a cache file"
+        ))?;
+        self.write(&path, &json)
+    }
+
+    fn get_repos_path(&self, org: &str) -> Option<PathBuf> {
+        Some(
+            self.dir
+                .join(REPOS_DIR_NAME)
+                .join(format!("{}.{CACHE_FILE_EXT}", to_path_component(org)?)),
+        )
+    }
+
+    fn get_dependency_file_path(
+        &self,
+        repo_name: &str,
+        branch: &str,
+        file_name: &str,
+    ) -> Option<PathBuf> {
+        Some(
+            self.dir
+                .join(DEPENDENCIES_DIR_NAME)
+                .join(to_path_component(repo_name)?)
+                .join(to_path_component(branch)?)
+                .join(format!(
+                    "{}.{CACHE_FILE_EXT}",
+                    to_path_component(file_name)?
+                )),
+        )
+    }
+
+    fn write(&self, path: &PathBuf, json: &JsonValue) -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| format!("Failed to create cache dir {:?}: {err}", parent))?;
+        }
+        fs::write(path, json.dump())
+            .map_err(|err| format!("Failed to write cache file {:?}: {err}", path))
+    }
+}
+
+/// Escapes the value into a single path component, so that names with
+/// slashes stay inside the cache dir. None for values that cannot be one.
+fn to_path_component(value: &str) -> Option<String> {
+    match value {
+        "" | "." | ".." => None,
+        _ => Some(value.replace('%', "%25").replace('/', "%2F")),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn names_stay_inside_the_cache_dir() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::with_dir(dir.path().to_path_buf(), Duration::from_secs(60));
+
+        let path = cache
+            .get_dependency_file_path("../../owner/name", "feature/x", "flamingo.dependencies")
+            .unwrap();
+        assert_eq!(
+            path.strip_prefix(dir.path()).unwrap(),
+            Path::new("dependencies/..%2F..%2Fowner%2Fname/feature%2Fx/flamingo.dependencies.json")
+        );
+        assert_ne!(
+            cache.get_dependency_file_path("a%2Fb", "A13", "flamingo.dependencies"),
+            cache.get_dependency_file_path("a/b", "A13", "flamingo.dependencies")
+        );
+        assert!(cache
+            .get_dependency_file_path("..", "A13", "flamingo.dependencies")
+            .is_none());
+        assert!(cache
+            .put_dependency_file("owner/name", "..", "flamingo.dependencies", None, None)
+            .is_err());
+        assert!(cache.get_repos_path("gitlab/..").is_some());
+        assert!(cache.get_repos_path("..").is_none());
+    }
+
+    #[test]
+    fn dependency_files_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::with_dir(dir.path().to_path_buf(), Duration::from_secs(60));
+
+        cache
+            .put_dependency_file("owner/name", "A13", "deps", Some("\"v1\""), Some("[]"))
+            .unwrap();
+
+        let cached_file = cache
+            .get_dependency_file("owner/name", "A13", "deps")
+            .unwrap();
+        assert_eq!(cached_file.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(cached_file.body.as_deref(), Some("[]"));
+        assert!(cache
+            .get_dependency_file("owner/name", "A14", "deps")
+            .is_none());
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::Args;
+use clap::{parser::ValueSource, ArgMatches};
+use std::{env, fs, path::PathBuf};
+
+const CONFIG_FILE_NAME: &str = "roomservice.toml";
+
+const ENV_XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+const ENV_HOME: &str = "HOME";
+
+const KEY_MANIFEST_ROOT: &str = "manifest_root";
+const KEY_ORG: &str = "org";
+const KEY_BRANCH: &str = "branch";
+
+/// Defaults for the arguments read from roomservice.toml, like
+///
+/// manifest_root = ".repo" (or a list of roots)
+/// org = "FlamingoOS-Devices"
+/// branch = "A13"
+#[derive(Debug, Default)]
+struct Config {
+    manifest_root: Option<Vec<String>>,
+    org: Option<String>,
+    branch: Option<String>,
+}
+
+/// Fills in the arguments that were not passed on the command line from
+/// roomservice.toml in the current dir, or if there is none from the one
+/// in $XDG_CONFIG_HOME (~/.config if not set). Arguments passed on the
+/// command line take precedence over the file, which takes precedence
+/// over the defaults. The org of the file is not used with --device-repo,
+/// whose owner is the org then.
+pub fn apply(args: &mut Args, matches: &ArgMatches) -> Result<(), String> {
+    match find(&get_search_dirs()) {
+        Some(path) => apply_file(args, matches, &path),
+        None => Ok(()),
+    }
+}
+
+fn apply_file(args: &mut Args, matches: &ArgMatches, path: &PathBuf) -> Result<(), String> {
+    let config = read(path)?;
+    if args.manifest_root.is_empty() {
+        if let Some(manifest_root) = config.manifest_root {
+            args.manifest_root = manifest_root;
+        }
+    }
+    if args.org.is_none() && args.device_repo.is_none() {
+        args.org = config.org;
+    }
+    if matches.value_source(KEY_BRANCH) != Some(ValueSource::CommandLine) {
+        if let Some(branch) = config.branch {
+            args.branch = branch;
+        }
+    }
+    Ok(())
+}
+
+/// The current dir, then $XDG_CONFIG_HOME or ~/.config.
+fn get_search_dirs() -> Vec<PathBuf> {
+    let config_home = env::var_os(ENV_XDG_CONFIG_HOME)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os(ENV_HOME).map(|home| PathBuf::from(home).join(".config")));
+    [Some(PathBuf::new()), config_home]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// The config file in the first of the dirs that has one.
+fn find(dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+fn read(path: &PathBuf) -> Result<Config, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read config file {:?}: {err}", path))?;
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(format!("Config file {:?} is not a table", path)),
+        Err(err) => return Err(format!("Failed to parse config file {:?}: {err}", path)),
+    };
+    let mut config = Config::default();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            (KEY_MANIFEST_ROOT, toml::Value::String(root)) => {
+                config.manifest_root = Some(vec![root]);
+            }
+            (KEY_MANIFEST_ROOT, toml::Value::Array(roots)) => {
+                let roots = roots
+                    .into_iter()
+                    .map(|root| match root {
+                        toml::Value::String(root) => Ok(root),
+                        other => Err(format!(
+                            "{KEY_MANIFEST_ROOT} in config file {:?} has {other} which is not a string",
+                            path
+                        )),
+                    })
+                    .collect::<Result<Vec<String>, String>>()?;
+                config.manifest_root = Some(roots);
+            }
+            (KEY_ORG, toml::Value::String(org)) => config.org = Some(org),
+            (KEY_BRANCH, toml::Value::String(branch)) => config.branch = Some(branch),
+            (KEY_MANIFEST_ROOT | KEY_ORG | KEY_BRANCH, other) => {
+                return Err(format!(
+                    "{key} in config file {:?} is {other}, not a string",
+                    path
+                ));
+            }
+            (key, _) => {
+                return Err(format!(
+                    "Unknown key {key} in config file {:?}, known keys are {KEY_MANIFEST_ROOT}, {KEY_ORG} and {KEY_BRANCH}",
+                    path
+                ));
+            }
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn write_config(dir: &Path, content: &str) -> PathBuf {
+        let path = dir.join(CONFIG_FILE_NAME);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn apply_config(argv: &[&str], content: &str) -> Args {
+        let dir = TempDir::new().unwrap();
+        let path = write_config(dir.path(), content);
+        let matches = Args::command()
+            .try_get_matches_from(["roomservice"].iter().chain(argv))
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        apply_file(&mut args, &matches, &path).unwrap();
+        args
+    }
+
+    #[test]
+    fn manifest_root_is_a_string_or_an_array() {
+        let dir = TempDir::new().unwrap();
+
+        let path = write_config(dir.path(), r#"manifest_root = ".repo""#);
+        assert_eq!(read(&path).unwrap().manifest_root.unwrap(), [".repo"]);
+
+        let path = write_config(dir.path(), r#"manifest_root = [".repo", "../.repo"]"#);
+        assert_eq!(
+            read(&path).unwrap().manifest_root.unwrap(),
+            [".repo", "../.repo"]
+        );
+    }
+
+    #[test]
+    fn non_string_values_are_errors() {
+        let dir = TempDir::new().unwrap();
+
+        let path = write_config(dir.path(), "branch = 13");
+        assert_eq!(
+            read(&path).unwrap_err(),
+            format!("branch in config file {path:?} is 13, not a string")
+        );
+
+        let path = write_config(dir.path(), r#"manifest_root = [".repo", 13]"#);
+        assert_eq!(
+            read(&path).unwrap_err(),
+            format!("manifest_root in config file {path:?} has 13 which is not a string")
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let dir = TempDir::new().unwrap();
+        let path = write_config(dir.path(), r#"device = "lmi""#);
+
+        assert_eq!(
+            read(&path).unwrap_err(),
+            format!("Unknown key device in config file {path:?}, known keys are manifest_root, org and branch")
+        );
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_the_file() {
+        let config = r#"
+            manifest_root = ".repo"
+            org = "someone"
+            branch = "A12"
+        "#;
+
+        let args = apply_config(
+            &["-d", "lmi", "--branch", "A13", "-m", "/src/.repo"],
+            config,
+        );
+        assert_eq!(args.branch, "A13");
+        assert_eq!(args.manifest_root, ["/src/.repo"]);
+
+        let args = apply_config(&["-d", "lmi"], config);
+        assert_eq!(args.branch, "A12");
+        assert_eq!(args.manifest_root, [".repo"]);
+        assert_eq!(args.org.as_deref(), Some("someone"));
+    }
+
+    #[test]
+    fn org_is_not_used_with_device_repo() {
+        let args = apply_config(
+            &["--device-repo", "FlamingoOS-Devices/device_xiaomi_lmi"],
+            r#"org = "someone""#,
+        );
+
+        assert_eq!(args.org, None);
+    }
+
+    #[test]
+    fn config_in_the_current_dir_wins() {
+        let cwd = TempDir::new().unwrap();
+        let config_home = TempDir::new().unwrap();
+        let dirs = [cwd.path().to_owned(), config_home.path().to_owned()];
+
+        let home_config = write_config(config_home.path(), "");
+        assert_eq!(find(&dirs), Some(home_config));
+
+        let cwd_config = write_config(cwd.path(), "");
+        assert_eq!(find(&dirs), Some(cwd_config));
+    }
+}
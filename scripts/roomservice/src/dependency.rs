@@ -3,10 +3,32 @@ use json::{object::Object, JsonValue};
 use std::collections::HashMap;
 
 const DEPS_KEY_NAME: &str = "repository";
-const DEPS_KEY_PATH: &str = "target_path";
-const DEPS_KEY_REMOTE: &str = "remote";
-const DEPS_KEY_BRANCH: &str = "branch";
-const DEPS_KEY_DEPTH: &str = "clone-depth";
+pub const DEPS_KEY_PATH: &str = "target_path";
+pub const DEPS_KEY_REMOTE: &str = "remote";
+pub const DEPS_KEY_BRANCH: &str = "branch";
+pub const DEPS_KEY_DEPTH: &str = "clone-depth";
+const DEPS_KEY_UPSTREAM: &str = "upstream";
+const DEPS_KEY_VARIANT: &str = "variant";
+const DEPS_KEY_GROUPS: &str = "groups";
+const DEPS_KEY_ACTION: &str = "action";
+
+/// Action of an entry that removes a project of the manifests
+/// it is included after instead of adding a dependency.
+const ACTION_REMOVE: &str = "remove";
+
+/// Values used for the keys that a dependency does not specify.
+pub struct Defaults<'a> {
+    /// Remote for dependencies that are not of the form owner/name
+    pub remote: &'a str,
+    /// Owner on github for dependencies that are not of the form
+    /// owner/name, takes precedence over the remote if set
+    pub owner: Option<&'a str>,
+    /// Branch for dependencies on the github remote
+    pub github_branch: Option<&'a str>,
+    /// Revision of the <default> element of the manifests, used when
+    /// neither the dependency nor its remote have one
+    pub revision: Option<&'a str>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Dependency {
@@ -15,27 +37,84 @@ pub struct Dependency {
     pub remote: String,
     pub branch: String,
     pub clone_depth: Option<String>,
+    /// Object filter of a partial clone, used instead of clone_depth
+    pub clone_filter: Option<String>,
+    pub upstream: Option<String>,
+    /// Comma separated groups of the project, to include or
+    /// exclude it with repo init -g
+    pub groups: Option<String>,
+    /// Name of the repository that declared this dependency,
+    /// None for the device repository
+    pub parent: Option<String>,
+}
+
+/// Project removed from the manifests the generated one is included
+/// after, so that its path can be taken by a dependency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemovedProject {
+    /// Name of the removed project, if the entry specifies it
+    pub name: Option<String>,
+    pub path: String,
+}
+
+impl RemovedProject {
+    /// Parses the removal from its json object, which
+    /// must have a target_path and may have a repository.
+    pub fn get(json: &JsonValue) -> Result<RemovedProject, String> {
+        if let JsonValue::Object(repo) = json {
+            let path = get_string(repo, DEPS_KEY_PATH)?.ok_or(format!(
+                "Removal {} does not contain string value for key {DEPS_KEY_PATH}",
+                repo.pretty(4)
+            ))?;
+            Ok(RemovedProject {
+                name: get_string(repo, DEPS_KEY_NAME)?,
+                path,
+            })
+        } else {
+            Err(format!("{json} is not an Object"))
+        }
+    }
 }
 
 impl Dependency {
-    pub fn get(json: JsonValue, remotes: &HashMap<String, Remote>) -> Result<Dependency, String> {
+    /// Whether the json entry removes a project rather than
+    /// adding a dependency, see [`RemovedProject`].
+    pub fn is_removal(json: &JsonValue) -> bool {
+        json[DEPS_KEY_ACTION].as_str() == Some(ACTION_REMOVE)
+    }
+
+    /// Whether the dependency json applies to the requested build variant.
+    /// Dependencies without a variant always apply, the ones with a variant
+    /// only apply when the same variant was requested.
+    pub fn matches_variant(json: &JsonValue, variant: Option<&str>) -> bool {
+        match json[DEPS_KEY_VARIANT].as_str() {
+            Some(dependency_variant) => variant == Some(dependency_variant),
+            None => true,
+        }
+    }
+
+    /// Parses the dependency from its json object, falling
+    /// back to the defaults for the keys that are missing.
+    pub fn get(
+        json: JsonValue,
+        remotes: &HashMap<String, Remote>,
+        defaults: &Defaults,
+    ) -> Result<Dependency, String> {
         if let JsonValue::Object(repo) = json {
-            let name = get_string(&repo, DEPS_KEY_NAME).ok_or(format!(
+            let name = get_string(&repo, DEPS_KEY_NAME)?.ok_or(format!(
                 "Dependency {} does not contain string value for key {DEPS_KEY_NAME}",
                 repo.pretty(4)
             ))?;
-            let path = get_string(&repo, DEPS_KEY_PATH).ok_or(format!(
+            let path = get_string(&repo, DEPS_KEY_PATH)?.ok_or(format!(
                 "Dependency {} does not contain string value for key {DEPS_KEY_PATH}",
                 repo.pretty(4)
             ))?;
-            let remote = get_string(&repo, DEPS_KEY_REMOTE).unwrap_or(
-                if name.contains("/") {
-                    remotes::GITHUB
-                } else {
-                    remotes::FLAMINGO_DEVICES
-                }
-                .to_owned(),
-            );
+            let (name, remote) = match (get_string(&repo, DEPS_KEY_REMOTE)?, defaults.owner) {
+                (Some(remote), _) => (name, remote),
+                (None, _) if name.contains('/') => (name, remotes::GITHUB.to_owned()),
+                (None, Some(owner)) => (format!("{owner}/{name}"), remotes::GITHUB.to_owned()),
+                (None, None) => (name, defaults.remote.to_owned()),
+            };
             let repo_name = match remote.as_str() {
                 remotes::GITHUB => Ok::<String, String>(name.to_owned()),
                 other => {
@@ -52,36 +131,154 @@ impl Dependency {
                     Ok(format!("{}/{name}", prefix))
                 }
             }?;
-            let branch = match get_string(&repo, DEPS_KEY_BRANCH) {
+            let branch = match get_string(&repo, DEPS_KEY_BRANCH)? {
                 Some(revision) => Ok::<String, String>(revision),
                 None => remotes
                     .get(&remote)
-                    .map(|remote| remote.revision.as_ref())
-                    .flatten()
+                    .and_then(|remote| remote.revision.as_ref())
                     .map(|revision| revision.to_owned())
-                    .ok_or(format!("Remote {remote} does not have a default revision")),
+                    .or_else(|| {
+                        defaults
+                            .github_branch
+                            .filter(|_| remote == remotes::GITHUB)
+                            .map(|branch| branch.to_owned())
+                    })
+                    .or_else(|| defaults.revision.map(|revision| revision.to_owned()))
+                    .ok_or(if remote == remotes::GITHUB {
+                        format!("Dependency {name} uses the github remote but specifies no {DEPS_KEY_BRANCH} and github has no default revision, pass --github-default-branch or add one")
+                    } else {
+                        format!("Dependency {name} specifies no {DEPS_KEY_BRANCH} and neither remote {remote} nor the manifest <default> has a revision")
+                    }),
             }?;
-            let clone_depth = get_string(&repo, DEPS_KEY_DEPTH);
+            let clone_depth = get_string(&repo, DEPS_KEY_DEPTH)?;
+            let upstream = get_string(&repo, DEPS_KEY_UPSTREAM)?;
+            let groups = get_string(&repo, DEPS_KEY_GROUPS)?;
             Ok(Dependency {
                 name: repo_name,
                 path,
                 remote,
                 branch,
                 clone_depth,
+                clone_filter: None,
+                upstream,
+                groups,
+                parent: None,
             })
         } else {
-            return Err(format!("{json} is not an Object"));
+            Err(format!("{json} is not an Object"))
         }
     }
 }
 
-fn get_string(object: &Object, key: &str) -> Option<String> {
-    object
-        .get(key)
-        .filter(|value| value.is_string())
-        .map(|value| match value {
-            JsonValue::String(string) => string.to_owned(),
-            JsonValue::Short(short) => short.to_string(),
-            other => panic!("{} is not a string", other),
-        })
+/// Value of the key, None if it's missing or null.
+/// Fails if the value is of any other type than string.
+fn get_string(object: &Object, key: &str) -> Result<Option<String>, String> {
+    match object.get(key) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(value) => value
+            .as_str()
+            .map(|string| Some(string.to_owned()))
+            .ok_or(format!("Value {value} of key {key} is not a string")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn get(json: &str) -> Result<Dependency, String> {
+        Dependency::get(
+            json::parse(json).unwrap(),
+            &testing::remotes(),
+            &testing::defaults(),
+        )
+    }
+
+    #[test]
+    fn upstream_is_parsed() {
+        let dependency = get(
+            r#"{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "branch": "0123456789abcdef0123456789abcdef01234567", "upstream": "A13"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(dependency.upstream.as_deref(), Some("A13"));
+        assert_eq!(
+            dependency.branch,
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+    }
+
+    #[test]
+    fn upstream_is_optional() {
+        let dependency =
+            get(r#"{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"}"#)
+                .unwrap();
+
+        assert_eq!(dependency.upstream, None);
+    }
+
+    #[test]
+    fn upstream_must_be_a_string() {
+        let err = get(
+            r#"{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "upstream": 13}"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(err, "Value 13 of key upstream is not a string");
+    }
+
+    #[test]
+    fn github_dependency_without_branch_uses_the_configured_default() {
+        let defaults = Defaults {
+            github_branch: Some("lineage-20"),
+            ..testing::defaults()
+        };
+        let dependency = Dependency::get(
+            json::parse(
+                r#"{"repository": "LineageOS/android_kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250"}"#,
+            )
+            .unwrap(),
+            &testing::remotes(),
+            &defaults,
+        )
+        .unwrap();
+
+        assert_eq!(dependency.remote, remotes::GITHUB);
+        assert_eq!(dependency.name, "LineageOS/android_kernel_xiaomi_sm8250");
+        assert_eq!(dependency.branch, "lineage-20");
+    }
+
+    #[test]
+    fn github_dependency_without_branch_fails() {
+        let err = get(
+            r#"{"repository": "LineageOS/android_kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250"}"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err,
+            "Dependency LineageOS/android_kernel_xiaomi_sm8250 uses the github remote but specifies no branch and github has no default revision, pass --github-default-branch or add one"
+        );
+    }
+
+    #[test]
+    fn non_string_values_are_errors() {
+        for (json, expected) in [
+            (
+                r#"{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "branch": true}"#,
+                "Value true of key branch is not a string",
+            ),
+            (
+                r#"{"repository": ["vendor_xiaomi_lmi"], "target_path": "vendor/xiaomi/lmi"}"#,
+                r#"Value ["vendor_xiaomi_lmi"] of key repository is not a string"#,
+            ),
+            (
+                r#"{"repository": "vendor_xiaomi_lmi", "target_path": 7}"#,
+                "Value 7 of key target_path is not a string",
+            ),
+        ] {
+            assert_eq!(get(json).unwrap_err(), expected);
+        }
+    }
 }
@@ -0,0 +1,236 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::UTF8_BOM;
+use clap::ValueEnum;
+use json::JsonValue;
+use std::{fmt, path::Path};
+use yaml_rust::{Yaml, YamlLoader};
+
+/// TOML documents cannot be an array, so the
+/// dependencies are an array of tables under this key.
+const TOML_KEY_DEPENDENCIES: &str = "dependencies";
+
+/// Format dependency files are written in. Whatever the format, the
+/// file is converted to the same JSON structure before it's validated
+/// and resolved, so the keys and their meaning are the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DepsFormat {
+    /// flamingo.dependencies, a JSON array of dependencies
+    Json,
+    /// flamingo.dependencies.toml, a [[dependencies]] array of tables
+    Toml,
+    /// flamingo.dependencies.yaml, a YAML sequence of dependencies
+    Yaml,
+}
+
+impl DepsFormat {
+    /// Name of the dependency file in the root of a repository.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Self::Json => "flamingo.dependencies",
+            Self::Toml => "flamingo.dependencies.toml",
+            Self::Yaml => "flamingo.dependencies.yaml",
+        }
+    }
+
+    /// Format of a local dependency file guessed from its
+    /// extension, None if it's not one of toml, yaml or yml.
+    pub fn from_path(path: &str) -> Option<Self> {
+        match Path::new(path).extension()?.to_str()? {
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Parses the dependency file into its JSON structure. Values are all
+    /// strings in JSON, so integers like clone-depth = 1 are converted to
+    /// strings rather than making the file fail validation.
+    pub fn parse(self, content: &str) -> Result<JsonValue, String> {
+        // Editors on windows like to prefix files with a byte order mark
+        let content = content.trim_start_matches(UTF8_BOM);
+        match self {
+            Self::Json => json::parse(content).map_err(|err| err.to_string()),
+            Self::Toml => {
+                let value = content
+                    .parse::<toml::Value>()
+                    .map_err(|err| err.to_string())?;
+                match value.get(TOML_KEY_DEPENDENCIES) {
+                    Some(dependencies) => Ok(toml_to_json(dependencies)),
+                    None => Err(format!("missing the {TOML_KEY_DEPENDENCIES} array")),
+                }
+            }
+            Self::Yaml => {
+                let documents =
+                    YamlLoader::load_from_str(content).map_err(|err| err.to_string())?;
+                match documents.first() {
+                    Some(document) => yaml_to_json(document),
+                    None => Ok(JsonValue::Array(Vec::with_capacity(0))),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for DepsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Json => "JSON",
+            Self::Toml => "TOML",
+            Self::Yaml => "YAML",
+        };
+        write!(f, "{name}")
+    }
+}
+
+fn toml_to_json(value: &toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(string) => string.as_str().into(),
+        toml::Value::Integer(integer) => integer.to_string().into(),
+        toml::Value::Float(float) => (*float).into(),
+        toml::Value::Boolean(boolean) => (*boolean).into(),
+        toml::Value::Datetime(datetime) => datetime.to_string().into(),
+        toml::Value::Array(array) => JsonValue::Array(array.iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => {
+            let mut object = json::object::Object::with_capacity(table.len());
+            table
+                .iter()
+                .for_each(|(key, value)| object.insert(key, toml_to_json(value)));
+            JsonValue::Object(object)
+        }
+    }
+}
+
+fn yaml_to_json(value: &Yaml) -> Result<JsonValue, String> {
+    let json = match value {
+        Yaml::String(string) => string.as_str().into(),
+        Yaml::Integer(integer) => integer.to_string().into(),
+        Yaml::Real(real) => real.parse::<f64>().map_or(JsonValue::Null, JsonValue::from),
+        Yaml::Boolean(boolean) => (*boolean).into(),
+        Yaml::Array(array) => JsonValue::Array(
+            array
+                .iter()
+                .map(yaml_to_json)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        Yaml::Hash(hash) => {
+            let mut object = json::object::Object::with_capacity(hash.len());
+            for (key, value) in hash {
+                let key = match key {
+                    Yaml::String(key) => key.to_owned(),
+                    Yaml::Integer(key) => key.to_string(),
+                    other => return Err(format!("unsupported key {other:?}")),
+                };
+                object.insert(&key, yaml_to_json(value)?);
+            }
+            JsonValue::Object(object)
+        }
+        Yaml::Null => JsonValue::Null,
+        Yaml::Alias(_) | Yaml::BadValue => return Err(String::from("unsupported value")),
+    };
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_dependencies_are_parsed() {
+        let deps = DepsFormat::Toml
+            .parse(
+                r#"
+[[dependencies]]
+repository = "vendor_xiaomi_lmi"
+target_path = "vendor/xiaomi/lmi"
+clone-depth = 1
+
+[[dependencies]]
+repository = "device_xiaomi_sm8250-common"
+target_path = "device/xiaomi/sm8250-common"
+"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            deps,
+            json::parse(
+                r#"[
+                    {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "clone-depth": "1"},
+                    {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"}
+                ]"#
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn toml_without_dependencies_is_an_error() {
+        let err = DepsFormat::Toml
+            .parse(r#"repository = "vendor_xiaomi_lmi""#)
+            .unwrap_err();
+
+        assert_eq!(err, "missing the dependencies array");
+    }
+
+    #[test]
+    fn yaml_dependencies_are_parsed() {
+        let deps = DepsFormat::Yaml
+            .parse(
+                r#"
+- repository: vendor_xiaomi_lmi
+  target_path: vendor/xiaomi/lmi
+  clone-depth: 1
+- repository: device_xiaomi_sm8250-common
+  target_path: device/xiaomi/sm8250-common
+"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            deps,
+            json::parse(
+                r#"[
+                    {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "clone-depth": "1"},
+                    {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"}
+                ]"#
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn yaml_non_string_keys_are_errors() {
+        let err = DepsFormat::Yaml
+            .parse("- repository: vendor_xiaomi_lmi\n  true: vendor/xiaomi/lmi\n")
+            .unwrap_err();
+
+        assert_eq!(err, "unsupported key Boolean(true)");
+    }
+
+    #[test]
+    fn formats_are_detected_by_extension() {
+        assert_eq!(
+            DepsFormat::from_path("deps/flamingo.dependencies.toml"),
+            Some(DepsFormat::Toml)
+        );
+        assert_eq!(DepsFormat::from_path("deps.yaml"), Some(DepsFormat::Yaml));
+        assert_eq!(DepsFormat::from_path("deps.yml"), Some(DepsFormat::Yaml));
+        assert_eq!(DepsFormat::from_path("flamingo.dependencies"), None);
+        assert_eq!(DepsFormat::from_path("deps.json"), None);
+    }
+}
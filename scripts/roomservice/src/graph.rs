@@ -0,0 +1,92 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::dependency::Dependency;
+use std::{collections::BTreeSet, fs};
+
+const GRAPH_NAME: &str = "dependencies";
+
+/// Writes the dependency graph in Graphviz DOT format. Every repository
+/// is a node, with an edge from the repository that declared it.
+pub fn write<'a>(
+    dependencies: impl IntoIterator<Item = &'a Dependency>,
+    file: &str,
+) -> Result<(), String> {
+    fs::write(file, to_dot(dependencies))
+        .map_err(|err| format!("Failed to write dependency graph to {file}: {err}"))
+}
+
+fn to_dot<'a>(dependencies: impl IntoIterator<Item = &'a Dependency>) -> String {
+    let mut nodes = BTreeSet::new();
+    let mut edges = BTreeSet::new();
+    for dependency in dependencies {
+        nodes.insert(dependency.name.as_str());
+        if let Some(parent) = dependency.parent.as_ref() {
+            edges.insert((parent.as_str(), dependency.name.as_str()));
+        }
+    }
+    let mut dot = format!("digraph {GRAPH_NAME} {{\n");
+    nodes
+        .iter()
+        .for_each(|node| dot.push_str(&format!("    {};\n", quote(node))));
+    edges.iter().for_each(|(parent, node)| {
+        dot.push_str(&format!("    {} -> {};\n", quote(parent), quote(node)))
+    });
+    dot.push_str("}\n");
+    dot
+}
+
+fn quote(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn dependency(name: &str, parent: &str) -> Dependency {
+        Dependency {
+            parent: Some(format!("FlamingoOS-Devices/{parent}")),
+            ..testing::dependency(name, name)
+        }
+    }
+
+    #[test]
+    fn dot_has_a_node_per_repo_and_an_edge_per_declaration() {
+        let dependencies = [
+            dependency("kernel_xiaomi_sm8250", "device_xiaomi_lmi"),
+            dependency("vendor_xiaomi_lmi", "device_xiaomi_lmi"),
+            dependency("device_xiaomi_sm8250-common", "device_xiaomi_lmi"),
+            dependency("vendor_xiaomi_sm8250-common", "device_xiaomi_sm8250-common"),
+        ];
+
+        assert_eq!(
+            to_dot(&dependencies),
+            r#"digraph dependencies {
+    "FlamingoOS-Devices/device_xiaomi_sm8250-common";
+    "FlamingoOS-Devices/kernel_xiaomi_sm8250";
+    "FlamingoOS-Devices/vendor_xiaomi_lmi";
+    "FlamingoOS-Devices/vendor_xiaomi_sm8250-common";
+    "FlamingoOS-Devices/device_xiaomi_lmi" -> "FlamingoOS-Devices/device_xiaomi_sm8250-common";
+    "FlamingoOS-Devices/device_xiaomi_lmi" -> "FlamingoOS-Devices/kernel_xiaomi_sm8250";
+    "FlamingoOS-Devices/device_xiaomi_lmi" -> "FlamingoOS-Devices/vendor_xiaomi_lmi";
+    "FlamingoOS-Devices/device_xiaomi_sm8250-common" -> "FlamingoOS-Devices/vendor_xiaomi_sm8250-common";
+}
+"#
+        );
+    }
+}
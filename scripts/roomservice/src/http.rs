@@ -0,0 +1,389 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use flamingo_utils::http;
+use reqwest::{header::HeaderMap, Client, RequestBuilder, Response, StatusCode};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_RETRY_AFTER_SECS: u64 = 60;
+
+const HEADER_RETRY_AFTER: &str = "retry-after";
+const HEADER_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const HEADER_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
+/// Builds the http client like [`http::build_client`] does. Requests
+/// taking longer than the timeout, including reading the body, are aborted.
+pub fn build_client(timeout: Duration, insecure: bool) -> Result<Client, String> {
+    http::build_client(
+        Client::builder().connect_timeout(timeout).timeout(timeout),
+        insecure,
+        |warning| warn!("{warning}"),
+    )
+}
+
+/// Sends the request, retrying with an exponential backoff up to
+/// max_attempts times when it times out, fails to connect or the server
+/// responds with 429 (Too Many Requests) or a 5xx. Retry-After header
+/// is honored if present. Any other response is returned as is, as are
+/// the ones that hit the API rate limit, for [`send_rate_limited`] to
+/// wait out.
+pub async fn send(request: RequestBuilder, max_attempts: u32) -> Result<Response, String> {
+    let mut attempt = 1;
+    loop {
+        let result = request
+            .try_clone()
+            .ok_or(String::from("Request cannot be retried"))?
+            .send()
+            .await;
+        let backoff = Duration::from_secs(1 << (attempt - 1));
+        let delay = match result {
+            Err(err) if (err.is_timeout() || err.is_connect()) && attempt < max_attempts => {
+                warn!(
+                    "GET request failed: {err}, retrying in {}s",
+                    backoff.as_secs()
+                );
+                backoff
+            }
+            Err(err) => return Err(format!("GET request failed: {err}")),
+            Ok(response)
+                if is_retryable(response.status())
+                    && !is_rate_limit_hit(&response)
+                    && attempt < max_attempts =>
+            {
+                let delay = get_retry_after(response.headers()).unwrap_or(backoff);
+                warn!(
+                    "{} responded with {}, retrying in {}s",
+                    response.url(),
+                    response.status().as_u16(),
+                    delay.as_secs()
+                );
+                delay
+            }
+            Ok(response) => return Ok(response),
+        };
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Sends the request like [`send`], and when the API rate limit is hit
+/// (403 or 429 with no requests remaining) waits until the limit resets
+/// before sending it again. Fails if the reset is further than max_wait
+/// away. Ordinary 403s without the rate limit headers are returned as is.
+pub async fn send_rate_limited(
+    request: RequestBuilder,
+    max_attempts: u32,
+    max_wait: Duration,
+) -> Result<Response, String> {
+    let mut attempt = 1;
+    loop {
+        let response = send(
+            request
+                .try_clone()
+                .ok_or(String::from("Request cannot be retried"))?,
+            max_attempts,
+        )
+        .await?;
+        let reset = match get_ratelimit_reset(response.headers()) {
+            Some(reset) if is_rate_limit_hit(&response) && attempt < max_attempts => reset,
+            _ => return Ok(response),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        // A second of slack in case the clocks are slightly off
+        let delay = Duration::from_secs(reset.saturating_sub(now) + 1);
+        if delay > max_wait {
+            return Err(format!(
+                "{} hit the API rate limit, which resets at epoch {reset} in {}s, longer than --max-wait of {}s",
+                response.url(),
+                delay.as_secs(),
+                max_wait.as_secs()
+            ));
+        }
+        warn!(
+            "{} hit the API rate limit, waiting {}s for it to reset",
+            response.url(),
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Reads the body of the response as text, aborting once more
+/// than max_bytes have been received.
+pub async fn read_text(mut response: Response, max_bytes: usize) -> Result<String, String> {
+    let url = response.url().to_string();
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|err| format!("Failed to read response from {url}: {err}"))?
+    {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(format!(
+                "Response from {url} exceeds the limit of {max_bytes} bytes"
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    String::from_utf8(body).map_err(|err| format!("Response from {url} is not valid UTF-8: {err}"))
+}
+
+/// Describes why a non successful response failed in a way
+/// that's actionable for the user.
+pub fn status_error(url: &str, response: &Response) -> String {
+    let status = response.status();
+    match status {
+        StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => {
+            format!("Repository at {url} is unavailable for legal reasons (status code 451)")
+        }
+        StatusCode::TOO_MANY_REQUESTS => {
+            format!("GET request to {url} was rate limited (status code 429) after all attempts")
+        }
+        StatusCode::FORBIDDEN if is_rate_limited(response.headers()) => {
+            let reset = response
+                .headers()
+                .get(HEADER_RATELIMIT_RESET)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("unknown");
+            format!("GET request to {url} hit the API rate limit (status code 403), limit resets at epoch {reset}")
+        }
+        StatusCode::FORBIDDEN => {
+            format!("Permission denied for GET request to {url} (status code 403)")
+        }
+        other => format!(
+            "GET request to {url} failed. Status code = {}",
+            other.as_str()
+        ),
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether the response is a 403 or 429 with no requests remaining
+/// and the time the rate limit resets at.
+fn is_rate_limit_hit(response: &Response) -> bool {
+    let status = response.status();
+    (status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS)
+        && is_rate_limited(response.headers())
+        && get_ratelimit_reset(response.headers()).is_some()
+}
+
+fn is_rate_limited(headers: &HeaderMap) -> bool {
+    headers
+        .get(HEADER_RATELIMIT_REMAINING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|remaining| remaining.trim() == "0")
+}
+
+fn get_ratelimit_reset(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(HEADER_RATELIMIT_RESET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+}
+
+fn get_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(HEADER_RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|secs| Duration::from_secs(secs.min(MAX_RETRY_AFTER_SECS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    const FILE_PATH: &str = "/FlamingoOS-Devices/device_xiaomi_lmi/A13/flamingo.dependencies";
+
+    /// Sends a GET request for the file to the server, returning
+    /// the response or the error describing its status.
+    async fn get(server: &MockServer, max_attempts: u32) -> Result<Response, String> {
+        let url = format!("{}{FILE_PATH}", server.uri());
+        let client = build_client(Duration::from_secs(5), false).unwrap();
+        let response = send(client.get(&url), max_attempts).await?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(status_error(&url, &response))
+        }
+    }
+
+    async fn mock(server: &MockServer, response: ResponseTemplate) {
+        Mock::given(method("GET"))
+            .and(path(FILE_PATH))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_is_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(FILE_PATH))
+            .respond_with(ResponseTemplate::new(429).insert_header(HEADER_RETRY_AFTER, "0"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        mock(&server, ResponseTemplate::new(200).set_body_string("[]")).await;
+
+        let response = get(&server, 2).await.unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "[]");
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_fails_after_all_attempts() {
+        let server = MockServer::start().await;
+        mock(
+            &server,
+            ResponseTemplate::new(429).insert_header(HEADER_RETRY_AFTER, "0"),
+        )
+        .await;
+
+        let err = get(&server, 2).await.unwrap_err();
+
+        assert_eq!(
+            err,
+            format!(
+                "GET request to {}{FILE_PATH} was rate limited (status code 429) after all attempts",
+                server.uri()
+            )
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unavailable_for_legal_reasons_is_reported() {
+        let server = MockServer::start().await;
+        mock(&server, ResponseTemplate::new(451)).await;
+
+        let err = get(&server, 3).await.unwrap_err();
+
+        assert_eq!(
+            err,
+            format!(
+                "Repository at {}{FILE_PATH} is unavailable for legal reasons (status code 451)",
+                server.uri()
+            )
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn forbidden_with_rate_limit_headers_is_a_rate_limit() {
+        let server = MockServer::start().await;
+        mock(
+            &server,
+            ResponseTemplate::new(403)
+                .insert_header(HEADER_RATELIMIT_REMAINING, "0")
+                .insert_header(HEADER_RATELIMIT_RESET, "1700000000"),
+        )
+        .await;
+
+        let err = get(&server, 3).await.unwrap_err();
+
+        assert_eq!(
+            err,
+            format!(
+                "GET request to {}{FILE_PATH} hit the API rate limit (status code 403), limit resets at epoch 1700000000",
+                server.uri()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn forbidden_without_rate_limit_headers_is_a_permission_error() {
+        let server = MockServer::start().await;
+        mock(
+            &server,
+            ResponseTemplate::new(403).insert_header(HEADER_RATELIMIT_REMAINING, "42"),
+        )
+        .await;
+
+        let err = get(&server, 3).await.unwrap_err();
+
+        assert_eq!(
+            err,
+            format!(
+                "Permission denied for GET request to {}{FILE_PATH} (status code 403)",
+                server.uri()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_cut_off() {
+        let server = MockServer::start().await;
+        mock(
+            &server,
+            ResponseTemplate::new(200).set_body_string("x".repeat(4096)),
+        )
+        .await;
+
+        let response = get(&server, 1).await.unwrap();
+        assert_eq!(
+            read_text(response, 4095).await.unwrap_err(),
+            format!(
+                "Response from {}{FILE_PATH} exceeds the limit of 4095 bytes",
+                server.uri()
+            )
+        );
+
+        let response = get(&server, 1).await.unwrap();
+        assert_eq!(read_text(response, 4096).await.unwrap().len(), 4096);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_waited_out_once_per_attempt() {
+        let server = MockServer::start().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        mock(
+            &server,
+            ResponseTemplate::new(429)
+                .insert_header(HEADER_RETRY_AFTER, "0")
+                .insert_header(HEADER_RATELIMIT_REMAINING, "0")
+                .insert_header(HEADER_RATELIMIT_RESET, now.to_string().as_str()),
+        )
+        .await;
+        let url = format!("{}{FILE_PATH}", server.uri());
+        let client = build_client(Duration::from_secs(5), false).unwrap();
+
+        let response = send(client.get(&url), 3).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+        let response = send_rate_limited(client.get(&url), 2, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+}
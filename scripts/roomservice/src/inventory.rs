@@ -0,0 +1,160 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::{dependency::Dependency, manifest, remotes::Remote};
+use json::{object, JsonValue};
+use std::{collections::HashMap, fs, path::Path};
+
+const CSV_EXT: &str = "csv";
+const CSV_HEADER: &str = "name,path,url,remote,revision";
+
+/// A resolved dependency as it will be synced.
+struct Entry<'a> {
+    name: &'a str,
+    path: &'a str,
+    url: Option<String>,
+    remote: &'a str,
+    revision: &'a str,
+}
+
+/// Writes the inventory of the dependencies to the file, as CSV if
+/// the file has a .csv extension and as a JSON array otherwise.
+pub fn write(
+    dependencies: &[Dependency],
+    remotes: &HashMap<String, Remote>,
+    file: &str,
+) -> Result<(), String> {
+    let entries: Vec<Entry> = dependencies
+        .iter()
+        .map(|dependency| get_entry(dependency, remotes))
+        .collect();
+    let is_csv = Path::new(file)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(CSV_EXT));
+    let content = if is_csv {
+        to_csv(&entries)
+    } else {
+        to_json(&entries).pretty(4)
+    };
+    fs::write(file, content).map_err(|err| format!("Failed to write inventory to {file}: {err}"))
+}
+
+fn get_entry<'a>(dependency: &'a Dependency, remotes: &HashMap<String, Remote>) -> Entry<'a> {
+    let name = manifest::get_project_name(dependency);
+    Entry {
+        name,
+        path: &dependency.path,
+        url: manifest::get_clone_url(dependency, remotes),
+        remote: &dependency.remote,
+        revision: &dependency.branch,
+    }
+}
+
+fn to_json(entries: &[Entry]) -> JsonValue {
+    JsonValue::Array(
+        entries
+            .iter()
+            .map(|entry| {
+                object! {
+                    name: entry.name,
+                    path: entry.path,
+                    url: entry.url.as_deref(),
+                    remote: entry.remote,
+                    revision: entry.revision,
+                }
+            })
+            .collect(),
+    )
+}
+
+fn to_csv(entries: &[Entry]) -> String {
+    let mut csv = format!("{CSV_HEADER}\n");
+    entries.iter().for_each(|entry| {
+        let fields = [
+            entry.name,
+            entry.path,
+            entry.url.as_deref().unwrap_or_default(),
+            entry.remote,
+            entry.revision,
+        ];
+        let row: Vec<String> = fields.iter().map(|field| escape_csv(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    });
+    csv
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{remotes, testing};
+
+    fn dependencies() -> Vec<Dependency> {
+        vec![
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+            Dependency {
+                name: String::from("LineageOS/android_kernel_xiaomi_sm8250"),
+                remote: remotes::GITHUB.to_owned(),
+                branch: String::from("lineage-20"),
+                ..testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250")
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_has_a_row_per_dependency() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("inventory.csv");
+
+        write(&dependencies(), &testing::remotes(), file.to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "name,path,url,remote,revision
+device_xiaomi_lmi,device/xiaomi/lmi,https://github.com/FlamingoOS-Devices/device_xiaomi_lmi,flamingo-devices,A13
+LineageOS/android_kernel_xiaomi_sm8250,kernel/xiaomi/sm8250,https://github.com/LineageOS/android_kernel_xiaomi_sm8250,github,lineage-20
+"
+        );
+    }
+
+    #[test]
+    fn json_has_an_object_per_dependency() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("inventory.json");
+
+        write(&dependencies(), &testing::remotes(), file.to_str().unwrap()).unwrap();
+
+        let inventory = json::parse(&fs::read_to_string(&file).unwrap()).unwrap();
+        let paths: Vec<&str> = inventory
+            .members()
+            .map(|entry| entry["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["device/xiaomi/lmi", "kernel/xiaomi/sm8250"]);
+        assert_eq!(
+            inventory[1]["url"],
+            "https://github.com/LineageOS/android_kernel_xiaomi_sm8250"
+        );
+        assert_eq!(inventory[1]["revision"], "lineage-20");
+    }
+}
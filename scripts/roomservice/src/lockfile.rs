@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::{dependency::Dependency, manifest, remotes::Remote, UTF8_BOM};
+use json::{object, JsonValue};
+use rayon::prelude::*;
+use std::{collections::HashMap, fs, process::Command};
+
+const KEY_REVISION: &str = "revision";
+
+/// Lengths of a commit sha, which may be abbreviated.
+const MIN_SHA_LENGTH: usize = 7;
+const MAX_SHA_LENGTH: usize = 40;
+
+/// Reads the lockfile, mapping the path of every
+/// dependency to the commit it is pinned at.
+pub fn read(file: &str) -> Result<HashMap<String, String>, String> {
+    let content =
+        fs::read_to_string(file).map_err(|err| format!("Failed to read lockfile {file}: {err}"))?;
+    let json = json::parse(content.trim_start_matches(UTF8_BOM))
+        .map_err(|err| format!("Lockfile {file} is not valid JSON: {err}"))?;
+    match json {
+        JsonValue::Object(object) => object
+            .iter()
+            .map(|(path, lock)| match lock[KEY_REVISION].as_str() {
+                Some(revision) => Ok((path.to_owned(), revision.to_owned())),
+                None => Err(format!(
+                    "Lock of {path} in {file} does not contain string value for key {KEY_REVISION}"
+                )),
+            })
+            .collect(),
+        other => Err(format!(
+            "Lockfile {file} should be a JSON object, found {}",
+            other.pretty(4)
+        )),
+    }
+}
+
+/// Writes the lockfile, pinning every dependency at the commit
+/// its branch currently points to on its remote.
+pub fn write(
+    dependencies: &[&Dependency],
+    remotes: &HashMap<String, Remote>,
+    file: &str,
+) -> Result<(), String> {
+    let revisions = dependencies
+        .par_iter()
+        .map(|dependency| get_revision(dependency, remotes))
+        .collect::<Result<Vec<String>, String>>()?;
+    let mut lockfile = JsonValue::new_object();
+    for (dependency, revision) in dependencies.iter().zip(revisions) {
+        lockfile[dependency.path.as_str()] = object! {
+            name: dependency.name.as_str(),
+            branch: dependency.branch.as_str(),
+            revision: revision,
+        };
+    }
+    fs::write(file, lockfile.pretty(4))
+        .map_err(|err| format!("Failed to write lockfile {file}: {err}"))
+}
+
+/// Commit the branch of the dependency points to, the branch itself
+/// if the dependency is already pinned to a commit. The branch may
+/// also be a tag or a full ref, like a revision in a manifest.
+fn get_revision(
+    dependency: &Dependency,
+    remotes: &HashMap<String, Remote>,
+) -> Result<String, String> {
+    if is_sha(&dependency.branch) {
+        return Ok(dependency.branch.to_owned());
+    }
+    let url = manifest::get_clone_url(dependency, remotes).ok_or(format!(
+        "No such remote exists with the name {}",
+        dependency.remote
+    ))?;
+    let output = Command::new("git")
+        .arg("ls-remote")
+        .arg(&url)
+        .arg(&dependency.branch)
+        .output()
+        .map_err(|err| format!("Failed to run git ls-remote: {err}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git ls-remote {url} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let refs: Vec<(&str, &str)> = stdout
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .collect();
+    // ls-remote matches the end of refs, so only exact matches are used
+    let branch = &dependency.branch;
+    [
+        branch.to_owned(),
+        format!("refs/heads/{branch}"),
+        format!("refs/tags/{branch}^{{}}"),
+        format!("refs/tags/{branch}"),
+    ]
+    .iter()
+    .find_map(|name| refs.iter().find(|(_, reference)| reference.trim() == name))
+    .map(|(revision, _)| revision.to_string())
+    .ok_or(format!("Revision {branch} does not exist in {url}"))
+}
+
+/// Whether the revision is a full or abbreviated commit sha.
+pub fn is_sha(revision: &str) -> bool {
+    (MIN_SHA_LENGTH..=MAX_SHA_LENGTH).contains(&revision.len())
+        && revision.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -23,103 +23,877 @@
  *          "target_path": "device/brand/name",
  *          "remote": "flamingo",
  *          "revision": "A13",
- *          "clone-depth": "100"
+ *          "clone-depth": "100",
+ *          "upstream": "A13",
+ *          "groups": "camera,blobs",
+ *          "variant": "eng"
  *     }
  * ]
- * Only "repository" and "target_path" are the required keys in each object.
- * If "remote" is not specified then there are two options, the value of "repository" should
- * be like username/device_brand_name such that the repository link can be obtained
- * by simply prefixing https://github.com/, if that is not the case then flamingo-devices
- * remote is used as the default. If "revision" is not specified then the remote must have a
- * default revision set in manifest.
+ * Only "repository" and "target_path" are the required keys in each object. If "remote" is not
+ * specified then there are two options, the value of "repository" should be like
+ * username/device_brand_name such that the repository link can be obtained by simply prefixing
+ * https://github.com/, if that is not the case then flamingo-devices remote (or the one passed
+ * with --default-remote) is used as the default, unless --org or the owner of --device-repo is
+ * another organization in which case the repository is looked up under it on github. If
+ * "revision" is not specified then the remote must have a default revision set in manifest, or
+ * --github-default-branch is used for the github remote. "upstream" is emitted as is to the
+ * project and is useful to record the branch a pinned commit sha in "revision" came from.
+ * "groups" is emitted as is too, so optional projects can be left out with repo init -g.
+ * An entry with "action": "remove" and a "target_path" (and optionally the "repository")
+ * removes that project of the manifests the generated one is included after, so that a
+ * dependency can take its path.
+ * Dependencies with a "variant" are only resolved when the same variant is passed with
+ * --variant. With --deps-format toml or yaml, flamingo.dependencies.toml (entries as
+ * [[dependencies]] tables) or flamingo.dependencies.yaml (a sequence of entries) are read
+ * instead, with the same keys.
  */
 use async_recursion::async_recursion;
-use clap::Parser;
-use dependency::Dependency;
-use json::JsonValue;
-use manifest::Manifest;
+use cache::Cache;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use colored::Colorize;
+use dependency::{Defaults, Dependency, RemovedProject};
+use deps_format::DepsFormat;
+use flamingo_utils::manifest::parse_xml;
+use futures::future;
+use json::{object, JsonValue};
+use log::{Level, LevelFilter};
+use manifest::{
+    defs::{COMMON_MANIFEST_FILE_NAME, DEVICE_MANIFEST_FILE_NAME, MANIFEST_EXT},
+    Manifest, ManifestFormat,
+};
 use regex::Regex;
-use remotes::Remote;
-use reqwest::{Client, StatusCode};
+use remotes::{ManifestRemotes, Remote, RemoteKind};
+use reqwest::{header, Client, StatusCode};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    process::{Command, ExitStatus},
+    io::{self, BufRead, IsTerminal, Write},
+    iter,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
+use tokio::sync::{OnceCell, Semaphore};
+
+#[macro_use]
+extern crate log;
 
+mod cache;
+mod config;
 mod dependency;
+mod deps_format;
+mod graph;
+mod http;
+mod inventory;
+mod lockfile;
 mod manifest;
+mod overrides;
 mod remotes;
+mod schema;
+#[cfg(test)]
+mod testing;
+mod validate;
 
 const ORG: &str = "FlamingoOS-Devices";
+/// Brand parts of device repo names, device_<brand>_<codename>. Brands are
+/// usually a single word, so repos with one take precedence over ones whose
+/// brand has underscores in it, like device_<brand_words>_<codename>.
+const DEVICE_REPO_BRANDS: [&str; 2] = [r"[^_]+", r".+"];
 const DEFAULT_BRANCH: &str = "A13";
-const DEPENDENCY_FILE_NAME: &str = "flamingo.dependencies";
+const DEFAULT_GITHUB_API_URL: &str = "https://api.github.com";
+const DEFAULT_GITHUB_API_VERSION: &str = "2022-11-28";
+const DEFAULT_GITHUB_RAW_URL: &str = "https://raw.githubusercontent.com";
+const DEFAULT_GITLAB_URL: &str = "https://gitlab.com";
+const DEFAULT_CACHE_TTL_SECS: u64 = 3600;
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_MAX_WAIT_SECS: u64 = 300;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_CONCURRENCY: u32 = 8;
+const SYNC_RETRY_DELAY_SECS: u64 = 10;
+const SYNC_FAILING_REPOS: &str = "Failing repos";
 
 const LOCAL_MANIFESTS_DIR: &str = "local_manifests";
 const SOURCE_MANIFESTS_DIR: &str = "manifests";
 
 const RESPONSE_KEY_NAME: &str = "name";
+const RESPONSE_KEY_PATH: &str = "path";
+
+const UTF8_BOM: char = '\u{feff}';
+
+const POST_RESOLVE_HOOK: &str = "post-resolve-hook";
+const ENV_HOOK_MANIFEST: &str = "ROOMSERVICE_MANIFEST";
+const ENV_HOOK_PROJECT_COUNT: &str = "ROOMSERVICE_PROJECT_COUNT";
+
+/// Projects with this clone depth are emitted as partial
+/// clones with the filter when --partial-clone is passed.
+const SHALLOW_CLONE_DEPTH: &str = "1";
+const PARTIAL_CLONE_FILTER: &str = "blob:none";
 
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    manifest_root: String,
+    #[command(subcommand)]
+    command: Option<Action>,
 
+    /// Root of the repo manifests (ex: .repo). Can be passed multiple
+    /// times to layer manifest dirs, remotes from later roots override
+    /// earlier ones. Local manifests are written to the first root. It,
+    /// --org and --branch default to the ones in roomservice.toml if any
     #[arg(short, long)]
-    device_name: String,
+    manifest_root: Vec<String>,
+
+    /// Name of the device. Can be passed multiple times or as a comma
+    /// separated list to resolve several devices in one go
+    #[arg(
+        short,
+        long,
+        required_unless_present = "device_repo",
+        value_delimiter = ','
+    )]
+    device_name: Vec<String>,
+
+    /// Device repository as owner/name. Skips searching for the
+    /// device repository in the organization
+    #[arg(long, conflicts_with = "device_name")]
+    device_repo: Option<String>,
 
     #[arg(short, long, default_value_t = DEFAULT_BRANCH.to_owned())]
     branch: String,
 
+    /// Commit of the device repository to resolve the dependencies at and
+    /// to sync it at, for reproducible builds. Dependencies of the device
+    /// still use their own branches
+    #[arg(long)]
+    device_revision: Option<String>,
+
+    /// Local dependency file used instead of the one of the device
+    /// repository, to try out changes before pushing them. Dependencies
+    /// of the dependencies are still fetched from their repositories
+    #[arg(long)]
+    local_deps: Option<String>,
+
     #[arg(short, long, default_value_t = false)]
     sync: bool,
 
+    /// Only log warnings and errors, and only the errors of repo sync
     #[arg(short, long, default_value_t = false)]
     quiet: bool,
+
+    /// Also log the steps of the resolution and the urls requested
+    #[arg(short, long, default_value_t = false, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// How the resolved projects are printed. `json` prints only a JSON
+    /// array of them to stdout, implying --quiet
+    #[arg(long, value_enum, default_value_t = Output::Text, conflicts_with_all = ["sync", "plan", "dry_run"])]
+    output: Output,
+
+    /// Format of the generated manifest. `grouped` tags projects under
+    /// device/, kernel/ and vendor/ with the matching `groups` attribute
+    #[arg(long, value_enum, default_value_t = ManifestFormat::Plain)]
+    manifest_format: ManifestFormat,
+
+    /// Format of the dependency files, which decides the file fetched
+    /// from every repository
+    #[arg(long, value_enum, default_value_t = DepsFormat::Json)]
+    deps_format: DepsFormat,
+
+    /// Write a separate manifest for each device instead of a single
+    /// device_manifest.xml. Dependencies shared between the devices
+    /// go into common_manifest.xml
+    #[arg(long, default_value_t = false)]
+    split_per_device: bool,
+
+    /// Only append the projects that are not in the existing
+    /// device_manifest.xml yet, instead of rewriting it
+    #[arg(long, default_value_t = false, conflicts_with = "split_per_device")]
+    only_new: bool,
+
+    /// Print the manifests that would be written instead of writing
+    /// them, skip writing --graph, --lockfile and --inventory, and do not
+    /// run the post resolve hook or sync
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Replace an existing device_manifest.xml that was not generated by
+    /// roomservice, instead of merging the projects into it
+    #[arg(long, default_value_t = false, conflicts_with_all = ["split_per_device", "only_new"])]
+    overwrite: bool,
+
+    /// Add the projects that are not in this existing manifest yet to it,
+    /// keeping everything else in it, instead of writing device_manifest.xml
+    #[arg(long, conflicts_with_all = ["split_per_device", "only_new"])]
+    merge_into: Option<String>,
+
+    /// Add the remotes used by the dependencies that are not defined in
+    /// the manifests included by manifest.xml to the generated manifest
+    #[arg(long, default_value_t = false)]
+    emit_missing_remotes: bool,
+
+    /// Add every remote used by the dependencies to the generated manifest,
+    /// so that it can be used on its own. repo rejects remotes defined again
+    /// with other attributes, so not meant for manifests included by
+    /// manifest.xml
+    #[arg(long, default_value_t = false, conflicts_with = "emit_missing_remotes")]
+    emit_remotes: bool,
+
+    /// Warn instead of failing when manifests define a remote more
+    /// than once with a different fetch or revision
+    #[arg(long, default_value_t = false)]
+    lenient: bool,
+
+    /// Path of a resolved dependency to sync. Can be passed multiple
+    /// times, all resolved dependencies are synced if not specified
+    #[arg(long)]
+    sync_path: Vec<String>,
+
+    /// Base url of the GitHub REST API
+    #[arg(long, default_value_t = DEFAULT_GITHUB_API_URL.to_owned())]
+    github_api_url: String,
+
+    /// Base url dependency files are fetched from, as
+    /// <url>/<owner>/<repo>/<branch>/<file>
+    #[arg(long, default_value_t = DEFAULT_GITHUB_RAW_URL.to_owned())]
+    github_raw_url: String,
+
+    /// Version of the GitHub REST API to request
+    #[arg(long, default_value_t = DEFAULT_GITHUB_API_VERSION.to_owned())]
+    github_api_version: String,
+
+    /// Where the organization's device repositories are hosted. With
+    /// gitlab, --org is the group and --default-remote must point to it
+    #[arg(long, value_enum, default_value_t = Host::Github)]
+    host: Host,
+
+    /// Hosting of a remote, as <remote>=<github|gitlab|generic>, for
+    /// remotes on hosts other than github.com and gitlab.com like
+    /// self hosted instances. Can be passed multiple times
+    #[arg(long, value_parser = parse_remote_kind)]
+    remote_kind: Vec<(String, RemoteKind)>,
+
+    /// Base url of the GitLab instance used with --host gitlab
+    #[arg(long, default_value_t = DEFAULT_GITLAB_URL.to_owned())]
+    gitlab_url: String,
+
+    /// Use clone bundles when syncing, useful on
+    /// mirrors that serve them
+    #[arg(long, default_value_t = false)]
+    clone_bundle: bool,
+
+    /// Number of parallel jobs of repo sync, defaults
+    /// to the number of CPUs
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+
+    /// Number of times repo sync is run again when it fails. It
+    /// resumes where it stopped, so transient failures converge
+    #[arg(long, default_value_t = 0)]
+    sync_retries: u32,
+
+    /// Print the repo sync command instead of running it
+    #[arg(long, default_value_t = false, requires = "sync")]
+    print_sync_cmd: bool,
+
+    /// Print the projects that will be synced with their remote, branch
+    /// and clone depth, and ask for confirmation before syncing
+    #[arg(long, default_value_t = false)]
+    plan: bool,
+
+    /// Do not ask for confirmation of the plan. Also implied
+    /// when stdin is not a terminal
+    #[arg(short, long, default_value_t = false)]
+    yes: bool,
+
+    /// Do not read from or write to the on-disk cache
+    #[arg(long, default_value_t = false)]
+    no_cache: bool,
+
+    /// Seconds after which cached entries are ignored. Cached dependency
+    /// files are always revalidated, the ttl only limits their lifetime
+    #[arg(long, default_value_t = DEFAULT_CACHE_TTL_SECS)]
+    cache_ttl: u64,
+
+    /// Ignore the cached repository list of the organization and
+    /// fetch it again
+    #[arg(long, default_value_t = false, conflicts_with = "no_cache")]
+    refresh_cache: bool,
+
+    /// Resolve dependencies only from the on-disk cache, however old the
+    /// entries are, without talking to github or the mirrors. The cache
+    /// is populated by every run without it
+    #[arg(long, default_value_t = false, conflicts_with_all = ["no_cache", "refresh_cache"])]
+    offline: bool,
+
+    /// Remote used for dependencies that neither specify a remote nor
+    /// are of the form owner/name. Defaults to flamingo-devices
+    #[arg(long)]
+    default_remote: Option<String>,
+
+    /// Branch used for dependencies on the github remote
+    /// that do not specify one
+    #[arg(long)]
+    github_default_branch: Option<String>,
+
+    /// Build variant to resolve dependencies for. Dependencies that
+    /// declare a different variant, or any variant when this is not
+    /// passed, are skipped
+    #[arg(long)]
+    variant: Option<String>,
+
+    /// Maximum size in bytes of a response body from GitHub
+    /// or of a dependency file
+    #[arg(long, default_value_t = DEFAULT_MAX_RESPONSE_BYTES)]
+    max_response_bytes: usize,
+
+    /// Maximum number of seconds to wait for the GitHub API
+    /// rate limit to reset before giving up
+    #[arg(long, default_value_t = DEFAULT_MAX_WAIT_SECS)]
+    max_wait: u64,
+
+    /// Seconds after which a request to GitHub or
+    /// the mirrors is given up on
+    #[arg(long, default_value_t = DEFAULT_TIMEOUT_SECS)]
+    timeout: u64,
+
+    /// Number of times a request is attempted when it times out, fails
+    /// to connect or the server responds with a 5xx or 429
+    #[arg(long, default_value_t = DEFAULT_MAX_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts: u32,
+
+    /// Maximum number of dependency files fetched at the same time
+    #[arg(long, default_value_t = DEFAULT_CONCURRENCY, value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: u32,
+
+    /// Write the graph of the resolved dependencies to this
+    /// file in Graphviz DOT format
+    #[arg(long)]
+    graph: Option<String>,
+
+    /// Write the name, path, clone url, remote and revision of every
+    /// resolved dependency to this file. Written as CSV if the file
+    /// ends with .csv, as JSON otherwise
+    #[arg(long)]
+    inventory: Option<String>,
+
+    /// JSON file with an object mapping repositories (owner/name or name)
+    /// or paths to the branch they should be synced at, overriding the
+    /// branch declared in the dependency files
+    #[arg(long)]
+    branch_map: Option<String>,
+
+    /// JSON file with an object mapping repositories (owner/name or name)
+    /// or paths to an object with the target_path, remote, branch or
+    /// clone-depth to use for them, applied after all devices are resolved
+    #[arg(long)]
+    dep_override: Option<String>,
+
+    /// Write the manifests the way `repo manifest` does, with remotes
+    /// sorted by name, projects sorted by path and attributes in a fixed
+    /// order, so that the two can be compared
+    #[arg(long, default_value_t = false)]
+    canonical: bool,
+
+    /// Emit projects with a clone-depth of 1 as blob filtered partial
+    /// clones instead, using the clone-filter attribute. Needs a repo
+    /// version with partial clone support
+    #[arg(long, default_value_t = false)]
+    partial_clone: bool,
+
+    /// clone-depth of the projects whose dependency does not specify one.
+    /// The ones that do keep theirs, 0 clones full history as without it.
+    /// Applied before --partial-clone, so 1 makes them partial clones too
+    #[arg(long)]
+    default_clone_depth: Option<u32>,
+
+    /// Organization to search device repositories in. Dependencies that
+    /// neither specify a remote nor are owner/name are looked up in it on
+    /// github if it's not FlamingoOS-Devices. Defaults to the owner of
+    /// --device-repo if passed, FlamingoOS-Devices otherwise
+    #[arg(long)]
+    org: Option<String>,
+
+    /// Do not verify TLS certificates when talking to github or the
+    /// mirrors. Only for internal mirrors with self signed certificates,
+    /// INSECURE
+    #[arg(long, default_value_t = false)]
+    insecure_skip_tls_verify: bool,
+
+    /// Command to run after the manifests are written. Path of the
+    /// manifest and the number of projects in it are passed as arguments
+    /// and as ROOMSERVICE_MANIFEST and ROOMSERVICE_PROJECT_COUNT environment
+    /// variables. The path is the local manifests dir with
+    /// --split-per-device
+    #[arg(long)]
+    post_resolve_hook: Option<String>,
+
+    /// Write the commit the branch of every resolved dependency currently
+    /// points to to this file, or read them from it with --locked
+    #[arg(long)]
+    lockfile: Option<String>,
+
+    /// Resolve and sync the dependencies at the commits in --lockfile
+    /// instead of their branches
+    #[arg(long, default_value_t = false, requires = "lockfile")]
+    locked: bool,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// List all device repositories in the organization
+    /// named device_<brand>_<pattern>
+    ListDevices {
+        /// Regex matching the whole codename, like lmi or [lu]mi
+        pattern: String,
+    },
+    /// Remove the generated manifests from the local manifests
+    /// dir of the first manifest root and clear the cache
+    Clean {
+        /// Only print what would be removed
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Also remove device_manifest.xml and common_manifest.xml
+        /// if they were not generated by roomservice
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
+    /// Check the dependency files of the whole tree without writing
+    /// anything, reporting every problem found along with the repository
+    /// it is in. Starts from the device repository, or from a local
+    /// dependency file
+    Validate {
+        /// Local dependency file to start from instead of the one of the
+        /// device repository. Files ending in .toml, .yaml or .yml are read
+        /// in that format, others in --deps-format
+        #[arg(long)]
+        file: Option<String>,
+    },
+}
+
+/// Hosting of the organization's device repositories.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Host {
+    /// Repositories are listed with the GitHub REST API
+    Github,
+    /// Repositories are listed with the projects API of the GitLab group
+    Gitlab,
+}
+
+/// REST API of the host used to list the repositories of the organization.
+struct HostApi<'a> {
+    client: &'a Client,
+    host: Host,
+    org: &'a str,
+    url: String,
+    version: &'a str,
+    max_response_bytes: usize,
+    max_attempts: u32,
+    max_wait: Duration,
+    cache: Option<&'a Cache>,
+    refresh_cache: bool,
+    offline: bool,
+}
+
+impl<'a> HostApi<'a> {
+    fn new(client: &'a Client, args: &'a Args, cache: Option<&'a Cache>) -> Self {
+        let url = match args.host {
+            Host::Github => args.github_api_url.trim_end_matches('/').to_owned(),
+            Host::Gitlab => format!("{}/api/v4", args.gitlab_url.trim_end_matches('/')),
+        };
+        Self {
+            client,
+            host: args.host,
+            org: get_org(args),
+            url,
+            version: &args.github_api_version,
+            max_response_bytes: args.max_response_bytes,
+            max_attempts: args.max_attempts,
+            max_wait: Duration::from_secs(args.max_wait),
+            cache,
+            refresh_cache: args.refresh_cache,
+            offline: args.offline,
+        }
+    }
+
+    /// Key the repository list is cached under, so that an org and a
+    /// gitlab group of the same name do not share one.
+    fn cache_key(&self) -> String {
+        match self.host {
+            Host::Github => self.org.to_owned(),
+            Host::Gitlab => format!("gitlab/{}", self.org),
+        }
+    }
+}
+
+/// State shared across the whole dependency resolution.
+/// Dependency file of a repository once it was fetched, None if it has none.
+type DependencyFile = Arc<OnceCell<Option<String>>>;
+
+struct Resolver<'a> {
+    client: &'a Client,
+    raw_url: &'a str,
+    host: Host,
+    remotes: &'a HashMap<String, Remote>,
+    /// Kinds of remotes passed with --remote-kind
+    remote_kinds: HashMap<&'a str, RemoteKind>,
+    cache: Option<&'a Cache>,
+    defaults: Defaults<'a>,
+    variant: Option<&'a str>,
+    deps_format: DepsFormat,
+    local_deps: Option<&'a str>,
+    branch_map: &'a HashMap<String, String>,
+    locked_revisions: &'a HashMap<String, String>,
+    max_response_bytes: usize,
+    max_attempts: u32,
+    /// Limits the number of dependency files fetched at once
+    requests: Semaphore,
+    /// Projects the dependency files remove, in the order found
+    removals: Mutex<Vec<RemovedProject>>,
+    /// Dependency files by repository and branch, so that each one is
+    /// fetched once however many repos in the tree depend on it
+    dependency_files: Mutex<HashMap<(String, String), DependencyFile>>,
+    offline: bool,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(
+        args: &'a Args,
+        client: &'a Client,
+        manifest_remotes: &'a ManifestRemotes,
+        cache: Option<&'a Cache>,
+        branch_map: &'a HashMap<String, String>,
+        locked_revisions: &'a HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let remotes = &manifest_remotes.remotes;
+        let default_remote = match args.default_remote.as_ref() {
+            Some(remote) if !remotes.contains_key(remote) => {
+                return Err(format!(
+                    "--default-remote {remote} is not defined in any manifest"
+                ));
+            }
+            Some(remote) => remote.as_str(),
+            None => remotes::FLAMINGO_DEVICES,
+        };
+        Ok(Self {
+            client,
+            raw_url: args.github_raw_url.trim_end_matches('/'),
+            host: args.host,
+            remotes,
+            cache,
+            defaults: Defaults {
+                remote: default_remote,
+                owner: Some(get_org(args))
+                    .filter(|org| !is_default_org(org) && args.default_remote.is_none()),
+                github_branch: args.github_default_branch.as_deref(),
+                revision: manifest_remotes.default_revision.as_deref(),
+            },
+            variant: args.variant.as_deref(),
+            deps_format: args.deps_format,
+            local_deps: args.local_deps.as_deref(),
+            remote_kinds: args
+                .remote_kind
+                .iter()
+                .map(|(remote, kind)| (remote.as_str(), *kind))
+                .collect(),
+            branch_map,
+            locked_revisions,
+            max_response_bytes: args.max_response_bytes,
+            max_attempts: args.max_attempts,
+            requests: Semaphore::new(args.concurrency as usize),
+            removals: Mutex::new(Vec::new()),
+            dependency_files: Mutex::new(HashMap::new()),
+            offline: args.offline,
+        })
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    config::apply(&mut args, &matches)?;
+    if args.output == Output::Json {
+        // stdout is reserved for the json
+        args.quiet = true;
+    }
+    init_logger(&args);
+    run(args).await
+}
+
+/// Runs roomservice with the parsed arguments.
+async fn run(args: Args) -> Result<(), String> {
+    if let Some(org) = args.org.as_ref() {
+        if org.is_empty() || org.contains('/') {
+            return Err(format!("--org {org} is not an organization name"));
+        }
+    }
+
+    if args.host == Host::Gitlab && args.default_remote.is_none() {
+        return Err(String::from(
+            "--host gitlab needs --default-remote, the remote of the group",
+        ));
+    }
+
+    let client = http::build_client(
+        Duration::from_secs(args.timeout),
+        args.insecure_skip_tls_verify,
+    )?;
+
+    match args.command.as_ref() {
+        Some(Action::ListDevices { pattern }) => {
+            return list_devices(&client, pattern, &args).await;
+        }
+        Some(Action::Clean { dry_run, force }) => return clean(&args, *dry_run, *force),
+        Some(Action::Validate { .. }) if args.manifest_root.is_empty() => {
+            return Err(String::from(
+                "--manifest-root is required to check the remotes of dependencies",
+            ));
+        }
+        None if args.manifest_root.is_empty() => {
+            return Err(String::from(
+                "--manifest-root is required, pass it or set manifest_root in roomservice.toml",
+            ));
+        }
+        Some(Action::Validate { .. }) | None => {}
+    }
 
-    let client = Client::new();
-    let repo_pattern = format!(r"device_.*_{}", &args.device_name);
-    let repo_regex = Regex::new(&repo_pattern).unwrap();
+    let manifest_dirs: Vec<String> = args
+        .manifest_root
+        .iter()
+        .map(|root| format!("{root}/{SOURCE_MANIFESTS_DIR}"))
+        .collect();
+    let manifest_remotes = remotes::get_all_remotes(&manifest_dirs, args.lenient)?;
+    let remotes = &manifest_remotes.remotes;
 
-    if !args.quiet {
-        println!("Searching for {} repository in {ORG}", &args.device_name);
+    if args.offline && args.lockfile.is_some() && !args.locked {
+        return Err(String::from(
+            "Writing --lockfile needs the network, pass --locked to use it --offline",
+        ));
+    }
+
+    if let Some(revision) = args.device_revision.as_ref() {
+        if args.device_name.len() > 1 {
+            return Err(String::from(
+                "--device-revision can only be used with a single device",
+            ));
+        }
+        if !lockfile::is_sha(revision) {
+            return Err(format!("--device-revision {revision} is not a commit sha"));
+        }
     }
-    let device_repo = find_device_repo(&client, &repo_regex, 1).await?;
-    if !args.quiet {
-        println!("Found device repository {device_repo}");
+
+    args.device_name
+        .iter()
+        .try_for_each(|device_name| check_device_name(device_name))?;
+
+    if args.local_deps.is_some() && args.device_name.len() > 1 {
+        return Err(String::from(
+            "--local-deps can only be used with a single device",
+        ));
     }
 
-    let remotes =
-        remotes::get_all_remotes(&format!("{}/{SOURCE_MANIFESTS_DIR}", args.manifest_root))?;
+    let cache = get_cache(&args);
+    let locked_revisions = match args.lockfile.as_ref().filter(|_| args.locked) {
+        Some(file) => lockfile::read(file)?,
+        None => HashMap::with_capacity(0),
+    };
+    let branch_map = match args.branch_map.as_ref() {
+        Some(file) => read_branch_map(file)?,
+        None => HashMap::with_capacity(0),
+    };
+    let dependency_overrides = match args.dep_override.as_ref() {
+        Some(file) => overrides::read(file)?,
+        None => HashMap::with_capacity(0),
+    };
+    let resolver = Resolver::new(
+        &args,
+        &client,
+        &manifest_remotes,
+        cache.as_ref(),
+        &branch_map,
+        &locked_revisions,
+    )?;
 
-    let local_manifest_dir = format!("{}/{LOCAL_MANIFESTS_DIR}", args.manifest_root);
+    if let Some(Action::Validate { file }) = args.command.as_ref() {
+        return validate::run(&resolver, file.as_deref(), &args).await;
+    }
+
+    let local_manifest_dir = format!("{}/{LOCAL_MANIFESTS_DIR}", args.manifest_root[0]);
     fs::create_dir_all(&local_manifest_dir)
         .map_err(|err| format!("failed to create local manifest dir: {err}"))?;
 
-    let device_dependency = Dependency {
-        name: format!("{ORG}/{device_repo}"),
-        path: device_repo.replace("_", "/"),
-        remote: remotes::FLAMINGO_DEVICES.to_owned(),
-        branch: args.branch.to_owned(),
-        clone_depth: None,
+    let device_names = match args.device_repo.as_ref() {
+        Some(device_repo) => vec![get_device_repo_codename(device_repo)?],
+        None => args.device_name.clone(),
     };
-    let all_dependencies = get_dependencies(
-        &client,
+    let mut device_dependencies = Vec::with_capacity(device_names.len());
+    for device_name in &device_names {
+        let dependencies = resolve_device(&resolver, device_name, &args).await?;
+        device_dependencies.push((device_name.as_str(), dependencies));
+    }
+    overrides::apply(
+        &dependency_overrides,
+        device_dependencies
+            .iter_mut()
+            .flat_map(|(_, dependencies)| dependencies),
+    );
+    if let Some(depth) = args.default_clone_depth.filter(|depth| *depth > 0) {
+        device_dependencies
+            .iter_mut()
+            .flat_map(|(_, dependencies)| dependencies)
+            .filter(|dependency| {
+                dependency.clone_depth.is_none() && dependency.clone_filter.is_none()
+            })
+            .for_each(|dependency| dependency.clone_depth = Some(depth.to_string()));
+    }
+    if args.partial_clone {
+        use_partial_clones(
+            device_dependencies
+                .iter_mut()
+                .flat_map(|(_, dependencies)| dependencies),
+        );
+    }
+
+    let manifest_options = ManifestOptions {
+        format: args.manifest_format,
+        canonical: args.canonical,
+        dry_run: args.dry_run,
+    };
+    if let Some(file) = args.graph.as_ref() {
+        manifest_options.write_with("graph", file, |file| {
+            graph::write(
+                device_dependencies
+                    .iter()
+                    .flat_map(|(_, dependencies)| dependencies),
+                file,
+            )
+        })?;
+    }
+    if let Some(file) = args.lockfile.as_ref() {
+        let dependencies: Vec<&Dependency> = device_dependencies
+            .iter()
+            .flat_map(|(_, dependencies)| dependencies)
+            .collect();
+        if args.locked {
+            dependencies
+                .iter()
+                .filter(|dependency| !locked_revisions.contains_key(&dependency.path))
+                .for_each(|dependency| {
+                    warn!("{} is not in {file}, using its branch", dependency.path)
+                });
+        } else {
+            manifest_options.write_with("lockfile", file, |file| {
+                lockfile::write(&dependencies, remotes, file)
+            })?;
+        }
+    }
+    let missing_remotes = get_missing_remotes(
+        device_dependencies
+            .iter()
+            .flat_map(|(_, dependencies)| dependencies),
+        remotes,
         &local_manifest_dir,
-        &device_dependency,
-        &remotes,
-        args.quiet,
-    )
-    .await?;
-    let dependencies = create_manifest(device_dependency, all_dependencies, &local_manifest_dir)?;
+        &args,
+    )?;
+    let removals = resolver.removals.into_inner().unwrap();
+    let dependencies = if let Some(file) = args.merge_into.as_ref() {
+        merge_into_manifest(
+            device_dependencies
+                .into_iter()
+                .flat_map(|(_, dependencies)| dependencies),
+            &missing_remotes,
+            &removals,
+            file,
+            &manifest_options,
+        )?
+    } else if args.split_per_device {
+        create_split_manifests(
+            &device_dependencies,
+            &missing_remotes,
+            &removals,
+            &local_manifest_dir,
+            &manifest_options,
+        )?
+    } else {
+        create_manifest(
+            device_dependencies
+                .into_iter()
+                .flat_map(|(_, dependencies)| dependencies),
+            &missing_remotes,
+            &removals,
+            &local_manifest_dir,
+            if args.only_new {
+                ExistingManifest::AppendNew
+            } else if args.overwrite {
+                ExistingManifest::Replace
+            } else {
+                ExistingManifest::Merge
+            },
+            &manifest_options,
+        )?
+    };
+    if let Some(file) = args.inventory.as_ref() {
+        manifest_options.write_with("inventory", file, |file| {
+            inventory::write(&dependencies, remotes, file)
+        })?;
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+    if let Some(hook) = args.post_resolve_hook.as_ref() {
+        let manifest_path = match args.merge_into.as_ref() {
+            Some(file) => file.to_owned(),
+            None if args.split_per_device => local_manifest_dir.to_owned(),
+            None => manifest::get_path(&local_manifest_dir, DEVICE_MANIFEST_FILE_NAME),
+        };
+        run_post_resolve_hook(hook, &manifest_path, dependencies.len())?;
+    }
     if args.sync {
-        let status = sync_dependencies(&dependencies)?;
-        println!("child process exited with status: {}", status.to_string());
+        let paths = get_sync_paths(&dependencies, &args.sync_path)?;
+        let jobs = args
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |cpus| cpus.get() as u32));
+        let command = get_sync_command(&paths, args.clone_bundle, jobs, args.quiet);
+        if args.print_sync_cmd {
+            println!("{}", format_command(&command));
+            return Ok(());
+        }
+        if args.plan {
+            let ask = !args.yes && io::stdin().is_terminal();
+            let proceed = plan_sync(
+                &dependencies,
+                &paths,
+                ask,
+                &mut io::stdin().lock(),
+                &mut io::stdout(),
+            )?;
+            if !proceed {
+                println!("Not syncing");
+                return Ok(());
+            }
+        }
+        let status = sync_dependencies(command, &paths, args.sync_retries, args.quiet)?;
+        info!("child process exited with status: {}", status);
+    } else if args.plan {
+        let paths = get_sync_paths(&dependencies, &args.sync_path)?;
+        print_plan(&dependencies, &paths, &mut io::stdout())?;
+    } else if args.output == Output::Json {
+        let json: Vec<JsonValue> = dependencies
+            .iter()
+            .map(|dependency| {
+                object! {
+                    name: dependency.name.as_str(),
+                    path: dependency.path.as_str(),
+                    remote: dependency.remote.as_str(),
+                    branch: dependency.branch.as_str(),
+                    clone_depth: dependency.clone_depth.as_deref(),
+                }
+            })
+            .collect();
+        println!("{}", JsonValue::Array(json).pretty(4));
     } else {
         println!("Projects are:");
         dependencies.iter().for_each(|dep| println!("{}", dep.path));
@@ -127,157 +901,2828 @@ async fn main() -> Result<(), String> {
     Ok(())
 }
 
-/// Attempts to get the name of the repo for the device name.
-/// The results from github api is paginated, therefore this
-/// function is recusively called until the all results are
-/// covered or a repo with matching pattern is found.
-#[async_recursion]
-async fn find_device_repo(client: &Client, regex: &Regex, page: u32) -> Result<String, String> {
-    let response = client
-        .get(format!("https://api.github.com/orgs/{ORG}/repos"))
-        .header("accept", "application/vnd.github+json")
-        .header("User-Agent", ORG)
-        .query(&[
-            ("type", "public"),
-            ("per_page", "100"),
-            ("page", &page.to_string()),
-        ])
-        .send()
-        .await
-        .map_err(|err| format!("GET request to list repositories failed: {err}"))?;
-    if !response.status().is_success() {
-        return Err(format!(
-            "GET request to list repositories failed. Status code = {}",
-            response.status().as_str()
-        ));
-    }
-    let json_response = response
-        .text()
-        .await
-        .map_err(|err| format!("Failed to get json response: {err}"))?;
-    let json = json::parse(&json_response).map_err(|err| format!("Failed to parse json: {err}"))?;
-    match json {
-        JsonValue::Array(repos) => {
-            if repos.is_empty() {
-                return Err(String::from("Failed to find repository"));
-            }
-            let repo_name = repos
-                .iter()
-                .filter_map(|value| {
-                    if let JsonValue::Object(object) = value {
-                        object
-                            .get(RESPONSE_KEY_NAME)
-                            .map(|value| value.as_str())
-                            .flatten()
-                    } else {
-                        None
-                    }
-                })
-                .find(|name| regex.is_match(name));
-            if repo_name.is_none() {
-                find_device_repo(client, regex, page + 1).await
-            } else {
-                Ok(repo_name.unwrap().to_owned())
-            }
-        }
-        other => Err(format!(
-            "GET response returned unexpected json response: {}",
-            other.pretty(4)
-        )),
+async fn list_devices(client: &Client, pattern: &str, args: &Args) -> Result<(), String> {
+    let repo_regexes = get_device_repo_regexes(pattern)?;
+    let cache = get_cache(args);
+    let api = HostApi::new(client, args, cache.as_ref());
+    let device_repos = list_device_repos(&api, &repo_regexes).await?;
+    if device_repos.is_empty() {
+        return Err(format!("No device repositories match {pattern}"));
     }
+    device_repos.iter().for_each(|repo| println!("{repo}"));
+    Ok(())
 }
 
-fn get_deps_url(repo_name: &str, branch: &str) -> String {
-    format!("https://raw.githubusercontent.com/{repo_name}/{branch}/{DEPENDENCY_FILE_NAME}")
+/// Regexes matching the whole names of the device repos whose codename
+/// matches the pattern, in the order of [`DEVICE_REPO_BRANDS`].
+fn get_device_repo_regexes(pattern: &str) -> Result<Vec<Regex>, String> {
+    DEVICE_REPO_BRANDS
+        .iter()
+        .map(|brand| {
+            Regex::new(&format!("^device_{brand}_(?:{pattern})$"))
+                .map_err(|err| format!("Invalid pattern {pattern}: {err}"))
+        })
+        .collect()
 }
 
-/// This is where the magic happens. The starting point will
-/// be device repo, dependecies in it will be fetched, and then
-/// recursively checks for their dependencies as well.
-#[async_recursion]
-async fn get_dependencies(
-    client: &Client,
-    local_manifest_dir: &str,
-    dependency: &Dependency,
-    remotes: &HashMap<String, Remote>,
-    quiet: bool,
-) -> Result<Vec<Dependency>, String> {
-    if !quiet {
-        println!("Looking for dependencies in {}", dependency.name);
+fn get_cache(args: &Args) -> Option<Cache> {
+    if args.no_cache {
+        return None;
     }
+    // Offline, any cached entry is better than none
+    let ttl = if args.offline {
+        Duration::MAX
+    } else {
+        Duration::from_secs(args.cache_ttl)
+    };
+    Cache::new(ttl)
+        .map_err(|err| warn!("Cache is disabled: {err}"))
+        .ok()
+}
 
-    let deps_url = get_deps_url(&dependency.name, &dependency.branch);
-    let response = client
-        .get(&deps_url)
-        .send()
-        .await
-        .map_err(|err| format!("Failed to get dependency file from {deps_url}: {err}"))?;
-    if response.status() == StatusCode::NOT_FOUND {
-        if !quiet {
-            println!("No dependencies in {}", dependency.name);
+/// Logs to stderr at the level picked by --quiet and --verbose, which
+/// RUST_LOG can override. Logs of the dependencies are only shown from
+/// warnings on, since they are noise for users of roomservice.
+fn init_logger(args: &Args) {
+    let level = if args.quiet {
+        LevelFilter::Warn
+    } else if args.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module(env!("CARGO_CRATE_NAME"), level)
+        .parse_default_env()
+        .format(|buf, record| {
+            let message = record.args().to_string();
+            match record.level() {
+                Level::Error => writeln!(buf, "{}", format!("Error: {message}").red()),
+                Level::Warn => writeln!(buf, "{}", format!("Warning: {message}").yellow()),
+                Level::Info => writeln!(buf, "{message}"),
+                Level::Debug | Level::Trace => writeln!(buf, "{}", message.dimmed()),
+            }
+        })
+        .init();
+}
+
+/// Removes the generated manifests and the cache dir.
+fn clean(args: &Args, dry_run: bool, force: bool) -> Result<(), String> {
+    let manifest_root = args
+        .manifest_root
+        .first()
+        .ok_or(String::from("--manifest-root is required to clean"))?;
+    let local_manifest_dir = format!("{manifest_root}/{LOCAL_MANIFESTS_DIR}");
+    let mut paths = get_generated_manifests(Path::new(&local_manifest_dir), force)?;
+    if !args.no_cache {
+        match Cache::new(Duration::from_secs(args.cache_ttl)) {
+            Ok(cache) if cache.dir().exists() => paths.push(cache.dir().to_path_buf()),
+            Ok(_) => {}
+            Err(err) => warn!("Not clearing the cache: {err}"),
         }
-        return Ok(Vec::with_capacity(0));
     }
-    if !response.status().is_success() {
-        return Err(format!(
-            "GET request to {deps_url} failed. Status code = {}",
-            response.status().as_str()
-        ));
+    if paths.is_empty() {
+        println!("Nothing to clean");
     }
-    let json_response = response
-        .text()
-        .await
-        .map_err(|err| format!("Failed to get dependency file as json: {err}"))?;
-    let deps = json::parse(&json_response).map_err(|err| format!("Failed to parse json: {err}"))?;
-    match deps {
-        JsonValue::Array(repos) => {
-            let mut dependencies = Vec::new();
-            for repo in repos {
-                let sub_dependency = Dependency::get(repo, remotes)?;
-                let sub_dependencies =
-                    get_dependencies(client, local_manifest_dir, &sub_dependency, remotes, quiet)
-                        .await?;
-                dependencies.push(sub_dependency);
-                dependencies.extend(sub_dependencies);
-            }
-            Ok(dependencies)
+    for path in paths {
+        if dry_run {
+            println!("Would remove {}", path.display());
+            continue;
         }
-        other => Err(format!("Unexpected element {other} in dependency json")),
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.map_err(|err| format!("Failed to remove {}: {err}", path.display()))?;
+        info!("Removed {}", path.display());
     }
+    Ok(())
 }
 
-fn create_manifest(
-    device_dependency: Dependency,
-    all_dependencies: Vec<Dependency>,
-    local_manifest_dir: &str,
+/// Returns the manifests in the dir that were generated by roomservice.
+/// Manifests with the default names that lack the generated header are
+/// only returned when forced, other manifests are never returned.
+fn get_generated_manifests(dir: &Path, force: bool) -> Result<Vec<PathBuf>, String> {
+    if !dir.exists() {
+        return Ok(Vec::with_capacity(0));
+    }
+    let entries =
+        fs::read_dir(dir).map_err(|err| format!("Failed to read dir {}: {err}", dir.display()))?;
+    let mut manifests = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| format!("Failed to open DirEntry: {err}"))?
+            .path();
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != MANIFEST_EXT) {
+            continue;
+        }
+        let is_generated = fs::read(&path)
+            .ok()
+            .and_then(|bytes| parse_xml(&bytes).ok())
+            .is_some_and(|element| manifest::is_generated(&element));
+        let has_default_name =
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| {
+                    stem == DEVICE_MANIFEST_FILE_NAME || stem == COMMON_MANIFEST_FILE_NAME
+                });
+        if is_generated || (has_default_name && force) {
+            manifests.push(path);
+        } else if has_default_name {
+            warn!(
+                "Not removing {} since it was not generated by roomservice, use --force to remove it anyway",
+                path.display()
+            );
+        }
+    }
+    manifests.sort();
+    Ok(manifests)
+}
+
+/// Prints the plan, then asks whether to proceed with the sync if ask
+/// is set. Returns whether to proceed.
+fn plan_sync(
+    dependencies: &[Dependency],
+    paths: &[&str],
+    ask: bool,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<bool, String> {
+    print_plan(dependencies, paths, output)?;
+    if !ask {
+        return Ok(true);
+    }
+    confirm("Proceed with sync?", input, output)
+}
+
+/// Prints the dependencies that will be synced in order.
+fn print_plan(
+    dependencies: &[Dependency],
+    paths: &[&str],
+    output: &mut impl Write,
+) -> Result<(), String> {
+    let write_error = |err: io::Error| format!("Failed to print plan: {err}");
+    writeln!(output, "Sync plan:").map_err(write_error)?;
+    paths
+        .iter()
+        .filter_map(|path| {
+            dependencies
+                .iter()
+                .find(|dependency| dependency.path == *path)
+        })
+        .try_for_each(|dependency| {
+            writeln!(
+                output,
+                "{} (remote: {}, branch: {}, clone-depth: {})",
+                dependency.path,
+                dependency.remote,
+                dependency.branch,
+                dependency.clone_depth.as_deref().unwrap_or("full")
+            )
+            .map_err(write_error)
+        })
+}
+
+/// Asks a yes / no question, defaulting to no.
+fn confirm(
+    question: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<bool, String> {
+    write!(output, "{question} [y/N] ")
+        .and_then(|_| output.flush())
+        .map_err(|err| format!("Failed to print question: {err}"))?;
+    let mut answer = String::new();
+    input
+        .read_line(&mut answer)
+        .map_err(|err| format!("Failed to read answer: {err}"))?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Finds the repository of the device and resolves all of its
+/// dependencies. Device dependency is the first element.
+async fn resolve_device(
+    resolver: &Resolver<'_>,
+    device_name: &str,
+    args: &Args,
 ) -> Result<Vec<Dependency>, String> {
+    let device_dependency = get_device_dependency(resolver, device_name, args).await?;
+    let all_dependencies = get_dependencies(resolver, &device_dependency, &[]).await?;
     let mut dependencies = Vec::with_capacity(all_dependencies.len() + 1);
     dependencies.push(device_dependency);
     dependencies.extend(all_dependencies);
-    let mut manifest = Manifest::new();
-    manifest.add_dependencies(&dependencies);
-    manifest.write(&local_manifest_dir)?;
     Ok(dependencies)
 }
 
-fn sync_dependencies(dependencies: &Vec<Dependency>) -> Result<ExitStatus, String> {
-    let sync_args = [
-        "--force-sync",
-        "--no-tags",
-        "--current-branch",
-        "--no-clone-bundle",
-    ];
-    let mut child = Command::new("repo")
-        .arg("sync")
-        .args(sync_args)
-        .args(
-            dependencies
-                .iter()
-                .map(|dependency| dependency.path.as_str()),
-        )
-        .spawn()
-        .map_err(|err| format!("failed to spawn repo sync process: {err}"))?;
-    child
-        .wait()
-        .map_err(|err| format!("failed to wait on child process: {err}"))
+/// Dependency of the device repository, searched for in the
+/// organization unless --device-repo is passed.
+async fn get_device_dependency(
+    resolver: &Resolver<'_>,
+    device_name: &str,
+    args: &Args,
+) -> Result<Dependency, String> {
+    let mut device_dependency = match args.device_repo.as_ref() {
+        Some(device_repo) => get_device_repo_dependency(device_repo, args)?,
+        None => {
+            info!(
+                "Searching for {} repository in {}",
+                device_name,
+                get_org(args)
+            );
+            let api = HostApi::new(resolver.client, args, resolver.cache);
+            let device_repo = find_device_repo(&api, device_name).await?;
+            info!("Found device repository {device_repo}");
+
+            Dependency {
+                name: format!("{}/{device_repo}", api.org),
+                path: device_repo.replace('_', "/"),
+                remote: get_device_remote(args, api.org).to_owned(),
+                branch: args.branch.to_owned(),
+                clone_depth: None,
+                clone_filter: None,
+                upstream: None,
+                groups: None,
+                parent: None,
+            }
+        }
+    };
+    apply_branch_map(resolver, &mut device_dependency);
+    apply_locked_revision(resolver, &mut device_dependency);
+    if let Some(revision) = args.device_revision.as_ref() {
+        // repo needs the branch the commit is on to sync it with -c
+        device_dependency.upstream = Some(device_dependency.branch.to_owned());
+        device_dependency.branch = revision.to_owned();
+    }
+    Ok(device_dependency)
+}
+
+/// Organization the device repository belongs to. --org takes
+/// precedence over the owner of --device-repo.
+fn get_org(args: &Args) -> &str {
+    args.org
+        .as_deref()
+        .or_else(|| {
+            args.device_repo
+                .as_deref()
+                .and_then(|device_repo| split_device_repo(device_repo).ok())
+                .map(|(owner, _)| owner)
+        })
+        .unwrap_or(ORG)
+}
+
+/// Format the resolved projects are printed in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Output {
+    /// A list of their paths
+    Text,
+    /// A JSON array of objects with their name, path, remote,
+    /// branch and clone_depth
+    Json,
+}
+
+/// How the generated manifests are written.
+struct ManifestOptions {
+    format: ManifestFormat,
+    canonical: bool,
+    /// Print the manifests instead of writing them
+    dry_run: bool,
+}
+
+impl ManifestOptions {
+    fn write(&self, mut manifest: Manifest, path: &str) -> Result<(), String> {
+        if self.canonical {
+            manifest.canonicalize();
+        }
+        if self.dry_run {
+            println!("{path}:");
+            return manifest.print();
+        }
+        manifest.write_file(path)
+    }
+
+    /// Writes a file next to the manifests with `write`, unless this is a
+    /// dry run, which only prints where it would have gone.
+    fn write_with<F>(&self, what: &str, path: &str, write: F) -> Result<(), String>
+    where
+        F: FnOnce(&str) -> Result<(), String>,
+    {
+        if self.dry_run {
+            println!("Would write {what} to {path}");
+            return Ok(());
+        }
+        write(path)
+    }
+}
+
+/// What happens to the projects of an existing device manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExistingManifest {
+    /// The manifest is written from scratch
+    Replace,
+    /// Only projects at new paths are appended to it
+    AppendNew,
+    /// Projects are updated by path and new ones appended, if the
+    /// manifest was written by hand. Generated ones are replaced.
+    Merge,
+}
+
+/// GitHub organization names are case insensitive.
+fn is_default_org(org: &str) -> bool {
+    org.eq_ignore_ascii_case(ORG)
+}
+
+/// Device names are used as file names with --split-per-device, so
+/// they must not point outside of the local manifests dir.
+fn check_device_name(device_name: &str) -> Result<(), String> {
+    if device_name.is_empty() || device_name.contains('/') || device_name.contains("..") {
+        return Err(format!("--device-name {device_name} is not a device name"));
+    }
+    Ok(())
+}
+
+/// Splits the device repo passed as owner/name.
+fn split_device_repo(device_repo: &str) -> Result<(&str, &str), String> {
+    device_repo
+        .split_once('/')
+        .filter(|(owner, name)| !owner.is_empty() && !name.is_empty() && !name.contains('/'))
+        .ok_or(format!(
+            "--device-repo {device_repo} is malformed, expected owner/name"
+        ))
+}
+
+/// Codename of the device is the last segment of device_<brand>_<codename>.
+fn get_device_repo_codename(device_repo: &str) -> Result<String, String> {
+    let (_, name) = split_device_repo(device_repo)?;
+    Ok(name.rsplit('_').next().unwrap_or(name).to_owned())
+}
+
+/// Remote of the device repository. Repositories on gitlab are
+/// on the --default-remote.
+fn get_device_remote<'a>(args: &'a Args, owner: &str) -> &'a str {
+    match (args.host, args.default_remote.as_deref()) {
+        (Host::Gitlab, Some(remote)) => remote,
+        _ if is_default_org(owner) => remotes::FLAMINGO_DEVICES,
+        _ => remotes::GITHUB,
+    }
+}
+
+fn get_device_repo_dependency(device_repo: &str, args: &Args) -> Result<Dependency, String> {
+    let (owner, name) = split_device_repo(device_repo)?;
+    Ok(Dependency {
+        name: device_repo.to_owned(),
+        path: name.replace('_', "/"),
+        remote: get_device_remote(args, owner).to_owned(),
+        branch: args.branch.to_owned(),
+        clone_depth: None,
+        clone_filter: None,
+        upstream: None,
+        groups: None,
+        parent: None,
+    })
+}
+
+/// Attempts to get the name of the repo for the device name.
+async fn find_device_repo(api: &HostApi<'_>, device_name: &str) -> Result<String, String> {
+    let repos = get_all_org_repos(api).await?;
+    match_device_repo(&repos, device_name).ok_or(String::from("Failed to find repository"))
+}
+
+/// Finds the repo named device_<brand>_<device_name> exactly,
+/// see [`DEVICE_REPO_BRANDS`] for which brand takes precedence.
+fn match_device_repo(repos: &[String], device_name: &str) -> Option<String> {
+    let device_name = regex::escape(device_name);
+    DEVICE_REPO_BRANDS.iter().find_map(|brand| {
+        let regex = Regex::new(&format!("^device_{brand}_{device_name}$")).unwrap();
+        repos.iter().find(|name| regex.is_match(name)).cloned()
+    })
+}
+
+/// Lists the repositories in the organization matching the first of
+/// the regexes that any of them matches.
+async fn list_device_repos(api: &HostApi<'_>, regexes: &[Regex]) -> Result<Vec<String>, String> {
+    let repos = get_all_org_repos(api).await?;
+    Ok(regexes
+        .iter()
+        .map(|regex| -> Vec<String> {
+            repos
+                .iter()
+                .filter(|name| regex.is_match(name))
+                .cloned()
+                .collect()
+        })
+        .find(|matches| !matches.is_empty())
+        .unwrap_or_default())
+}
+
+/// Gets the names of all repositories of the organization, from the
+/// cache if it has a fresh copy, otherwise by walking every page of
+/// the listing and caching the result.
+async fn get_all_org_repos(api: &HostApi<'_>) -> Result<Vec<String>, String> {
+    if let Some(repos) = api
+        .cache
+        .filter(|_| !api.refresh_cache)
+        .and_then(|cache| cache.get_repos(&api.cache_key()))
+    {
+        return Ok(repos);
+    }
+    if api.offline {
+        return Err(format!(
+            "Repositories of {} are not cached, run once without --offline to cache them",
+            api.org
+        ));
+    }
+    let mut all_repos = Vec::new();
+    let mut page = 1;
+    loop {
+        let repos = get_org_repos(api, page).await?;
+        if repos.is_empty() {
+            break;
+        }
+        all_repos.extend(repos);
+        page += 1;
+    }
+    if let Some(cache) = api.cache {
+        if let Err(err) = cache.put_repos(&api.cache_key(), &all_repos) {
+            warn!("{err}");
+        }
+    }
+    Ok(all_repos)
+}
+
+/// Gets the names of the repositories in a page of the organization's
+/// repository listing. Returns an empty list past the last page.
+async fn get_org_repos(api: &HostApi<'_>, page: u32) -> Result<Vec<String>, String> {
+    let page = page.to_string();
+    let (url, request, name_key) = match api.host {
+        Host::Github => {
+            let url = format!("{}/orgs/{}/repos", api.url, api.org);
+            let request = api
+                .client
+                .get(&url)
+                .header("accept", "application/vnd.github+json")
+                .header("User-Agent", ORG)
+                .header("X-GitHub-Api-Version", api.version)
+                .query(&[("type", "public"), ("per_page", "100"), ("page", &page)]);
+            (url, request, RESPONSE_KEY_NAME)
+        }
+        Host::Gitlab => {
+            let url = format!("{}/groups/{}/projects", api.url, api.org);
+            let request = api.client.get(&url).header("User-Agent", ORG).query(&[
+                ("visibility", "public"),
+                ("per_page", "100"),
+                ("page", &page),
+            ]);
+            // The name of a project may differ from its path, which is
+            // what the repository is cloned as
+            (url, request, RESPONSE_KEY_PATH)
+        }
+    };
+    debug!("Listing repositories from {url}, page {page}");
+    let response = http::send_rate_limited(request, api.max_attempts, api.max_wait)
+        .await
+        .map_err(|err| format!("GET request to list repositories failed: {err}"))?;
+    if !response.status().is_success() {
+        return Err(http::status_error(&url, &response));
+    }
+    let json_response = http::read_text(response, api.max_response_bytes)
+        .await
+        .map_err(|err| format!("Failed to get json response: {err}"))?;
+    let json = json::parse(&json_response).map_err(|err| format!("Failed to parse json: {err}"))?;
+    match json {
+        JsonValue::Array(repos) => Ok(repos
+            .iter()
+            .filter_map(|value| {
+                if let JsonValue::Object(object) = value {
+                    object
+                        .get(name_key)
+                        .and_then(|value| value.as_str())
+                        .map(|name| name.to_owned())
+                } else {
+                    None
+                }
+            })
+            .collect()),
+        other => Err(format!(
+            "GET response returned unexpected json response: {}",
+            other.pretty(4)
+        )),
+    }
+}
+
+/// Turns the shallow clones into blob filtered partial clones.
+fn use_partial_clones<'a>(dependencies: impl IntoIterator<Item = &'a mut Dependency>) {
+    dependencies
+        .into_iter()
+        .filter(|dependency| dependency.clone_depth.as_deref() == Some(SHALLOW_CLONE_DEPTH))
+        .for_each(|dependency| {
+            dependency.clone_depth = None;
+            dependency.clone_filter = Some(PARTIAL_CLONE_FILTER.to_owned());
+        });
+}
+
+/// Parses a --remote-kind value of the form <remote>=<kind>.
+fn parse_remote_kind(value: &str) -> Result<(String, RemoteKind), String> {
+    let (remote, kind) = value
+        .split_once('=')
+        .filter(|(remote, _)| !remote.is_empty())
+        .ok_or(format!("{value} is not of the form <remote>=<kind>"))?;
+    let kind = RemoteKind::from_str(kind, true)?;
+    Ok((remote.to_owned(), kind))
+}
+
+/// Url of the raw dependency file of the dependency, which depends
+/// on where its remote is hosted. Dependencies on the github remote
+/// and on remotes hosted on github are fetched from the raw url.
+fn get_deps_url(resolver: &Resolver<'_>, dependency: &Dependency) -> Result<String, String> {
+    let branch = &dependency.branch;
+    let file_name = resolver.deps_format.file_name();
+    if dependency.remote == remotes::GITHUB {
+        return Ok(format!(
+            "{}/{}/{branch}/{file_name}",
+            resolver.raw_url, dependency.name
+        ));
+    }
+    let remote = resolver.remotes.get(&dependency.remote).ok_or(format!(
+        "No such remote exists with the name {}",
+        dependency.remote
+    ))?;
+    let repo_url = format!(
+        "{}/{}",
+        remote.fetch.trim_end_matches('/'),
+        manifest::get_project_name(dependency)
+    );
+    // Self hosted gitlab instances are not recognizable by their url
+    let kind = match (
+        resolver.remote_kinds.get(remote.name.as_str()),
+        remote.kind(),
+    ) {
+        (Some(kind), _) => *kind,
+        (None, RemoteKind::Generic) if resolver.host == Host::Gitlab => RemoteKind::Gitlab,
+        (None, kind) => kind,
+    };
+    let url = match kind {
+        RemoteKind::Github => format!(
+            "{}/{}/{branch}/{file_name}",
+            resolver.raw_url, dependency.name
+        ),
+        RemoteKind::Gitlab => format!("{repo_url}/-/raw/{branch}/{file_name}"),
+        RemoteKind::Generic => format!("{repo_url}/raw/{branch}/{file_name}"),
+    };
+    Ok(url)
+}
+
+/// This is where the magic happens. The starting point will
+/// be device repo, dependecies in it will be fetched, and then
+/// recursively checks for their dependencies as well.
+/// Ancestors are the repos being resolved above this one, a
+/// dependency on any of them is a cycle and is not descended into.
+#[async_recursion]
+async fn get_dependencies(
+    resolver: &Resolver<'_>,
+    dependency: &Dependency,
+    ancestors: &[String],
+) -> Result<Vec<Dependency>, String> {
+    info!("Looking for dependencies in {}", dependency.name);
+
+    // Only the device repo has no parent
+    let (content, format) = match (resolver.local_deps, dependency.parent.as_ref()) {
+        (Some(file), None) => {
+            let content = fs::read_to_string(file)
+                .map_err(|err| format!("Failed to read dependency file {file}: {err}"))?;
+            let format = DepsFormat::from_path(file).unwrap_or(resolver.deps_format);
+            (Some(content), format)
+        }
+        _ => (
+            fetch_dependency_file_once(resolver, dependency).await?,
+            resolver.deps_format,
+        ),
+    };
+    let content = match content {
+        Some(content) => content,
+        None => {
+            debug!("No dependencies in {}", dependency.name);
+            return Ok(Vec::with_capacity(0));
+        }
+    };
+    let deps = format.parse(&content).map_err(|err| {
+        format!(
+            "Dependency file of {} is not valid {format}: {err}",
+            dependency.name
+        )
+    })?;
+    let violations = schema::validate(&deps);
+    if !violations.is_empty() {
+        return Err(format!(
+            "Dependency file of {} is malformed:\n{}",
+            dependency.name,
+            violations.join("\n")
+        ));
+    }
+    match deps {
+        JsonValue::Array(repos) => {
+            let mut chain = ancestors.to_vec();
+            chain.push(get_cycle_key(dependency));
+            let mut direct_dependencies = Vec::new();
+            for repo in repos {
+                if !Dependency::matches_variant(&repo, resolver.variant) {
+                    continue;
+                }
+                if Dependency::is_removal(&repo) {
+                    let removal = RemovedProject::get(&repo)?;
+                    let mut removals = resolver.removals.lock().unwrap();
+                    if !removals.contains(&removal) {
+                        removals.push(removal);
+                    }
+                    continue;
+                }
+                let mut sub_dependency =
+                    Dependency::get(repo, resolver.remotes, &resolver.defaults)?;
+                sub_dependency.parent = Some(dependency.name.to_owned());
+                apply_branch_map(resolver, &mut sub_dependency);
+                apply_locked_revision(resolver, &mut sub_dependency);
+                let key = get_cycle_key(&sub_dependency);
+                if let Some(start) = chain.iter().position(|ancestor| *ancestor == key) {
+                    warn!(
+                        "Dependency cycle {} -> {key}, not descending into {} again",
+                        chain[start..].join(" -> "),
+                        sub_dependency.name
+                    );
+                    continue;
+                }
+                direct_dependencies.push(sub_dependency);
+            }
+            // Results come back in the order of the dependency file, so
+            // the tree is flattened the same way as when fetched one by one
+            let sub_dependencies = future::try_join_all(
+                direct_dependencies
+                    .iter()
+                    .map(|sub_dependency| get_dependencies(resolver, sub_dependency, &chain)),
+            )
+            .await?;
+            let mut dependencies = Vec::new();
+            for (sub_dependency, sub_dependencies) in
+                direct_dependencies.into_iter().zip(sub_dependencies)
+            {
+                dependencies.push(sub_dependency);
+                dependencies.extend(sub_dependencies);
+            }
+            Ok(dependencies)
+        }
+        other => Err(format!("Unexpected element {other} in dependency json")),
+    }
+}
+
+/// Identifies a repo in a dependency cycle, the same
+/// repo on another branch has different dependencies.
+fn get_cycle_key(dependency: &Dependency) -> String {
+    format!("{}@{}", dependency.name, dependency.branch)
+}
+
+/// Reads the branch map file, a json object of repository
+/// or path to branch.
+fn read_branch_map(file: &str) -> Result<HashMap<String, String>, String> {
+    let content = fs::read_to_string(file)
+        .map_err(|err| format!("Failed to read branch map {file}: {err}"))?;
+    let json = json::parse(content.trim_start_matches(UTF8_BOM))
+        .map_err(|err| format!("Branch map {file} is not valid JSON: {err}"))?;
+    match json {
+        JsonValue::Object(object) => object
+            .iter()
+            .map(|(key, value)| match value.as_str() {
+                Some(branch) => Ok((key.to_owned(), branch.to_owned())),
+                None => Err(format!(
+                    "Branch of {key} in branch map {file} is not a string"
+                )),
+            })
+            .collect(),
+        other => Err(format!(
+            "Branch map {file} should be a JSON object, found {}",
+            other.pretty(4)
+        )),
+    }
+}
+
+/// Overrides the branch of the dependency if its repository,
+/// the name of its repository or its path is in the branch map.
+fn apply_branch_map(resolver: &Resolver<'_>, dependency: &mut Dependency) {
+    let branch = [
+        dependency.name.as_str(),
+        manifest::get_project_name(dependency),
+        dependency.path.as_str(),
+    ]
+    .iter()
+    .find_map(|key| resolver.branch_map.get(*key));
+    if let Some(branch) = branch {
+        dependency.branch = branch.to_owned();
+    }
+}
+
+/// Pins the dependency to the commit its path is locked at with
+/// --locked. The branch is kept as upstream for repo sync -c.
+fn apply_locked_revision(resolver: &Resolver<'_>, dependency: &mut Dependency) {
+    if let Some(revision) = resolver.locked_revisions.get(&dependency.path) {
+        if dependency.upstream.is_none() {
+            dependency.upstream = Some(dependency.branch.to_owned());
+        }
+        dependency.branch = revision.to_owned();
+    }
+}
+
+/// Fetches the dependency file like [`fetch_dependency_file`], unless
+/// it was already fetched for the same repository and branch.
+async fn fetch_dependency_file_once(
+    resolver: &Resolver<'_>,
+    dependency: &Dependency,
+) -> Result<Option<String>, String> {
+    let key = (dependency.name.to_owned(), dependency.branch.to_owned());
+    let dependency_file = resolver
+        .dependency_files
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .clone();
+    dependency_file
+        .get_or_try_init(|| fetch_dependency_file(resolver, dependency))
+        .await
+        .cloned()
+}
+
+/// Fetches the dependency file of the dependency, returns None if
+/// it does not have one. A cached copy is revalidated using its
+/// ETag and reused if the server responds with 304.
+async fn fetch_dependency_file(
+    resolver: &Resolver<'_>,
+    dependency: &Dependency,
+) -> Result<Option<String>, String> {
+    let cached_file = resolver.cache.and_then(|cache| {
+        cache.get_dependency_file(
+            &dependency.name,
+            &dependency.branch,
+            resolver.deps_format.file_name(),
+        )
+    });
+    if resolver.offline {
+        return match cached_file {
+            Some(cached_file) => {
+                debug!(
+                    "Using the cached dependency file of {} at {}",
+                    dependency.name, dependency.branch
+                );
+                Ok(cached_file.body)
+            }
+            None => Err(format!(
+                "Dependency file of {} at {} is not cached, run once without --offline to cache it",
+                dependency.name, dependency.branch
+            )),
+        };
+    }
+
+    let deps_url = get_deps_url(resolver, dependency)?;
+    let _permit = resolver
+        .requests
+        .acquire()
+        .await
+        .map_err(|err| format!("Failed to wait for a request slot: {err}"))?;
+    let mut request = resolver.client.get(&deps_url);
+    if let Some(etag) = cached_file.as_ref().and_then(|file| file.etag.as_ref()) {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    debug!("Fetching dependency file from {deps_url}");
+    let response = http::send(request, resolver.max_attempts)
+        .await
+        .map_err(|err| format!("Failed to get dependency file from {deps_url}: {err}"))?;
+    let (etag, body) = match (response.status(), cached_file) {
+        (StatusCode::NOT_MODIFIED, Some(cached_file)) => (cached_file.etag, cached_file.body),
+        (StatusCode::NOT_FOUND, _) => (None, None),
+        (status, _) if !status.is_success() => {
+            return Err(http::status_error(&deps_url, &response));
+        }
+        _ => {
+            let etag = response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(|etag| etag.to_owned());
+            let body = http::read_text(response, resolver.max_response_bytes)
+                .await
+                .map_err(|err| {
+                    format!(
+                        "Failed to read dependency file of {}: {err}",
+                        dependency.name
+                    )
+                })?;
+            (etag, Some(body))
+        }
+    };
+    if let Some(cache) = resolver.cache {
+        let result = cache.put_dependency_file(
+            &dependency.name,
+            &dependency.branch,
+            resolver.deps_format.file_name(),
+            etag.as_deref(),
+            body.as_deref(),
+        );
+        if let Err(err) = result {
+            warn!("{err}");
+        }
+    }
+    Ok(body)
+}
+
+/// Checks that repo knows about the remotes of all the dependencies.
+/// Remotes it does not know about are returned to be written to the
+/// generated manifest if --emit-missing-remotes is passed, otherwise
+/// they are only warned about. With --emit-remotes all the remotes
+/// of the dependencies are returned.
+fn get_missing_remotes<'a>(
+    dependencies: impl IntoIterator<Item = &'a Dependency>,
+    remotes: &HashMap<String, Remote>,
+    local_manifest_dir: &str,
+    args: &Args,
+) -> Result<Vec<Remote>, String> {
+    if args.emit_remotes {
+        let mut used_remotes: Vec<&str> = dependencies
+            .into_iter()
+            .map(|dependency| dependency.remote.as_str())
+            .collect::<HashSet<&str>>()
+            .into_iter()
+            .collect();
+        used_remotes.sort();
+        return get_remotes(&used_remotes, remotes);
+    }
+    let included_remotes = match remotes::get_included_remotes(
+        &args.manifest_root[0],
+        local_manifest_dir,
+        args.lenient,
+    )? {
+        Some(included_remotes) => included_remotes,
+        None => {
+            warn!(
+                "Not checking remotes since {} does not have a manifest.xml",
+                args.manifest_root[0]
+            );
+            return Ok(Vec::with_capacity(0));
+        }
+    };
+    let mut missing_remotes: Vec<&str> = dependencies
+        .into_iter()
+        .map(|dependency| dependency.remote.as_str())
+        .filter(|remote| !included_remotes.contains(*remote))
+        .collect::<HashSet<&str>>()
+        .into_iter()
+        .collect();
+    missing_remotes.sort();
+    if !args.emit_missing_remotes {
+        missing_remotes.iter().for_each(|remote| {
+            warn!("Remote {remote} is not defined in the manifests repo syncs, use --emit-missing-remotes to add it to the generated manifest")
+        });
+        return Ok(Vec::with_capacity(0));
+    }
+    get_remotes(&missing_remotes, remotes)
+}
+
+fn get_remotes(names: &[&str], remotes: &HashMap<String, Remote>) -> Result<Vec<Remote>, String> {
+    names
+        .iter()
+        .map(|name| {
+            remotes.get(*name).cloned().ok_or(format!(
+                "Remote {name} is not defined in any manifest, cannot emit it"
+            ))
+        })
+        .collect()
+}
+
+/// Writes the device manifest, keeping the projects of
+/// the existing one as decided by existing.
+fn create_manifest(
+    dependencies: impl IntoIterator<Item = Dependency>,
+    remotes: &[Remote],
+    removals: &[RemovedProject],
+    local_manifest_dir: &str,
+    existing: ExistingManifest,
+    options: &ManifestOptions,
+) -> Result<Vec<Dependency>, String> {
+    let dependencies = dedup_dependencies(dependencies)?;
+    let manual_manifest = match existing {
+        ExistingManifest::Merge => Manifest::read(
+            local_manifest_dir,
+            DEVICE_MANIFEST_FILE_NAME,
+            options.format,
+        )?
+        .filter(|manifest| !manifest.is_generated()),
+        _ => None,
+    };
+    if let Some(mut manifest) = manual_manifest {
+        warn!("{DEVICE_MANIFEST_FILE_NAME} was not generated by roomservice, merging the projects into it. Pass --overwrite to replace it");
+        manifest.add_remotes(remotes);
+        manifest.add_removals(removals);
+        manifest.merge_dependencies(&dependencies);
+        options.write(
+            manifest,
+            &manifest::get_path(local_manifest_dir, DEVICE_MANIFEST_FILE_NAME),
+        )?;
+        return Ok(dependencies);
+    }
+    if existing == ExistingManifest::AppendNew {
+        let mut manifest = Manifest::read(
+            local_manifest_dir,
+            DEVICE_MANIFEST_FILE_NAME,
+            options.format,
+        )?
+        .unwrap_or_else(|| Manifest::new(options.format));
+        manifest.add_remotes(remotes);
+        manifest.add_removals(removals);
+        manifest
+            .add_new_dependencies(&dependencies)
+            .iter()
+            .for_each(|path| {
+                warn!("{path} is already in {DEVICE_MANIFEST_FILE_NAME} with a different name, remote or revision, keeping it as is")
+            });
+        options.write(
+            manifest,
+            &manifest::get_path(local_manifest_dir, DEVICE_MANIFEST_FILE_NAME),
+        )?;
+        return Ok(dependencies);
+    }
+    let mut manifest = Manifest::new(options.format);
+    manifest.add_remotes(remotes);
+    manifest.add_removals(removals);
+    manifest.add_dependencies(&dependencies);
+    options.write(
+        manifest,
+        &manifest::get_path(local_manifest_dir, DEVICE_MANIFEST_FILE_NAME),
+    )?;
+    Ok(dependencies)
+}
+
+/// Adds the projects whose path is not in the existing manifest
+/// file yet, keeping everything else in it as it is.
+fn merge_into_manifest(
+    dependencies: impl IntoIterator<Item = Dependency>,
+    remotes: &[Remote],
+    removals: &[RemovedProject],
+    file: &str,
+    options: &ManifestOptions,
+) -> Result<Vec<Dependency>, String> {
+    let dependencies = dedup_dependencies(dependencies)?;
+    let mut manifest = Manifest::read_file(file, options.format)?
+        .ok_or(format!("Manifest {file} to merge into does not exist"))?;
+    manifest.add_remotes(remotes);
+    manifest.add_removals(removals);
+    manifest
+        .add_new_dependencies(&dependencies)
+        .iter()
+        .for_each(|path| {
+            warn!("{path} is already in {file} with a different name, remote or revision, keeping it as is")
+        });
+    options.write(manifest, file)?;
+    Ok(dependencies)
+}
+
+/// Writes a manifest for every device. Dependencies required by
+/// more than one device are written to a common manifest instead
+/// since repo does not allow duplicate projects across manifests.
+/// Remotes and removed projects are written to the common manifest
+/// for the same reason.
+fn create_split_manifests(
+    device_dependencies: &[(&str, Vec<Dependency>)],
+    remotes: &[Remote],
+    removals: &[RemovedProject],
+    local_manifest_dir: &str,
+    options: &ManifestOptions,
+) -> Result<Vec<Dependency>, String> {
+    let mut path_counts: HashMap<&str, usize> = HashMap::new();
+    device_dependencies
+        .iter()
+        .flat_map(|(_, dependencies)| {
+            dependencies
+                .iter()
+                .map(|dependency| dependency.path.as_str())
+                .collect::<HashSet<&str>>()
+        })
+        .for_each(|path| *path_counts.entry(path).or_default() += 1);
+    let is_shared = |dependency: &Dependency| path_counts[dependency.path.as_str()] > 1;
+
+    let common_dependencies = dedup_dependencies(
+        device_dependencies
+            .iter()
+            .flat_map(|(_, dependencies)| dependencies.iter())
+            .filter(|dependency| is_shared(dependency))
+            .cloned(),
+    )?;
+    if !common_dependencies.is_empty() || !remotes.is_empty() || !removals.is_empty() {
+        let mut manifest = Manifest::new(options.format);
+        manifest.add_remotes(remotes);
+        manifest.add_removals(removals);
+        manifest.add_dependencies(&common_dependencies);
+        options.write(
+            manifest,
+            &manifest::get_path(local_manifest_dir, COMMON_MANIFEST_FILE_NAME),
+        )?;
+    }
+
+    let mut all_dependencies = common_dependencies;
+    for (device_name, dependencies) in device_dependencies {
+        let dependencies = dedup_dependencies(
+            dependencies
+                .iter()
+                .filter(|dependency| !is_shared(dependency))
+                .cloned(),
+        )?;
+        let mut manifest = Manifest::new(options.format);
+        manifest.add_dependencies(&dependencies);
+        options.write(
+            manifest,
+            &manifest::get_path(local_manifest_dir, device_name),
+        )?;
+        all_dependencies.extend(dependencies);
+    }
+    Ok(all_dependencies)
+}
+
+/// Removes dependencies with a path that was already seen, keeping the
+/// first occurrence. Fails listing every path that is required with a
+/// different name, remote or revision, as only one of them can be synced.
+fn dedup_dependencies(
+    dependencies: impl IntoIterator<Item = Dependency>,
+) -> Result<Vec<Dependency>, String> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut unique: Vec<Dependency> = Vec::new();
+    let mut conflicts = Vec::new();
+    for dependency in dependencies {
+        match seen.get(&dependency.path) {
+            Some(index) => {
+                let first = &unique[*index];
+                if first.name != dependency.name
+                    || first.remote != dependency.remote
+                    || first.branch != dependency.branch
+                {
+                    conflicts.push(format!(
+                        "{}: {} conflicts with {}",
+                        dependency.path,
+                        describe_requirement(first),
+                        describe_requirement(&dependency)
+                    ));
+                }
+            }
+            None => {
+                seen.insert(dependency.path.to_owned(), unique.len());
+                unique.push(dependency);
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(format!(
+            "Dependencies require the same path differently:\n{}",
+            conflicts.join("\n")
+        ));
+    }
+    Ok(unique)
+}
+
+fn describe_requirement(dependency: &Dependency) -> String {
+    format!(
+        "{} on {} at {} (required by {})",
+        dependency.name,
+        dependency.remote,
+        dependency.branch,
+        dependency.parent.as_deref().unwrap_or("the device")
+    )
+}
+
+/// Returns the paths to be synced. If no paths were requested
+/// then paths of all dependencies are returned.
+fn get_sync_paths<'a>(
+    dependencies: &'a [Dependency],
+    requested_paths: &'a [String],
+) -> Result<Vec<&'a str>, String> {
+    if requested_paths.is_empty() {
+        return Ok(dependencies
+            .iter()
+            .map(|dependency| dependency.path.as_str())
+            .collect());
+    }
+    let resolved_paths: HashSet<&str> = dependencies
+        .iter()
+        .map(|dependency| dependency.path.as_str())
+        .collect();
+    let unknown_paths: Vec<&str> = requested_paths
+        .iter()
+        .map(|path| path.trim_end_matches('/'))
+        .filter(|path| !resolved_paths.contains(path))
+        .collect();
+    if !unknown_paths.is_empty() {
+        return Err(format!(
+            "--sync-path {} is not among the resolved dependencies",
+            unknown_paths.join(", ")
+        ));
+    }
+    Ok(requested_paths
+        .iter()
+        .map(|path| path.trim_end_matches('/'))
+        .collect())
+}
+
+/// Runs the hook with sh, failing if it exits with a non-zero status.
+fn run_post_resolve_hook(
+    hook: &str,
+    manifest_path: &str,
+    project_count: usize,
+) -> Result<(), String> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{hook} \"$@\""))
+        .arg(POST_RESOLVE_HOOK)
+        .arg(manifest_path)
+        .arg(project_count.to_string())
+        .env(ENV_HOOK_MANIFEST, manifest_path)
+        .env(ENV_HOOK_PROJECT_COUNT, project_count.to_string())
+        .status()
+        .map_err(|err| format!("Failed to run post resolve hook: {err}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Post resolve hook exited with {status}"))
+    }
+}
+
+/// The repo sync command that syncs the paths.
+fn get_sync_command(paths: &[&str], clone_bundle: bool, jobs: u32, quiet: bool) -> Command {
+    let sync_args = [
+        "--force-sync",
+        "--no-tags",
+        "--current-branch",
+        if clone_bundle {
+            "--clone-bundle"
+        } else {
+            "--no-clone-bundle"
+        },
+    ];
+    let mut command = Command::new("repo");
+    command.arg("sync").args(sync_args).arg(format!("-j{jobs}"));
+    if quiet {
+        command.arg("-q");
+    }
+    command.args(paths);
+    command
+}
+
+/// Command line of the command, with the arguments that
+/// the shell would split or expand quoted.
+fn format_command(command: &Command) -> String {
+    iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let is_plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c));
+            if is_plain {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Runs the repo sync command for the paths, running it again up to
+/// retries times while it fails, and prints how many of the projects
+/// synced. Returns the status of the last run.
+fn sync_dependencies(
+    mut command: Command,
+    paths: &[&str],
+    retries: u32,
+    quiet: bool,
+) -> Result<ExitStatus, String> {
+    let mut attempt = 0;
+    loop {
+        let (status, stderr_lines) = run_repo_sync(&mut command, quiet)?;
+        if status.success() || attempt == retries {
+            let failed = stderr_lines.map(|lines| get_failed_paths(&lines, paths));
+            println!("{}", get_sync_summary(paths, status, failed.as_deref()));
+            return Ok(status);
+        }
+        attempt += 1;
+        info!(
+            "repo sync exited with {status}, retrying in {SYNC_RETRY_DELAY_SECS}s ({attempt}/{retries})"
+        );
+        thread::sleep(Duration::from_secs(SYNC_RETRY_DELAY_SECS));
+    }
+}
+
+/// Runs the repo sync command once. Unless quiet it inherits stdio, so
+/// repo can draw its progress on the terminal. If quiet its output is
+/// captured and only the lines reporting errors are printed, and the
+/// lines of stderr are returned along with the exit status to tell
+/// which projects failed.
+fn run_repo_sync(
+    command: &mut Command,
+    quiet: bool,
+) -> Result<(ExitStatus, Option<Vec<String>>), String> {
+    if !quiet {
+        let status = command
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()
+            .map_err(|err| format!("failed to spawn repo sync process: {err}"))?;
+        return Ok((status, None));
+    }
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to spawn repo sync process: {err}"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or(String::from("failed to capture stdout of repo sync"))?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or(String::from("failed to capture stderr of repo sync"))?;
+    // Drained so that repo does not block on a full pipe
+    let stdout_reader =
+        thread::spawn(move || io::copy(&mut io::BufReader::new(stdout), &mut io::sink()));
+    let mut stderr_lines = Vec::new();
+    let mut is_failing_repos = false;
+    for line in io::BufReader::new(stderr)
+        .split(b'\n')
+        .map_while(Result::ok)
+    {
+        let line = String::from_utf8_lossy(&line).into_owned();
+        let lowercase = line.trim_start().to_lowercase();
+        if is_failing_repos_header(&line) {
+            is_failing_repos = true;
+        } else if line.trim().is_empty() {
+            is_failing_repos = false;
+        }
+        if is_failing_repos || lowercase.starts_with("error") || lowercase.starts_with("fatal") {
+            eprintln!("{line}");
+        }
+        stderr_lines.push(line);
+    }
+    let status = child
+        .wait()
+        .map_err(|err| format!("failed to wait on child process: {err}"))?;
+    stdout_reader
+        .join()
+        .map_err(|_| String::from("failed to read stdout of repo sync"))?
+        .map_err(|err| format!("failed to read stdout of repo sync: {err}"))?;
+    Ok((status, Some(stderr_lines)))
+}
+
+/// Whether the line starts the block of projects that repo failed to
+/// sync, like "Failing repos:" or "Failing repos (checkout):".
+fn is_failing_repos_header(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with(SYNC_FAILING_REPOS) && line.ends_with(':')
+}
+
+/// The paths that repo listed in its "Failing repos:" blocks, which
+/// hold one project path per line and end at an empty line.
+fn get_failed_paths<'a>(stderr_lines: &[String], paths: &[&'a str]) -> Vec<&'a str> {
+    let mut failing = HashSet::new();
+    let mut is_failing_repos = false;
+    for line in stderr_lines {
+        if is_failing_repos_header(line) {
+            is_failing_repos = true;
+        } else if line.trim().is_empty() {
+            is_failing_repos = false;
+        } else if is_failing_repos {
+            failing.insert(line.trim());
+        }
+    }
+    paths
+        .iter()
+        .filter(|path| failing.contains(**path))
+        .copied()
+        .collect()
+}
+
+/// Summary of a sync of the paths. Failed are the paths repo listed as
+/// failing, or none if its output was not captured.
+fn get_sync_summary(paths: &[&str], status: ExitStatus, failed: Option<&[&str]>) -> String {
+    match failed {
+        _ if status.success() => format!("Synced {0} of {0} projects", paths.len()),
+        Some(failed) if !failed.is_empty() => format!(
+            "Synced {} of {} projects, failed to sync {}",
+            paths.len() - failed.len(),
+            paths.len(),
+            failed.join(", ")
+        ),
+        _ => format!(
+            "Synced an unknown number of the {} projects, repo sync exited with {status}",
+            paths.len()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{Context, MockHost};
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, ResponseTemplate,
+    };
+
+    const DEVICE_REPO: &str = "device_xiaomi_lmi";
+
+    fn manifest_options() -> ManifestOptions {
+        ManifestOptions {
+            format: ManifestFormat::Plain,
+            canonical: false,
+            dry_run: false,
+        }
+    }
+
+    /// Paths of the projects in the manifest file.
+    fn project_paths(file: &Path) -> Vec<String> {
+        let element = parse_xml(&fs::read(file).unwrap()).unwrap();
+        element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == manifest::defs::PROJECT_ELEMENT)
+            .filter_map(|element| element.attributes.get(manifest::defs::ATTR_PATH))
+            .cloned()
+            .collect()
+    }
+
+    /// Dependencies of the device repository of the organization,
+    /// resolved without searching for it.
+    async fn resolve_device_repo(
+        host: &MockHost,
+        root: &tempfile::TempDir,
+        extra: &[&str],
+    ) -> Result<Vec<Dependency>, String> {
+        let device_repo = format!("{ORG}/{DEVICE_REPO}");
+        let mut argv = vec!["--device-repo", &device_repo];
+        argv.extend_from_slice(extra);
+        let context = Context::new(testing::args(host, root, &argv));
+        let resolver = context.resolver();
+        let device = get_device_dependency(&resolver, "lmi", &context.args).await?;
+        get_dependencies(&resolver, &device, &[]).await
+    }
+
+    fn paths_of(dependencies: &[Dependency]) -> Vec<&str> {
+        dependencies
+            .iter()
+            .map(|dependency| dependency.path.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn find_device_repo_searches_the_organization() {
+        let host = MockHost::start().await;
+        host.mock_org_repos(
+            ORG,
+            &["vendor_xiaomi_lmi", DEVICE_REPO, "device_xiaomi_umi"],
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let args = testing::args(&host, &root, &["-d", "lmi"]);
+        let client = http::build_client(Duration::from_secs(5), false).unwrap();
+        let api = HostApi::new(&client, &args, None);
+
+        assert_eq!(find_device_repo(&api, "lmi").await.unwrap(), DEVICE_REPO);
+        assert_eq!(
+            find_device_repo(&api, "alioth").await.unwrap_err(),
+            "Failed to find repository"
+        );
+    }
+
+    #[tokio::test]
+    async fn find_device_repo_pins_the_api_version() {
+        for (extra, version) in [
+            (&["-d", "lmi"][..], DEFAULT_GITHUB_API_VERSION),
+            (
+                &["-d", "lmi", "--github-api-version", "2026-03-10"][..],
+                "2026-03-10",
+            ),
+        ] {
+            let host = MockHost::start().await;
+            host.mock_org_repos(ORG, &[DEVICE_REPO]).await;
+            let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+            let args = testing::args(&host, &root, extra);
+            let client = http::build_client(Duration::from_secs(5), false).unwrap();
+            let api = HostApi::new(&client, &args, None);
+
+            find_device_repo(&api, "lmi").await.unwrap();
+
+            let requests = host.server.received_requests().await.unwrap();
+            assert!(!requests.is_empty());
+            for request in requests {
+                let header = request
+                    .headers
+                    .get(&"X-GitHub-Api-Version".parse().unwrap())
+                    .unwrap();
+                assert_eq!(header.last().as_str(), version);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_device_repos_lists_every_match() {
+        let host = MockHost::start().await;
+        host.mock_org_repos(
+            ORG,
+            &[
+                "device_xiaomi_lmi",
+                "vendor_xiaomi_lmi",
+                "device_xiaomi_umi",
+                "device_oneplus_lemonade",
+                "device_xiaomi_sm8250-common",
+            ],
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let args = testing::args(&host, &root, &["list-devices", "[lu]mi"]);
+        let client = http::build_client(Duration::from_secs(5), false).unwrap();
+        let api = HostApi::new(&client, &args, None);
+
+        let regexes = get_device_repo_regexes("[lu]mi").unwrap();
+        assert_eq!(
+            list_device_repos(&api, &regexes).await.unwrap(),
+            ["device_xiaomi_lmi", "device_xiaomi_umi"]
+        );
+        assert_eq!(
+            get_device_repo_regexes("(").unwrap_err().lines().next(),
+            Some("Invalid pattern (: regex parse error:")
+        );
+    }
+
+    #[tokio::test]
+    async fn near_miss_device_repos_are_not_listed() {
+        let host = MockHost::start().await;
+        host.mock_org_repos(
+            ORG,
+            &[
+                "device_xiaomi_x",
+                "device_xiaomi_xtra",
+                "device_xiaomi_x-common",
+                "device_xiaomi_lmi_x",
+                "my_device_xiaomi_x",
+                "device_asus_rog_x",
+                "device_asus_rog_phone",
+            ],
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let args = testing::args(&host, &root, &["list-devices", "x"]);
+        let client = http::build_client(Duration::from_secs(5), false).unwrap();
+        let api = HostApi::new(&client, &args, None);
+        let list = |pattern: &str| {
+            let regexes = get_device_repo_regexes(pattern).unwrap();
+            let api = &api;
+            async move { list_device_repos(api, &regexes).await.unwrap() }
+        };
+
+        assert_eq!(list("x").await, ["device_xiaomi_x"]);
+        assert_eq!(list("phone").await, ["device_asus_rog_phone"]);
+        assert_eq!(
+            list("x|xtra").await,
+            ["device_xiaomi_x", "device_xiaomi_xtra"]
+        );
+        assert!(list("tra").await.is_empty());
+
+        let repos: Vec<String> = ["device_xiaomi_xtra", "device_asus_rog_x", "device_xiaomi_x"]
+            .iter()
+            .map(|repo| repo.to_string())
+            .collect();
+        assert_eq!(match_device_repo(&repos, "x").unwrap(), "device_xiaomi_x");
+        assert_eq!(
+            match_device_repo(&repos[..2], "x").unwrap(),
+            "device_asus_rog_x"
+        );
+        assert_eq!(match_device_repo(&repos[..1], "x"), None);
+    }
+
+    #[tokio::test]
+    async fn device_repo_skips_discovery() {
+        let host = MockHost::start().await;
+        host.mock_org_repos(ORG, &[DEVICE_REPO]).await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let args = testing::args(
+            &host,
+            &root,
+            &[
+                "--device-repo",
+                "someone/device_oem_lmi",
+                "--branch",
+                "thirteen",
+            ],
+        );
+        let context = Context::new(args);
+
+        let device = get_device_dependency(&context.resolver(), "lmi", &context.args)
+            .await
+            .unwrap();
+
+        assert_eq!(device.name, "someone/device_oem_lmi");
+        assert_eq!(device.path, "device/oem/lmi");
+        assert_eq!(device.remote, remotes::GITHUB);
+        assert_eq!(device.branch, "thirteen");
+        assert!(host.requested_paths().await.is_empty());
+        for device_repo in ["device_oem_lmi", "someone/", "/device_oem_lmi", "a/b/c"] {
+            assert_eq!(
+                split_device_repo(device_repo).unwrap_err(),
+                format!("--device-repo {device_repo} is malformed, expected owner/name")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_device_walks_the_dependency_files() {
+        let host = MockHost::start().await;
+        host.mock_org_repos(ORG, &[DEVICE_REPO]).await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[
+                {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"},
+                {"repository": "someone/vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "branch": "thirteen"}
+            ]"#,
+        )
+        .await;
+        host.mock_dependency_file(
+            &format!("{ORG}/device_xiaomi_sm8250-common"),
+            DEFAULT_BRANCH,
+            r#"[{"repository": "kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250", "clone-depth": "1"}]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let context = Context::new(testing::args(&host, &root, &["-d", "lmi"]));
+
+        let dependencies = resolve_device(&context.resolver(), "lmi", &context.args)
+            .await
+            .unwrap();
+
+        let resolved: Vec<(&str, &str, &str, &str, Option<&str>)> = dependencies
+            .iter()
+            .map(|dependency| {
+                (
+                    dependency.name.as_str(),
+                    dependency.path.as_str(),
+                    dependency.remote.as_str(),
+                    dependency.branch.as_str(),
+                    dependency.parent.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            resolved,
+            [
+                (
+                    "FlamingoOS-Devices/device_xiaomi_lmi",
+                    "device/xiaomi/lmi",
+                    "flamingo-devices",
+                    "A13",
+                    None
+                ),
+                (
+                    "FlamingoOS-Devices/device_xiaomi_sm8250-common",
+                    "device/xiaomi/sm8250-common",
+                    "flamingo-devices",
+                    "A13",
+                    Some("FlamingoOS-Devices/device_xiaomi_lmi")
+                ),
+                (
+                    "FlamingoOS-Devices/kernel_xiaomi_sm8250",
+                    "kernel/xiaomi/sm8250",
+                    "flamingo-devices",
+                    "A13",
+                    Some("FlamingoOS-Devices/device_xiaomi_sm8250-common")
+                ),
+                (
+                    "someone/vendor_xiaomi_lmi",
+                    "vendor/xiaomi/lmi",
+                    "github",
+                    "thirteen",
+                    Some("FlamingoOS-Devices/device_xiaomi_lmi")
+                ),
+            ]
+        );
+        assert_eq!(dependencies[2].clone_depth.as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn get_dependencies_fails_on_a_malformed_dependency_file() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[{"repository": "vendor_xiaomi_lmi"}]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let args = testing::args(
+            &host,
+            &root,
+            &["--device-repo", "FlamingoOS-Devices/device_xiaomi_lmi"],
+        );
+        let context = Context::new(args);
+        let resolver = context.resolver();
+        let device = get_device_dependency(&resolver, "lmi", &context.args)
+            .await
+            .unwrap();
+
+        let err = get_dependencies(&resolver, &device, &[]).await.unwrap_err();
+
+        assert_eq!(
+            err,
+            "Dependency file of FlamingoOS-Devices/device_xiaomi_lmi is malformed:\n/0: missing required key \"target_path\""
+        );
+        // --device-repo skips searching the organization
+        assert_eq!(
+            host.requested_paths().await,
+            ["/FlamingoOS-Devices/device_xiaomi_lmi/A13/flamingo.dependencies"]
+        );
+    }
+
+    #[tokio::test]
+    async fn cycles_terminate_and_each_file_is_fetched_once() {
+        let host = MockHost::start().await;
+        let files = [
+            (
+                DEVICE_REPO,
+                r#"[
+                    {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"},
+                    {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"}
+                ]"#,
+            ),
+            (
+                "device_xiaomi_sm8250-common",
+                r#"[
+                    {"repository": "device_xiaomi_lmi", "target_path": "device/xiaomi/lmi"},
+                    {"repository": "hardware_xiaomi", "target_path": "hardware/xiaomi"}
+                ]"#,
+            ),
+            (
+                "vendor_xiaomi_lmi",
+                r#"[{"repository": "hardware_xiaomi", "target_path": "hardware/xiaomi"}]"#,
+            ),
+            (
+                "hardware_xiaomi",
+                r#"[{"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"}]"#,
+            ),
+        ];
+        for (repo, content) in files {
+            host.mock_dependency_file(&format!("{ORG}/{repo}"), DEFAULT_BRANCH, content)
+                .await;
+        }
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+
+        let dependencies = resolve_device_repo(&host, &root, &[]).await.unwrap();
+
+        assert_eq!(
+            paths_of(&dependencies),
+            [
+                "device/xiaomi/sm8250-common",
+                "hardware/xiaomi",
+                "vendor/xiaomi/lmi",
+                "hardware/xiaomi",
+                "device/xiaomi/sm8250-common",
+            ]
+        );
+        let mut requested_paths = host.requested_paths().await;
+        requested_paths.sort();
+        assert_eq!(
+            requested_paths,
+            [
+                DEVICE_REPO,
+                "device_xiaomi_sm8250-common",
+                "hardware_xiaomi",
+                "vendor_xiaomi_lmi",
+            ]
+            .map(|repo| format!("/{ORG}/{repo}/{DEFAULT_BRANCH}/flamingo.dependencies"))
+        );
+    }
+
+    #[tokio::test]
+    async fn default_remote_is_used_for_remote_less_dependencies() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[
+                {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"},
+                {"repository": "someone/kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250"}
+            ]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        testing::write_manifest(
+            root.path(),
+            "private.xml",
+            &format!(
+                r#"<manifest><remote name="private" fetch="{}" revision="main" /></manifest>"#,
+                host.uri()
+            ),
+        );
+        let args = testing::args(
+            &host,
+            &root,
+            &[
+                "--device-repo",
+                "FlamingoOS-Devices/device_xiaomi_lmi",
+                "--default-remote",
+                "private",
+            ],
+        );
+        let context = Context::new(args);
+        let resolver = context.resolver();
+        let device = get_device_dependency(&resolver, "lmi", &context.args)
+            .await
+            .unwrap();
+
+        let dependencies = get_dependencies(&resolver, &device, &[]).await.unwrap();
+
+        let remotes: Vec<(&str, &str, &str)> = dependencies
+            .iter()
+            .map(|dependency| {
+                (
+                    dependency.path.as_str(),
+                    dependency.remote.as_str(),
+                    dependency.branch.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            remotes,
+            [
+                ("vendor/xiaomi/lmi", "private", "main"),
+                ("kernel/xiaomi/sm8250", "github", "A13"),
+            ]
+        );
+
+        let args = testing::args(&host, &root, &["-d", "lmi", "--default-remote", "gitea"]);
+        let context = Context::new(args);
+        let err = Resolver::new(
+            &context.args,
+            &context.client,
+            &context.remotes,
+            None,
+            &context.branch_map,
+            &context.locked_revisions,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err, "--default-remote gitea is not defined in any manifest");
+    }
+
+    #[tokio::test]
+    async fn only_dependencies_of_the_variant_are_resolved() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[
+                {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"},
+                {"repository": "packages_apps_Debug", "target_path": "packages/apps/Debug", "variant": "eng"},
+                {"repository": "packages_apps_Store", "target_path": "packages/apps/Store", "variant": "user"}
+            ]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+
+        for (extra, expected) in [
+            (&[][..], &["device/xiaomi/sm8250-common"][..]),
+            (
+                &["--variant", "eng"][..],
+                &["device/xiaomi/sm8250-common", "packages/apps/Debug"][..],
+            ),
+            (
+                &["--variant", "user"][..],
+                &["device/xiaomi/sm8250-common", "packages/apps/Store"][..],
+            ),
+        ] {
+            let dependencies = resolve_device_repo(&host, &root, extra).await.unwrap();
+            assert_eq!(paths_of(&dependencies), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_response_bytes_applies_to_every_response() {
+        let host = MockHost::start().await;
+        host.mock_org_repos(ORG, &[DEVICE_REPO]).await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            &format!(
+                r#"[{{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "upstream": "{}"}}]"#,
+                "x".repeat(1024)
+            ),
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+
+        let err = resolve_device_repo(&host, &root, &["--max-response-bytes", "1024"])
+            .await
+            .unwrap_err();
+        assert!(err.ends_with("exceeds the limit of 1024 bytes"), "{err}");
+
+        let args = testing::args(&host, &root, &["-d", "lmi", "--max-response-bytes", "16"]);
+        let client = http::build_client(Duration::from_secs(5), false).unwrap();
+        let api = HostApi::new(&client, &args, None);
+        let err = find_device_repo(&api, "lmi").await.unwrap_err();
+        assert!(err.ends_with("exceeds the limit of 16 bytes"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn not_modified_reuses_the_cached_dependency_file() {
+        let deps = r#"[{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"}]"#;
+        let deps_path = format!("/{ORG}/{DEVICE_REPO}/{DEFAULT_BRANCH}/flamingo.dependencies");
+        let host = MockHost::start().await;
+        Mock::given(method("GET"))
+            .and(path(deps_path.as_str()))
+            .and(header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .mount(&host.server)
+            .await;
+        host.mock_file(
+            &deps_path,
+            ResponseTemplate::new(200)
+                .insert_header("ETag", "\"v1\"")
+                .set_body_string(deps),
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let cache_dir = tempfile::TempDir::new().unwrap();
+        let mut context = Context::new(testing::args(&host, &root, &["-d", "lmi"]));
+        context.cache = Some(Cache::with_dir(
+            cache_dir.path().to_path_buf(),
+            Duration::from_secs(60),
+        ));
+        let resolver = context.resolver();
+        let device = testing::dependency(DEVICE_REPO, "device/xiaomi/lmi");
+
+        let fetched = fetch_dependency_file(&resolver, &device).await.unwrap();
+        let revalidated = fetch_dependency_file(&resolver, &device).await.unwrap();
+
+        assert_eq!(fetched.as_deref(), Some(deps));
+        assert_eq!(revalidated.as_deref(), Some(deps));
+        let requests = host.server.received_requests().await.unwrap();
+        let etags: Vec<Option<String>> = requests
+            .iter()
+            .map(|request| {
+                request
+                    .headers
+                    .get(&"If-None-Match".parse().unwrap())
+                    .map(|etag| etag.last().as_str().to_owned())
+            })
+            .collect();
+        assert_eq!(etags, [None, Some(String::from("\"v1\""))]);
+    }
+
+    #[test]
+    fn split_per_device_writes_a_manifest_per_device() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let local_manifest_dir = dir.path().to_str().unwrap();
+        let common =
+            testing::dependency("device_xiaomi_sm8250-common", "device/xiaomi/sm8250-common");
+        let device_dependencies = [
+            (
+                "lmi",
+                vec![
+                    testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+                    common.clone(),
+                ],
+            ),
+            (
+                "umi",
+                vec![
+                    testing::dependency("device_xiaomi_umi", "device/xiaomi/umi"),
+                    common,
+                ],
+            ),
+        ];
+
+        let dependencies = create_split_manifests(
+            &device_dependencies,
+            &[],
+            &[],
+            local_manifest_dir,
+            &manifest_options(),
+        )
+        .unwrap();
+
+        assert_eq!(dependencies.len(), 3);
+        let mut files: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["common_manifest.xml", "lmi.xml", "umi.xml"]);
+        assert_eq!(
+            project_paths(&dir.path().join("lmi.xml")),
+            ["device/xiaomi/lmi"]
+        );
+        assert_eq!(
+            project_paths(&dir.path().join("umi.xml")),
+            ["device/xiaomi/umi"]
+        );
+        assert_eq!(
+            project_paths(&dir.path().join("common_manifest.xml")),
+            ["device/xiaomi/sm8250-common"]
+        );
+    }
+
+    #[test]
+    fn device_names_cannot_leave_the_local_manifests_dir() {
+        assert!(check_device_name("lmi").is_ok());
+        assert!(check_device_name("sm8250-common").is_ok());
+        for device_name in ["../../x", "a/b", "..", ""] {
+            assert_eq!(
+                check_device_name(device_name).unwrap_err(),
+                format!("--device-name {device_name} is not a device name")
+            );
+        }
+    }
+
+    #[test]
+    fn sync_command_has_only_the_requested_paths() {
+        let dependencies = [
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+            testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi"),
+            testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250"),
+        ];
+        let requested_paths = [
+            String::from("vendor/xiaomi/lmi/"),
+            String::from("kernel/xiaomi/sm8250"),
+        ];
+
+        let paths = get_sync_paths(&dependencies, &requested_paths).unwrap();
+        let command = get_sync_command(&paths, false, 8, false);
+
+        let args: Vec<&std::ffi::OsStr> = command.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "sync",
+                "--force-sync",
+                "--no-tags",
+                "--current-branch",
+                "--no-clone-bundle",
+                "-j8",
+                "vendor/xiaomi/lmi",
+                "kernel/xiaomi/sm8250",
+            ]
+        );
+        assert_eq!(
+            get_sync_paths(&dependencies, &[String::from("device/xiaomi/umi")]).unwrap_err(),
+            "--sync-path device/xiaomi/umi is not among the resolved dependencies"
+        );
+        assert_eq!(get_sync_paths(&dependencies, &[]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn plan_is_printed_and_sync_needs_a_yes() {
+        let mut kernel = testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250");
+        kernel.clone_depth = Some(String::from("1"));
+        let dependencies = [
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+            kernel,
+        ];
+        let paths = ["device/xiaomi/lmi", "kernel/xiaomi/sm8250"];
+        let plan = "Sync plan:\n\
+            device/xiaomi/lmi (remote: flamingo-devices, branch: A13, clone-depth: full)\n\
+            kernel/xiaomi/sm8250 (remote: flamingo-devices, branch: A13, clone-depth: 1)\n";
+
+        for (answer, proceed) in [("n\n", false), ("\n", false), ("yes\n", true)] {
+            let mut output = Vec::new();
+            let proceed_with_sync = plan_sync(
+                &dependencies,
+                &paths,
+                true,
+                &mut answer.as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+            assert_eq!(proceed_with_sync, proceed);
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("{plan}Proceed with sync? [y/N] ")
+            );
+        }
+
+        // --yes and non interactive runs do not ask
+        let mut output = Vec::new();
+        assert!(plan_sync(
+            &dependencies,
+            &paths,
+            false,
+            &mut "n\n".as_bytes(),
+            &mut output
+        )
+        .unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), plan);
+    }
+
+    #[tokio::test]
+    async fn clean_removes_only_generated_manifests() {
+        let host = MockHost::start().await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let local_manifest_dir = root.path().join(LOCAL_MANIFESTS_DIR);
+        fs::create_dir(&local_manifest_dir).unwrap();
+        let generated = format!(
+            "<manifest><!--{}--><project name=\"a\" path=\"a\" /></manifest>",
+            manifest::defs::GENERATED_COMMENT
+        );
+        let hand_written = r#"<manifest><project name="b" path="b" /></manifest>"#;
+        for (name, content) in [
+            ("device_manifest.xml", hand_written),
+            ("device_manifest_lmi.xml", generated.as_str()),
+            ("common_manifest.xml", generated.as_str()),
+            ("extra.xml", hand_written),
+            ("notes.txt", generated.as_str()),
+        ] {
+            fs::write(local_manifest_dir.join(name), content).unwrap();
+        }
+        let args = testing::args(&host, &root, &["clean"]);
+
+        clean(&args, true, false).unwrap();
+        assert_eq!(fs::read_dir(&local_manifest_dir).unwrap().count(), 5);
+
+        clean(&args, false, false).unwrap();
+        let mut remaining: Vec<String> = fs::read_dir(&local_manifest_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec!["device_manifest.xml", "extra.xml", "notes.txt"]
+        );
+
+        clean(&args, false, true).unwrap();
+        assert!(!local_manifest_dir.join("device_manifest.xml").exists());
+        assert!(local_manifest_dir.join("extra.xml").exists());
+    }
+
+    #[tokio::test]
+    async fn clone_bundle_toggles_the_sync_argument() {
+        let host = MockHost::start().await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let sync_args = |extra: &[&str]| {
+            let mut argv = vec!["-d", "lmi"];
+            argv.extend_from_slice(extra);
+            let args = testing::args(&host, &root, &argv);
+            let command = get_sync_command(&["device/xiaomi/lmi"], args.clone_bundle, 4, false);
+            command
+                .get_args()
+                .map(|arg| arg.to_str().unwrap().to_owned())
+                .collect::<Vec<String>>()
+        };
+
+        let default_args = sync_args(&[]);
+        assert!(default_args.contains(&String::from("--no-clone-bundle")));
+        assert!(!default_args.contains(&String::from("--clone-bundle")));
+
+        let clone_bundle_args = sync_args(&["--clone-bundle"]);
+        assert!(clone_bundle_args.contains(&String::from("--clone-bundle")));
+        assert!(!clone_bundle_args.contains(&String::from("--no-clone-bundle")));
+    }
+
+    #[test]
+    fn only_new_projects_are_appended() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let local_manifest_dir = dir.path().to_str().unwrap();
+        let manifest_file = dir.path().join("device_manifest.xml");
+        fs::write(
+            &manifest_file,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+    <!-- Picked by hand -->
+    <project name="LineageOS/android_kernel_xiaomi_sm8250" path="kernel/xiaomi/sm8250" remote="github" revision="lineage-20" />
+    <project name="FlamingoOS-Devices/device_xiaomi_lmi" path="device/xiaomi/lmi" remote="flamingo-devices" />
+</manifest>
+"#,
+        )
+        .unwrap();
+        let dependencies = vec![
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+            testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi"),
+            testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250"),
+            testing::dependency("hardware_xiaomi", "hardware/xiaomi"),
+        ];
+
+        create_manifest(
+            dependencies,
+            &[],
+            &[],
+            local_manifest_dir,
+            ExistingManifest::AppendNew,
+            &manifest_options(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            project_paths(&manifest_file),
+            [
+                "kernel/xiaomi/sm8250",
+                "device/xiaomi/lmi",
+                "vendor/xiaomi/lmi",
+                "hardware/xiaomi",
+            ]
+        );
+        let content = fs::read_to_string(&manifest_file).unwrap();
+        assert!(content.contains("<!-- Picked by hand -->"));
+        assert!(content.contains(r#"name="LineageOS/android_kernel_xiaomi_sm8250""#));
+        assert!(!content.contains("FlamingoOS-Devices/kernel_xiaomi_sm8250"));
+    }
+
+    #[test]
+    fn resolved_projects_are_merged_into_a_curated_manifest() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let manifest_file = dir.path().join("curated.xml");
+        let file = manifest_file.to_str().unwrap();
+        fs::write(
+            &manifest_file,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+    <!-- Mirror of the kernel trees -->
+    <remote name="kernel-mirror" fetch="https://git.example.com/kernel" />
+    <default revision="A13" remote="github" />
+    <project name="android_kernel_xiaomi_sm8250" path="kernel/xiaomi/sm8250" remote="kernel-mirror" />
+</manifest>
+"#,
+        )
+        .unwrap();
+        let dependencies = vec![
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+            testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250"),
+        ];
+        let remotes = [testing::remote(
+            "lineage",
+            "https://github.com/LineageOS",
+            None,
+        )];
+
+        merge_into_manifest(dependencies, &remotes, &[], file, &manifest_options()).unwrap();
+
+        assert_eq!(
+            project_paths(&manifest_file),
+            ["kernel/xiaomi/sm8250", "device/xiaomi/lmi"]
+        );
+        let content = fs::read_to_string(&manifest_file).unwrap();
+        for curated in [
+            "<!-- Mirror of the kernel trees -->",
+            r#"<remote name="kernel-mirror" fetch="https://git.example.com/kernel" />"#,
+            r#"<default revision="A13" remote="github" />"#,
+            r#"name="android_kernel_xiaomi_sm8250" path="kernel/xiaomi/sm8250" remote="kernel-mirror""#,
+            r#"<remote name="lineage" fetch="https://github.com/LineageOS""#,
+        ] {
+            assert!(
+                content.contains(curated),
+                "{curated} is missing in {content}"
+            );
+        }
+        assert!(!content.contains("FlamingoOS-Devices/kernel_xiaomi_sm8250"));
+
+        let missing = dir.path().join("missing.xml");
+        let missing = missing.to_str().unwrap();
+        assert_eq!(
+            merge_into_manifest(Vec::new(), &[], &[], missing, &manifest_options()).unwrap_err(),
+            format!("Manifest {missing} to merge into does not exist")
+        );
+    }
+
+    #[tokio::test]
+    async fn dependency_files_with_a_byte_order_mark_parse() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            &format!(
+                r#"{UTF8_BOM}[{{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"}}]"#
+            ),
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+
+        let dependencies = resolve_device_repo(&host, &root, &[]).await.unwrap();
+
+        assert_eq!(paths_of(&dependencies), ["vendor/xiaomi/lmi"]);
+    }
+
+    #[tokio::test]
+    async fn dependencies_of_a_personal_device_repo_resolve_against_its_owner() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            "someone/device_xiaomi_lmi",
+            DEFAULT_BRANCH,
+            r#"[
+                {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"},
+                {"repository": "LineageOS/android_kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250", "branch": "lineage-20"}
+            ]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let resolve = |extra: &'static [&'static str]| {
+            let mut argv = vec!["--device-repo", "someone/device_xiaomi_lmi"];
+            argv.extend_from_slice(extra);
+            let context = Context::new(testing::args(&host, &root, &argv));
+            async move {
+                let resolver = context.resolver();
+                let device = get_device_dependency(&resolver, "lmi", &context.args)
+                    .await
+                    .unwrap();
+                get_dependencies(&resolver, &device, &[]).await.unwrap()
+            }
+        };
+        let names = |dependencies: Vec<Dependency>| {
+            dependencies
+                .into_iter()
+                .map(|dependency| format!("{} {}", dependency.remote, dependency.name))
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            names(resolve(&[]).await),
+            [
+                "github someone/vendor_xiaomi_lmi",
+                "github LineageOS/android_kernel_xiaomi_sm8250",
+            ]
+        );
+        assert_eq!(
+            names(resolve(&["--org", ORG]).await),
+            [
+                "flamingo-devices FlamingoOS-Devices/vendor_xiaomi_lmi",
+                "github LineageOS/android_kernel_xiaomi_sm8250",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn branch_map_overrides_the_branches_in_the_manifest() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[
+                {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"},
+                {"repository": "LineageOS/android_kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250", "branch": "lineage-20"},
+                {"repository": "hardware_xiaomi", "target_path": "hardware/xiaomi"}
+            ]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let branch_map_file = root.path().join("branch_map.json");
+        fs::write(
+            &branch_map_file,
+            r#"{"vendor/xiaomi/lmi": "A13-staging", "LineageOS/android_kernel_xiaomi_sm8250": "lineage-20.1"}"#,
+        )
+        .unwrap();
+        let device_repo = format!("{ORG}/{DEVICE_REPO}");
+        let mut context = Context::new(testing::args(
+            &host,
+            &root,
+            &["--device-repo", &device_repo],
+        ));
+        context.branch_map = read_branch_map(branch_map_file.to_str().unwrap()).unwrap();
+        let resolver = context.resolver();
+        let device = get_device_dependency(&resolver, "lmi", &context.args)
+            .await
+            .unwrap();
+        let dependencies = get_dependencies(&resolver, &device, &[]).await.unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+
+        create_manifest(
+            dependencies,
+            &[],
+            &[],
+            dir.path().to_str().unwrap(),
+            ExistingManifest::Replace,
+            &manifest_options(),
+        )
+        .unwrap();
+
+        let element =
+            parse_xml(&fs::read(dir.path().join("device_manifest.xml")).unwrap()).unwrap();
+        let mut revisions: Vec<(&str, &str)> = element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == manifest::defs::PROJECT_ELEMENT)
+            .map(|element| {
+                (
+                    element.attributes[manifest::defs::ATTR_PATH].as_str(),
+                    element.attributes[manifest::defs::ATTR_REVISION].as_str(),
+                )
+            })
+            .collect();
+        revisions.sort();
+        assert_eq!(
+            revisions,
+            [
+                ("hardware/xiaomi", DEFAULT_BRANCH),
+                ("kernel/xiaomi/sm8250", "lineage-20.1"),
+                ("vendor/xiaomi/lmi", "A13-staging"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn undefined_remotes_are_emitted_into_the_manifest() {
+        let host = MockHost::start().await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        // Not included by manifest.xml, so repo does not know about it
+        testing::write_manifest(
+            root.path(),
+            "private.xml",
+            r#"<manifest><remote name="private" fetch="https://git.example.com" revision="main" /></manifest>"#,
+        );
+        let local_manifest_dir = root.path().join(LOCAL_MANIFESTS_DIR);
+        fs::create_dir(&local_manifest_dir).unwrap();
+        let local_manifest_dir = local_manifest_dir.to_str().unwrap();
+        let dependencies = [
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+            Dependency {
+                remote: String::from("private"),
+                ..testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi")
+            },
+        ];
+        let missing_remotes = |extra: &[&str]| {
+            let mut argv = vec!["-d", "lmi"];
+            argv.extend_from_slice(extra);
+            let context = Context::new(testing::args(&host, &root, &argv));
+            get_missing_remotes(
+                &dependencies,
+                &context.remotes.remotes,
+                local_manifest_dir,
+                &context.args,
+            )
+            .unwrap()
+        };
+
+        assert!(missing_remotes(&[]).is_empty());
+        let remotes = missing_remotes(&["--emit-missing-remotes"]);
+        assert_eq!(
+            remotes
+                .iter()
+                .map(|remote| remote.name.as_str())
+                .collect::<Vec<&str>>(),
+            ["private"]
+        );
+
+        create_manifest(
+            dependencies.clone(),
+            &remotes,
+            &[],
+            local_manifest_dir,
+            ExistingManifest::Replace,
+            &manifest_options(),
+        )
+        .unwrap();
+        let element = parse_xml(
+            &fs::read(
+                root.path()
+                    .join(LOCAL_MANIFESTS_DIR)
+                    .join("device_manifest.xml"),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let emitted: Vec<(&str, &str)> = element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == manifest::defs::REMOTE_ELEMENT)
+            .map(|element| {
+                (
+                    element.attributes[manifest::defs::ATTR_NAME].as_str(),
+                    element.attributes[manifest::defs::ATTR_FETCH].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(emitted, [("private", "https://git.example.com")]);
+    }
+
+    #[tokio::test]
+    async fn dependencies_on_gitlab_remotes_resolve_their_own_dependencies() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi", "remote": "gitlab"}]"#,
+        )
+        .await;
+        host.mock_file(
+            "/flamingo/vendor_xiaomi_lmi/-/raw/main/flamingo.dependencies",
+            ResponseTemplate::new(200).set_body_string(
+                r#"[
+                    {"repository": "vendor_xiaomi_sm8250-common", "target_path": "vendor/xiaomi/sm8250-common", "remote": "gitlab"},
+                    {"repository": "LineageOS/android_hardware_xiaomi", "target_path": "hardware/xiaomi", "branch": "lineage-20"}
+                ]"#,
+            ),
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        testing::write_manifest(
+            root.path(),
+            "gitlab.xml",
+            &format!(
+                r#"<manifest><remote name="gitlab" fetch="{}/flamingo" revision="main" /></manifest>"#,
+                host.uri()
+            ),
+        );
+
+        // The mock host is not recognizable as gitlab by its url
+        let dependencies = resolve_device_repo(&host, &root, &[]).await.unwrap();
+        assert_eq!(paths_of(&dependencies), ["vendor/xiaomi/lmi"]);
+
+        let dependencies = resolve_device_repo(&host, &root, &["--remote-kind", "gitlab=gitlab"])
+            .await
+            .unwrap();
+        assert_eq!(
+            paths_of(&dependencies),
+            [
+                "vendor/xiaomi/lmi",
+                "vendor/xiaomi/sm8250-common",
+                "hardware/xiaomi"
+            ]
+        );
+        assert!(host.requested_paths().await.contains(&String::from(
+            "/flamingo/vendor_xiaomi_sm8250-common/-/raw/main/flamingo.dependencies"
+        )));
+    }
+
+    #[test]
+    fn remote_kinds_are_parsed() {
+        assert_eq!(
+            parse_remote_kind("gitlab=GitLab").unwrap(),
+            (String::from("gitlab"), RemoteKind::Gitlab)
+        );
+        assert_eq!(
+            parse_remote_kind("gitlab").unwrap_err(),
+            "gitlab is not of the form <remote>=<kind>"
+        );
+        assert!(parse_remote_kind("gitlab=gitea").is_err());
+    }
+
+    #[test]
+    fn shallow_dependencies_are_emitted_as_partial_clones() {
+        let mut kernel = testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250");
+        kernel.clone_depth = Some(String::from(SHALLOW_CLONE_DEPTH));
+        let mut vendor = testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi");
+        vendor.clone_depth = Some(String::from("10"));
+        let mut dependencies = vec![kernel, vendor];
+        let dir = tempfile::TempDir::new().unwrap();
+
+        use_partial_clones(&mut dependencies);
+        create_manifest(
+            dependencies,
+            &[],
+            &[],
+            dir.path().to_str().unwrap(),
+            ExistingManifest::Replace,
+            &manifest_options(),
+        )
+        .unwrap();
+
+        let element =
+            parse_xml(&fs::read(dir.path().join("device_manifest.xml")).unwrap()).unwrap();
+        let clone_attributes: Vec<(&str, Option<&str>, Option<&str>)> = element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == manifest::defs::PROJECT_ELEMENT)
+            .map(|element| {
+                let attribute = |name| element.attributes.get(name).map(String::as_str);
+                (
+                    attribute(manifest::defs::ATTR_PATH).unwrap(),
+                    attribute(manifest::defs::ATTR_CLONE_DEPTH),
+                    attribute(manifest::defs::ATTR_CLONE_FILTER),
+                )
+            })
+            .collect();
+        assert_eq!(
+            clone_attributes,
+            [
+                ("kernel/xiaomi/sm8250", None, Some(PARTIAL_CLONE_FILTER)),
+                ("vendor/xiaomi/lmi", Some("10"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn post_resolve_hook_gets_the_manifest_path() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hook = dir.path().join("hook.sh");
+        let output = dir.path().join("output");
+        fs::write(
+            &hook,
+            format!(
+                "echo \"$@\" \"${ENV_HOOK_MANIFEST}\" \"${ENV_HOOK_PROJECT_COUNT}\" > {}\n",
+                output.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+        let hook = format!("sh {}", hook.to_str().unwrap());
+        let manifest_path = "/src/.repo/local_manifests/device_manifest.xml";
+
+        run_post_resolve_hook(&hook, manifest_path, 7).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            format!("{manifest_path} 7 {manifest_path} 7\n")
+        );
+        assert_eq!(
+            run_post_resolve_hook("exit 3", manifest_path, 7).unwrap_err(),
+            "Post resolve hook exited with exit status: 3"
+        );
+    }
+
+    #[tokio::test]
+    async fn device_revision_fetches_the_dependency_file_at_the_commit() {
+        const SHA: &str = "3f786850e387550fdab836ed7e6dc881de23001b";
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            SHA,
+            r#"[{"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"}]"#,
+        )
+        .await;
+        host.mock_dependency_file(
+            &format!("{ORG}/device_xiaomi_sm8250-common"),
+            DEFAULT_BRANCH,
+            r#"[{"repository": "vendor_xiaomi_sm8250-common", "target_path": "vendor/xiaomi/sm8250-common"}]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let device_repo = format!("{ORG}/{DEVICE_REPO}");
+        let context = Context::new(testing::args(
+            &host,
+            &root,
+            &["--device-repo", &device_repo, "--device-revision", SHA],
+        ));
+        let resolver = context.resolver();
+
+        let device = get_device_dependency(&resolver, "lmi", &context.args)
+            .await
+            .unwrap();
+        let dependencies = get_dependencies(&resolver, &device, &[]).await.unwrap();
+
+        assert_eq!(device.branch, SHA);
+        assert_eq!(device.upstream.as_deref(), Some(DEFAULT_BRANCH));
+        assert_eq!(
+            paths_of(&dependencies),
+            ["device/xiaomi/sm8250-common", "vendor/xiaomi/sm8250-common"]
+        );
+        assert_eq!(
+            host.requested_paths().await,
+            [
+                format!("/{ORG}/{DEVICE_REPO}/{SHA}/flamingo.dependencies"),
+                format!(
+                    "/{ORG}/device_xiaomi_sm8250-common/{DEFAULT_BRANCH}/flamingo.dependencies"
+                ),
+                format!(
+                    "/{ORG}/vendor_xiaomi_sm8250-common/{DEFAULT_BRANCH}/flamingo.dependencies"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn locked_runs_use_the_revisions_of_the_lockfile() {
+        let upstream = tempfile::TempDir::new().unwrap();
+        let git = |repo: &str, args: &[&str]| {
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(upstream.path().join(repo))
+                .args([
+                    "-c",
+                    "user.name=Flamingo",
+                    "-c",
+                    "user.email=flamingo@example.com",
+                ])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_owned()
+        };
+        let commit = |repo: &str| {
+            git(repo, &["commit", "-q", "--allow-empty", "-m", "Update"]);
+            git(repo, &["rev-parse", "HEAD"])
+        };
+        for repo in [DEVICE_REPO, "vendor_xiaomi_lmi"] {
+            fs::create_dir(upstream.path().join(repo)).unwrap();
+            git(repo, &["init", "-q", "-b", DEFAULT_BRANCH]);
+        }
+        let device_sha = commit(DEVICE_REPO);
+        let vendor_sha = commit("vendor_xiaomi_lmi");
+        git("vendor_xiaomi_lmi", &["tag", "v1"]);
+        let deps = r#"[{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"}]"#;
+        let host = MockHost::start().await;
+        for branch in [DEFAULT_BRANCH, &device_sha] {
+            host.mock_dependency_file(&format!("{ORG}/{DEVICE_REPO}"), branch, deps)
+                .await;
+        }
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let dir = tempfile::TempDir::new().unwrap();
+        let lockfile = dir.path().join("roomservice.lock");
+        let lockfile = lockfile.to_str().unwrap();
+        let device_repo = format!("{ORG}/{DEVICE_REPO}");
+        let resolve = |locked: bool| {
+            let mut argv = vec!["--device-repo", &device_repo, "--lockfile", lockfile];
+            if locked {
+                argv.push("--locked");
+            }
+            let mut context = Context::new(testing::args(&host, &root, &argv));
+            context
+                .remotes
+                .remotes
+                .get_mut(remotes::FLAMINGO_DEVICES)
+                .unwrap()
+                .fetch = upstream.path().to_str().unwrap().to_owned();
+            if locked {
+                context.locked_revisions = lockfile::read(lockfile).unwrap();
+            }
+            async move {
+                let resolver = context.resolver();
+                let device = get_device_dependency(&resolver, "lmi", &context.args)
+                    .await
+                    .unwrap();
+                let mut dependencies = vec![device.clone()];
+                dependencies.extend(get_dependencies(&resolver, &device, &[]).await.unwrap());
+                (dependencies, context)
+            }
+        };
+
+        let (dependencies, context) = resolve(false).await;
+        lockfile::write(
+            &dependencies.iter().collect::<Vec<&Dependency>>(),
+            &context.remotes.remotes,
+            lockfile,
+        )
+        .unwrap();
+        commit(DEVICE_REPO);
+        commit("vendor_xiaomi_lmi");
+        let (dependencies, _) = resolve(true).await;
+        create_manifest(
+            dependencies,
+            &[],
+            &[],
+            dir.path().to_str().unwrap(),
+            ExistingManifest::Replace,
+            &manifest_options(),
+        )
+        .unwrap();
+
+        let manifest_path =
+            manifest::get_path(dir.path().to_str().unwrap(), DEVICE_MANIFEST_FILE_NAME);
+        let manifest = parse_xml(&fs::read(manifest_path).unwrap()).unwrap();
+        let revisions: Vec<(&str, &str, &str)> = manifest
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == manifest::defs::PROJECT_ELEMENT)
+            .map(|element| {
+                let attribute = |name: &str| element.attributes[name].as_str();
+                (
+                    attribute(manifest::defs::ATTR_PATH),
+                    attribute(manifest::defs::ATTR_REVISION),
+                    attribute(manifest::defs::ATTR_UPSTREAM),
+                )
+            })
+            .collect();
+        assert_eq!(
+            revisions,
+            [
+                ("device/xiaomi/lmi", device_sha.as_str(), DEFAULT_BRANCH),
+                ("vendor/xiaomi/lmi", vendor_sha.as_str(), DEFAULT_BRANCH),
+            ]
+        );
+
+        let tag = Dependency {
+            branch: String::from("v1"),
+            ..testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi")
+        };
+        lockfile::write(&[&tag], &context.remotes.remotes, lockfile).unwrap();
+        assert_eq!(
+            lockfile::read(lockfile).unwrap()["vendor/xiaomi/lmi"],
+            vendor_sha
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_runs_do_not_write_side_files() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[{"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"}]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let dir = tempfile::TempDir::new().unwrap();
+        let files = ["deps.dot", "roomservice.lock", "inventory.json"]
+            .map(|name| dir.path().join(name).to_str().unwrap().to_owned());
+        let device_repo = format!("{ORG}/{DEVICE_REPO}");
+        let args = |dry_run: bool| {
+            let mut argv = vec![
+                "--device-repo",
+                &device_repo,
+                "--graph",
+                &files[0],
+                "--inventory",
+                &files[2],
+            ];
+            if dry_run {
+                // Writing it would need to ls-remote the repos
+                argv.extend(["--dry-run", "--lockfile", &files[1]]);
+            }
+            testing::args(&host, &root, &argv)
+        };
+        let manifest_path = manifest::get_path(
+            &format!("{}/{LOCAL_MANIFESTS_DIR}", root.path().to_str().unwrap()),
+            DEVICE_MANIFEST_FILE_NAME,
+        );
+
+        run(args(true)).await.unwrap();
+        for file in files.iter().chain([&manifest_path]) {
+            assert!(!Path::new(file).exists(), "{file} was written");
+        }
+
+        run(args(false)).await.unwrap();
+        for file in [&files[0], &files[2], &manifest_path] {
+            assert!(Path::new(file).exists(), "{file} was not written");
+        }
+    }
+
+    #[tokio::test]
+    async fn remotes_without_a_revision_use_the_manifest_default() {
+        let host = MockHost::start().await;
+        host.mock_dependency_file(
+            &format!("{ORG}/{DEVICE_REPO}"),
+            DEFAULT_BRANCH,
+            r#"[{"repository": "LineageOS/android_kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250"}]"#,
+        )
+        .await;
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+
+        let dependencies = resolve_device_repo(&host, &root, &[]).await.unwrap();
+
+        assert_eq!(paths_of(&dependencies), ["kernel/xiaomi/sm8250"]);
+        assert_eq!(dependencies[0].remote, remotes::GITHUB);
+        assert_eq!(dependencies[0].branch, "A13");
+    }
+
+    #[test]
+    fn only_paths_in_failing_repos_blocks_have_failed() {
+        let paths = [
+            "device/xiaomi/lmi",
+            "vendor/xiaomi/lmi",
+            "kernel/xiaomi/sm8250",
+        ];
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            r#"echo "Fetching: 100% (3/3)"
+echo "error: vendor/xiaomi/lmi-firmware: sync failed" >&2
+echo "Failing repos (network):" >&2
+echo "device/xiaomi/lmi" >&2
+echo "kernel/xiaomi/sm8250-extra" >&2
+echo "" >&2
+echo "kernel/xiaomi/sm8250" >&2
+exit 1"#,
+        );
+
+        let (status, lines) = run_repo_sync(&mut command, true).unwrap();
+        let failed = get_failed_paths(&lines.unwrap(), &paths);
+
+        assert!(!status.success());
+        assert_eq!(failed, ["device/xiaomi/lmi"]);
+        assert_eq!(
+            get_sync_summary(&paths, status, Some(&failed)),
+            "Synced 2 of 3 projects, failed to sync device/xiaomi/lmi"
+        );
+    }
+
+    #[test]
+    fn sync_summary_is_printed_without_captured_output() {
+        let paths = ["device/xiaomi/lmi", "vendor/xiaomi/lmi"];
+        let success = Command::new("true").status().unwrap();
+        let failure = Command::new("false").status().unwrap();
+
+        assert_eq!(
+            get_sync_summary(&paths, success, None),
+            "Synced 2 of 2 projects"
+        );
+        assert_eq!(
+            get_sync_summary(&paths, failure, Some(&[])),
+            format!("Synced an unknown number of the 2 projects, repo sync exited with {failure}")
+        );
+        assert_eq!(
+            get_sync_summary(&paths, failure, None),
+            format!("Synced an unknown number of the 2 projects, repo sync exited with {failure}")
+        );
+    }
 }
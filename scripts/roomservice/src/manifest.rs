@@ -14,17 +14,28 @@
  * limitations under the License.
  */
 
-use crate::{dependency::Dependency, remotes};
-use std::fs::File;
+use crate::{
+    dependency::{Dependency, RemovedProject},
+    remotes::{self, Remote},
+};
+use clap::ValueEnum;
+use flamingo_utils::manifest::parse_xml;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
 use xmltree::{Element, EmitterConfig, XMLNode};
 
 pub mod defs {
     pub const DEVICE_MANIFEST_FILE_NAME: &str = "device_manifest";
+    pub const COMMON_MANIFEST_FILE_NAME: &str = "common_manifest";
     pub const MANIFEST_EXT: &str = "xml";
 
     pub const MANIFEST_ELEMENT: &str = "manifest";
     pub const REMOTE_ELEMENT: &str = "remote";
     pub const PROJECT_ELEMENT: &str = "project";
+    pub const INCLUDE_ELEMENT: &str = "include";
+    pub const REMOVE_PROJECT_ELEMENT: &str = "remove-project";
+    pub const DEFAULT_ELEMENT: &str = "default";
 
     pub const ATTR_NAME: &str = "name";
     pub const ATTR_PATH: &str = "path";
@@ -32,59 +43,395 @@ pub mod defs {
     pub const ATTR_REMOTE: &str = "remote";
     pub const ATTR_REVISION: &str = "revision";
     pub const ATTR_CLONE_DEPTH: &str = "clone-depth";
+    pub const ATTR_CLONE_FILTER: &str = "clone-filter";
+    pub const ATTR_UPSTREAM: &str = "upstream";
+    pub const ATTR_GROUPS: &str = "groups";
 
     pub const INDENT: &str = "    ";
+
+    pub const GENERATED_COMMENT: &str = " Generated by roomservice, do not edit ";
+}
+
+/// Path prefixes used to tag projects into groups when the manifest
+/// is generated in [`ManifestFormat::Grouped`] format. First match wins,
+/// projects matching none of the prefixes are left ungrouped.
+const PATH_GROUPS: [(&str, &str); 3] = [
+    ("device/", "device"),
+    ("kernel/", "kernel"),
+    ("vendor/", "vendor"),
+];
+
+/// Attributes of projects that roomservice owns. Merging a dependency
+/// into a project replaces these and keeps any other attribute.
+const OWNED_PROJECT_ATTRIBUTES: [&str; 8] = [
+    defs::ATTR_NAME,
+    defs::ATTR_PATH,
+    defs::ATTR_REMOTE,
+    defs::ATTR_REVISION,
+    defs::ATTR_UPSTREAM,
+    defs::ATTR_CLONE_DEPTH,
+    defs::ATTR_CLONE_FILTER,
+    defs::ATTR_GROUPS,
+];
+
+/// Order repo writes the attributes of remotes and projects
+/// in, unknown attributes are written after these by name.
+const CANONICAL_ATTRIBUTE_ORDER: [&str; 9] = [
+    defs::ATTR_NAME,
+    defs::ATTR_PATH,
+    defs::ATTR_FETCH,
+    defs::ATTR_REMOTE,
+    defs::ATTR_REVISION,
+    defs::ATTR_UPSTREAM,
+    defs::ATTR_GROUPS,
+    defs::ATTR_CLONE_DEPTH,
+    defs::ATTR_CLONE_FILTER,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ManifestFormat {
+    /// Projects are emitted without any groups attribute
+    Plain,
+    /// Projects are tagged with device, kernel or vendor groups based on their path
+    Grouped,
 }
 
 pub struct Manifest {
     xml: Element,
+    format: ManifestFormat,
 }
 
 impl Manifest {
-    pub fn new() -> Self {
-        Self {
-            xml: Element::new(defs::MANIFEST_ELEMENT),
-        }
+    pub fn new(format: ManifestFormat) -> Self {
+        let mut xml = Element::new(defs::MANIFEST_ELEMENT);
+        xml.children
+            .push(XMLNode::Comment(defs::GENERATED_COMMENT.to_owned()));
+        Self { xml, format }
     }
 
-    pub fn add_dependencies(&mut self, dependencies: &Vec<Dependency>) {
-        dependencies
+    /// Reads the manifest previously written to dir with the
+    /// file name, returns None if there is no such manifest.
+    pub fn read(
+        dir: &str,
+        file_name: &str,
+        format: ManifestFormat,
+    ) -> Result<Option<Self>, String> {
+        Self::read_file(&get_path(dir, file_name), format)
+    }
+
+    /// Reads the manifest at path, returns None if it does not exist.
+    pub fn read_file(path: &str, format: ManifestFormat) -> Result<Option<Self>, String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Failed to read {path}: {err}")),
+        };
+        let xml = parse_xml(&bytes).map_err(|err| format!("Failed to parse {path}: {err}"))?;
+        Ok(Some(Self { xml, format }))
+    }
+
+    /// Adds the remotes that are not defined in the manifest
+    /// yet, before any of the projects.
+    pub fn add_remotes(&mut self, remotes: &[Remote]) {
+        let position = self
+            .xml
+            .children
             .iter()
-            .map(|dependency| {
-                let mut project_element = Element::new(defs::PROJECT_ELEMENT);
-                let attrs = &mut project_element.attributes;
-                attrs.insert(
-                    defs::ATTR_NAME.to_owned(),
-                    get_project_name(dependency).to_owned(),
-                );
-                attrs.insert(defs::ATTR_PATH.to_owned(), dependency.path.to_owned());
-                attrs.insert(defs::ATTR_REMOTE.to_owned(), dependency.remote.to_owned());
-                attrs.insert(defs::ATTR_REVISION.to_owned(), dependency.branch.to_owned());
-                if let Some(depth) = dependency.clone_depth.as_ref() {
-                    attrs.insert(defs::ATTR_CLONE_DEPTH.to_owned(), depth.to_owned());
+            .position(|node| {
+                node.as_element()
+                    .is_some_and(|element| element.name == defs::PROJECT_ELEMENT)
+            })
+            .unwrap_or(self.xml.children.len());
+        let new_elements: Vec<XMLNode> = remotes
+            .iter()
+            .filter(|remote| {
+                !self.xml.children.iter().any(|node| {
+                    node.as_element().is_some_and(|element| {
+                        element.name == defs::REMOTE_ELEMENT
+                            && element.attributes.get(defs::ATTR_NAME) == Some(&remote.name)
+                    })
+                })
+            })
+            .map(|remote| {
+                let mut remote_element = Element::new(defs::REMOTE_ELEMENT);
+                let attrs = &mut remote_element.attributes;
+                attrs.insert(defs::ATTR_NAME.to_owned(), remote.name.to_owned());
+                attrs.insert(defs::ATTR_FETCH.to_owned(), remote.fetch.to_owned());
+                if let Some(revision) = remote.revision.as_ref() {
+                    attrs.insert(defs::ATTR_REVISION.to_owned(), revision.to_owned());
+                }
+                XMLNode::Element(remote_element)
+            })
+            .collect();
+        self.xml.children.splice(position..position, new_elements);
+    }
+
+    /// Adds a remove-project for each of the removed projects that is
+    /// not in the manifest yet, before any of the projects so that a
+    /// dependency can add a project at the same path again.
+    pub fn add_removals(&mut self, removals: &[RemovedProject]) {
+        let position = self
+            .xml
+            .children
+            .iter()
+            .position(|node| {
+                node.as_element()
+                    .is_some_and(|element| element.name == defs::PROJECT_ELEMENT)
+            })
+            .unwrap_or(self.xml.children.len());
+        let new_elements: Vec<XMLNode> = removals
+            .iter()
+            .filter(|removal| {
+                !self.xml.children.iter().any(|node| {
+                    node.as_element().is_some_and(|element| {
+                        element.name == defs::REMOVE_PROJECT_ELEMENT
+                            && element.attributes.get(defs::ATTR_PATH) == Some(&removal.path)
+                    })
+                })
+            })
+            .map(|removal| {
+                let mut remove_element = Element::new(defs::REMOVE_PROJECT_ELEMENT);
+                let attrs = &mut remove_element.attributes;
+                if let Some(name) = removal.name.as_ref() {
+                    attrs.insert(defs::ATTR_NAME.to_owned(), name.to_owned());
                 }
-                project_element
+                attrs.insert(defs::ATTR_PATH.to_owned(), removal.path.to_owned());
+                XMLNode::Element(remove_element)
             })
+            .collect();
+        self.xml.children.splice(position..position, new_elements);
+    }
+
+    pub fn add_dependencies(&mut self, dependencies: &[Dependency]) {
+        dependencies
+            .iter()
+            .map(|dependency| get_project_element(dependency, self.format))
             .for_each(|element| self.xml.children.push(XMLNode::Element(element)));
     }
 
-    pub fn write(&self, dir: &str) -> Result<(), String> {
-        let file = File::create(format!(
-            "{dir}/{}.{}",
-            defs::DEVICE_MANIFEST_FILE_NAME,
-            defs::MANIFEST_EXT
-        ))
-        .map_err(|err| format!("failed to create manifest file in {dir}: {err}"))?;
+    /// Appends the projects of the dependencies whose path is not in
+    /// the manifest yet, leaving the existing projects untouched.
+    /// Returns the paths of the dependencies that are already in the
+    /// manifest as a project with a different name, remote or revision.
+    pub fn add_new_dependencies(&mut self, dependencies: &[Dependency]) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        for dependency in dependencies {
+            let element = get_project_element(dependency, self.format);
+            let existing_element = self
+                .xml
+                .children
+                .iter()
+                .filter_map(|node| node.as_element())
+                .filter(|element| element.name == defs::PROJECT_ELEMENT)
+                .find(|element| element.attributes.get(defs::ATTR_PATH) == Some(&dependency.path));
+            match existing_element {
+                Some(existing_element) => {
+                    let is_conflicting = [defs::ATTR_NAME, defs::ATTR_REMOTE, defs::ATTR_REVISION]
+                        .iter()
+                        .any(|attr| {
+                            existing_element.attributes.get(*attr) != element.attributes.get(*attr)
+                        });
+                    if is_conflicting {
+                        conflicts.push(dependency.path.to_owned());
+                    }
+                }
+                None => self.xml.children.push(XMLNode::Element(element)),
+            }
+        }
+        conflicts
+    }
+
+    /// Replaces the attributes roomservice owns of the projects at the paths
+    /// of the dependencies and appends the rest. Other attributes, projects
+    /// at other paths, other elements and children of the projects are kept.
+    pub fn merge_dependencies(&mut self, dependencies: &[Dependency]) {
+        for dependency in dependencies {
+            let element = get_project_element(dependency, self.format);
+            let existing_element = self
+                .xml
+                .children
+                .iter_mut()
+                .filter_map(|node| node.as_mut_element())
+                .filter(|element| element.name == defs::PROJECT_ELEMENT)
+                .find(|element| element.attributes.get(defs::ATTR_PATH) == Some(&dependency.path));
+            let existing_element = match existing_element {
+                Some(existing_element) => existing_element,
+                None => {
+                    self.xml.children.push(XMLNode::Element(element));
+                    continue;
+                }
+            };
+            for attribute in OWNED_PROJECT_ATTRIBUTES {
+                match element.attributes.get(attribute) {
+                    Some(value) => {
+                        existing_element
+                            .attributes
+                            .insert(attribute.to_owned(), value.to_owned());
+                    }
+                    None => {
+                        existing_element.attributes.shift_remove(attribute);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the manifest was written by roomservice.
+    pub fn is_generated(&self) -> bool {
+        is_generated(&self.xml)
+    }
+
+    /// Orders the manifest the way `repo manifest` does. Comments come
+    /// first, then remotes sorted by name, other elements as they are and
+    /// projects sorted by path, each with attributes in canonical order.
+    pub fn canonicalize(&mut self) {
+        self.xml
+            .children
+            .iter_mut()
+            .filter_map(|node| node.as_mut_element())
+            .for_each(sort_attributes);
+        sort_elements(&mut self.xml);
+    }
+
+    /// Writes the manifest to path. Elements of generated manifests are
+    /// sorted first, so that they only change when the projects do.
+    pub fn write_file(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path)
+            .map_err(|err| format!("failed to create manifest file {path}: {err}"))?;
+        self.write_to(file)
+    }
+
+    /// Writes the manifest to stdout the same way it's written to a file.
+    pub fn print(&self) -> Result<(), String> {
+        self.write_to(io::stdout().lock())?;
+        println!();
+        Ok(())
+    }
+
+    fn write_to(&self, writer: impl Write) -> Result<(), String> {
         let config = EmitterConfig::new()
             .indent_string(defs::INDENT)
             .perform_indent(true);
-        self.xml
-            .write_with_config(file, config)
+        let mut xml = self.xml.clone();
+        if self.is_generated() {
+            sort_elements(&mut xml);
+        }
+        xml.write_with_config(writer, config)
             .map_err(|err| format!("{err}"))
     }
 }
 
-fn get_project_name(dependency: &Dependency) -> &str {
+/// Path of the manifest with the file name in dir.
+pub fn get_path(dir: &str, file_name: &str) -> String {
+    format!("{dir}/{file_name}.{}", defs::MANIFEST_EXT)
+}
+
+/// Whether the manifest starts with the comment
+/// that roomservice adds to the manifests it writes.
+pub fn is_generated(manifest: &Element) -> bool {
+    manifest.children.first().is_some_and(
+        |node| matches!(node, XMLNode::Comment(comment) if comment == defs::GENERATED_COMMENT),
+    )
+}
+
+/// Moves comments first, then remotes sorted by name, other elements
+/// as they are and projects sorted by path and then name.
+fn sort_elements(manifest: &mut Element) {
+    let mut comments = Vec::new();
+    let mut remotes = Vec::new();
+    let mut others = Vec::new();
+    let mut projects = Vec::new();
+    for node in manifest.children.drain(..) {
+        match node {
+            XMLNode::Element(element) => match element.name.as_str() {
+                defs::REMOTE_ELEMENT => remotes.push(element),
+                defs::PROJECT_ELEMENT => projects.push(element),
+                _ => others.push(element),
+            },
+            other => comments.push(other),
+        }
+    }
+    let sort_key =
+        |element: &Element, attr: &str| element.attributes.get(attr).cloned().unwrap_or_default();
+    remotes.sort_by_cached_key(|element| sort_key(element, defs::ATTR_NAME));
+    projects.sort_by_cached_key(|element| {
+        (
+            sort_key(element, defs::ATTR_PATH),
+            sort_key(element, defs::ATTR_NAME),
+        )
+    });
+    manifest.children = comments
+        .into_iter()
+        .chain(
+            remotes
+                .into_iter()
+                .chain(others)
+                .chain(projects)
+                .map(XMLNode::Element),
+        )
+        .collect();
+}
+
+fn sort_attributes(element: &mut Element) {
+    let mut attributes: Vec<(String, String)> = element.attributes.drain(..).collect();
+    attributes.sort_by_cached_key(|(name, _)| {
+        let position = CANONICAL_ATTRIBUTE_ORDER
+            .iter()
+            .position(|attr| attr == name)
+            .unwrap_or(CANONICAL_ATTRIBUTE_ORDER.len());
+        (position, name.to_owned())
+    });
+    element.attributes = attributes.into_iter().collect();
+}
+
+fn get_project_element(dependency: &Dependency, format: ManifestFormat) -> Element {
+    let mut project_element = Element::new(defs::PROJECT_ELEMENT);
+    let attrs = &mut project_element.attributes;
+    attrs.insert(
+        defs::ATTR_NAME.to_owned(),
+        get_project_name(dependency).to_owned(),
+    );
+    attrs.insert(defs::ATTR_PATH.to_owned(), dependency.path.to_owned());
+    attrs.insert(defs::ATTR_REMOTE.to_owned(), dependency.remote.to_owned());
+    attrs.insert(defs::ATTR_REVISION.to_owned(), dependency.branch.to_owned());
+    if let Some(upstream) = dependency.upstream.as_ref() {
+        attrs.insert(defs::ATTR_UPSTREAM.to_owned(), upstream.to_owned());
+    }
+    if let Some(depth) = dependency.clone_depth.as_ref() {
+        attrs.insert(defs::ATTR_CLONE_DEPTH.to_owned(), depth.to_owned());
+    }
+    if let Some(filter) = dependency.clone_filter.as_ref() {
+        attrs.insert(defs::ATTR_CLONE_FILTER.to_owned(), filter.to_owned());
+    }
+    let mut groups: Vec<&str> = dependency
+        .groups
+        .iter()
+        .flat_map(|groups| groups.split(','))
+        .map(|group| group.trim())
+        .filter(|group| !group.is_empty())
+        .collect();
+    if format == ManifestFormat::Grouped {
+        if let Some(group) = get_path_group(&dependency.path) {
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+    }
+    if !groups.is_empty() {
+        attrs.insert(defs::ATTR_GROUPS.to_owned(), groups.join(","));
+    }
+    project_element
+}
+
+fn get_path_group(path: &str) -> Option<&'static str> {
+    PATH_GROUPS
+        .iter()
+        .find(|(prefix, _)| path.starts_with(prefix))
+        .map(|(_, group)| *group)
+}
+
+/// Name of the project of the dependency relative to its remote.
+pub fn get_project_name(dependency: &Dependency) -> &str {
     if dependency.remote == remotes::GITHUB || !dependency.name.contains("/") {
         &dependency.name
     } else {
@@ -92,3 +439,166 @@ fn get_project_name(dependency: &Dependency) -> &str {
         repo_name
     }
 }
+
+/// Url the dependency is cloned from, None if its remote is unknown.
+/// Relative fetch urls are resolved against the manifest url.
+pub fn get_clone_url(dependency: &Dependency, remotes: &HashMap<String, Remote>) -> Option<String> {
+    remotes.get(&dependency.remote).map(|remote| {
+        format!(
+            "{}/{}",
+            remote.get_fetch_url().trim_end_matches('/'),
+            get_project_name(dependency)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn groups_of(dependency: &Dependency, format: ManifestFormat) -> Option<String> {
+        get_project_element(dependency, format)
+            .attributes
+            .get(defs::ATTR_GROUPS)
+            .cloned()
+    }
+
+    #[test]
+    fn grouped_format_assigns_groups_by_path() {
+        let groups: Vec<Option<String>> = [
+            "device/xiaomi/lmi",
+            "kernel/xiaomi/sm8250",
+            "vendor/xiaomi/lmi",
+            "hardware/qcom/display",
+            "devices/xiaomi/lmi",
+        ]
+        .iter()
+        .map(|path| groups_of(&testing::dependency("repo", path), ManifestFormat::Grouped))
+        .collect();
+
+        assert_eq!(
+            groups,
+            [
+                Some(String::from("device")),
+                Some(String::from("kernel")),
+                Some(String::from("vendor")),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn grouped_format_keeps_declared_groups() {
+        let mut dependency = testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi");
+        dependency.groups = Some(String::from("blobs, vendor"));
+
+        assert_eq!(
+            groups_of(&dependency, ManifestFormat::Grouped).as_deref(),
+            Some("blobs,vendor")
+        );
+        assert_eq!(
+            groups_of(&dependency, ManifestFormat::Plain).as_deref(),
+            Some("blobs,vendor")
+        );
+    }
+
+    #[test]
+    fn upstream_is_written_to_the_project() {
+        let mut dependency = testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi");
+        dependency.branch = String::from("0123456789abcdef0123456789abcdef01234567");
+        dependency.upstream = Some(String::from("A13"));
+        let mut manifest = Manifest::new(ManifestFormat::Plain);
+        manifest.add_dependencies(&[dependency]);
+
+        let mut xml = Vec::new();
+        manifest.write_to(&mut xml).unwrap();
+
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(
+            xml.contains(r#"<project name="vendor_xiaomi_lmi" path="vendor/xiaomi/lmi" remote="flamingo-devices" revision="0123456789abcdef0123456789abcdef01234567" upstream="A13" />"#),
+            "{xml}"
+        );
+    }
+
+    #[test]
+    fn plain_format_assigns_no_groups() {
+        let dependency = testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi");
+
+        assert_eq!(groups_of(&dependency, ManifestFormat::Plain), None);
+    }
+
+    #[test]
+    fn canonical_manifest_orders_elements_and_attributes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("device_manifest.xml");
+        fs::write(
+            &file,
+            r#"<manifest>
+                <project clone-depth="1" revision="A13" remote="flamingo-devices" path="vendor/xiaomi/lmi" name="vendor_xiaomi_lmi" custom="yes" groups="vendor" />
+                <remote revision="A13" fetch="https://github.com/FlamingoOS-Devices" name="flamingo-devices" />
+                <project path="device/xiaomi/lmi" upstream="A13" name="device_xiaomi_lmi" />
+                <!-- Picked by hand -->
+            </manifest>"#,
+        )
+        .unwrap();
+        let mut manifest = Manifest::read_file(file.to_str().unwrap(), ManifestFormat::Plain)
+            .unwrap()
+            .unwrap();
+
+        manifest.canonicalize();
+
+        let mut xml = Vec::new();
+        manifest.write_to(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        let lines: Vec<&str> = xml.lines().map(str::trim).collect();
+        assert_eq!(
+            lines[2..],
+            [
+                "<!-- Picked by hand -->",
+                r#"<remote name="flamingo-devices" fetch="https://github.com/FlamingoOS-Devices" revision="A13" />"#,
+                r#"<project name="device_xiaomi_lmi" path="device/xiaomi/lmi" upstream="A13" />"#,
+                r#"<project name="vendor_xiaomi_lmi" path="vendor/xiaomi/lmi" remote="flamingo-devices" revision="A13" groups="vendor" clone-depth="1" custom="yes" />"#,
+                "</manifest>",
+            ]
+        );
+    }
+
+    #[test]
+    fn merging_replaces_only_the_owned_attributes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("device_manifest.xml");
+        fs::write(
+            &file,
+            r#"<manifest>
+                <project name="LineageOS/android_device_xiaomi_lmi" path="device/xiaomi/lmi" remote="github" revision="lineage-20" clone-depth="1" sync-c="true">
+                    <linkfile src="lmi.mk" dest="lmi.mk" />
+                </project>
+            </manifest>"#,
+        )
+        .unwrap();
+        let mut manifest = Manifest::read_file(file.to_str().unwrap(), ManifestFormat::Plain)
+            .unwrap()
+            .unwrap();
+
+        manifest.merge_dependencies(&[testing::dependency(
+            "device_xiaomi_lmi",
+            "device/xiaomi/lmi",
+        )]);
+
+        let mut xml = Vec::new();
+        manifest.write_to(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        let lines: Vec<&str> = xml.lines().map(str::trim).collect();
+        assert_eq!(
+            lines[2..],
+            [
+                r#"<project name="device_xiaomi_lmi" path="device/xiaomi/lmi" remote="flamingo-devices" revision="A13" sync-c="true">"#,
+                r#"<linkfile src="lmi.mk" dest="lmi.mk" />"#,
+                "</project>",
+                "</manifest>",
+            ]
+        );
+    }
+}
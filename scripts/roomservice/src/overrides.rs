@@ -0,0 +1,179 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::{
+    dependency::{self, Dependency},
+    manifest, UTF8_BOM,
+};
+use json::JsonValue;
+use std::{collections::HashMap, fs};
+
+const OVERRIDABLE_KEYS: [&str; 4] = [
+    dependency::DEPS_KEY_PATH,
+    dependency::DEPS_KEY_REMOTE,
+    dependency::DEPS_KEY_BRANCH,
+    dependency::DEPS_KEY_DEPTH,
+];
+
+/// Fields of a resolved dependency to replace, None for
+/// the ones that are left as the dependency file declared.
+#[derive(Default)]
+pub struct Override {
+    path: Option<String>,
+    remote: Option<String>,
+    branch: Option<String>,
+    clone_depth: Option<String>,
+}
+
+/// Reads the overrides from a JSON object keyed by repository (owner/name
+/// or name) or path, with objects using the same keys as dependency files.
+pub fn read(file: &str) -> Result<HashMap<String, Override>, String> {
+    let content = fs::read_to_string(file)
+        .map_err(|err| format!("Failed to read dependency overrides {file}: {err}"))?;
+    let json = json::parse(content.trim_start_matches(UTF8_BOM))
+        .map_err(|err| format!("Dependency overrides {file} is not valid JSON: {err}"))?;
+    match json {
+        JsonValue::Object(object) => object
+            .iter()
+            .map(|(key, value)| Ok((key.to_owned(), parse_override(key, value, file)?)))
+            .collect(),
+        other => Err(format!(
+            "Dependency overrides {file} should be a JSON object, found {}",
+            other.pretty(4)
+        )),
+    }
+}
+
+fn parse_override(key: &str, value: &JsonValue, file: &str) -> Result<Override, String> {
+    let object = match value {
+        JsonValue::Object(object) => object,
+        _ => {
+            return Err(format!(
+                "Override of {key} in {file} should be a JSON object"
+            ))
+        }
+    };
+    let mut dependency_override = Override::default();
+    for (field, value) in object.iter() {
+        if !OVERRIDABLE_KEYS.contains(&field) {
+            return Err(format!(
+                "Override of {key} in {file} has unknown key {field}, expected one of {}",
+                OVERRIDABLE_KEYS.join(", ")
+            ));
+        }
+        let value = value.as_str().map(str::to_owned).ok_or(format!(
+            "Value of {field} in override of {key} in {file} is not a string"
+        ))?;
+        let slot = match field {
+            dependency::DEPS_KEY_PATH => &mut dependency_override.path,
+            dependency::DEPS_KEY_REMOTE => &mut dependency_override.remote,
+            dependency::DEPS_KEY_BRANCH => &mut dependency_override.branch,
+            _ => &mut dependency_override.clone_depth,
+        };
+        *slot = Some(value);
+    }
+    Ok(dependency_override)
+}
+
+/// Applies the override whose key is the repository, the name of
+/// the repository or the path of each of the dependencies.
+pub fn apply<'a>(
+    overrides: &HashMap<String, Override>,
+    dependencies: impl IntoIterator<Item = &'a mut Dependency>,
+) {
+    for dependency in dependencies {
+        let dependency_override = match [
+            dependency.name.as_str(),
+            manifest::get_project_name(dependency),
+            dependency.path.as_str(),
+        ]
+        .iter()
+        .find_map(|key| overrides.get(*key))
+        {
+            Some(dependency_override) => dependency_override,
+            None => continue,
+        };
+        if let Some(path) = dependency_override.path.as_ref() {
+            dependency.path = path.to_owned();
+        }
+        if let Some(remote) = dependency_override.remote.as_ref() {
+            dependency.remote = remote.to_owned();
+        }
+        if let Some(branch) = dependency_override.branch.as_ref() {
+            dependency.branch = branch.to_owned();
+        }
+        if let Some(clone_depth) = dependency_override.clone_depth.as_ref() {
+            dependency.clone_depth = Some(clone_depth.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{remotes, testing, DEFAULT_BRANCH};
+
+    fn read_overrides(content: &str) -> Result<HashMap<String, Override>, String> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("overrides.json");
+        fs::write(&file, content).unwrap();
+        read(file.to_str().unwrap()).map_err(|err| err.replace(file.to_str().unwrap(), "<file>"))
+    }
+
+    #[test]
+    fn overrides_replace_the_fields_they_set() {
+        let overrides = read_overrides(
+            r#"{
+                "FlamingoOS-Devices/kernel_xiaomi_sm8250": {"branch": "A13-next", "clone-depth": "1"},
+                "vendor_xiaomi_lmi": {"remote": "github"},
+                "hardware/xiaomi": {"target_path": "hardware/xiaomi-legacy"}
+            }"#,
+        )
+        .unwrap();
+        let mut dependencies = vec![
+            testing::dependency("kernel_xiaomi_sm8250", "kernel/xiaomi/sm8250"),
+            testing::dependency("vendor_xiaomi_lmi", "vendor/xiaomi/lmi"),
+            testing::dependency("hardware_xiaomi", "hardware/xiaomi"),
+            testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi"),
+        ];
+
+        apply(&overrides, &mut dependencies);
+
+        let kernel = &dependencies[0];
+        assert_eq!(kernel.branch, "A13-next");
+        assert_eq!(kernel.clone_depth.as_deref(), Some("1"));
+        assert_eq!(kernel.remote, remotes::FLAMINGO_DEVICES);
+        assert_eq!(dependencies[1].remote, remotes::GITHUB);
+        assert_eq!(dependencies[1].branch, DEFAULT_BRANCH);
+        assert_eq!(dependencies[2].path, "hardware/xiaomi-legacy");
+        let device = testing::dependency("device_xiaomi_lmi", "device/xiaomi/lmi");
+        assert_eq!(dependencies[3].path, device.path);
+        assert_eq!(dependencies[3].branch, device.branch);
+        assert_eq!(dependencies[3].remote, device.remote);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = read_overrides(r#"{"vendor_xiaomi_lmi": {"revision": "A14"}}"#)
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err,
+            "Override of vendor_xiaomi_lmi in <file> has unknown key revision, expected one of target_path, remote, branch, clone-depth"
+        );
+    }
+}
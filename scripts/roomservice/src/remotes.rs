@@ -14,22 +14,205 @@
  * limitations under the License.
  */
 
-use crate::manifest::defs;
-use std::collections::HashMap;
+use crate::manifest::{self, defs};
+use clap::ValueEnum;
+use flamingo_utils::manifest::parse_xml;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{BufReader, Read};
 use std::path::Path;
+use std::process::Command;
 use std::vec::Vec;
 use xmltree::Element;
 
 pub const GITHUB: &str = "github";
 pub const FLAMINGO_DEVICES: &str = "flamingo-devices";
 
+const GITHUB_HOST: &str = "github.com";
+const GITLAB_HOST: &str = "gitlab.com";
+
+/// Manifest repo reads, relative to the manifest root. Included
+/// manifests are relative to the manifests dir in the root.
+const ROOT_MANIFEST: &str = "manifest.xml";
+const INCLUDE_DIR: &str = "manifests";
+
 #[derive(Clone, Debug)]
 pub struct Remote {
     pub name: String,
     pub fetch: String,
     pub revision: Option<String>,
+    /// Manifest the remote is defined in
+    pub manifest: String,
+    /// Url of the manifests repository the remote is defined in,
+    /// which a relative fetch url is resolved against
+    pub manifest_url: Option<String>,
+}
+
+/// Remotes defined by the manifests, along with the revision of their
+/// <default> element, which repo uses for remotes without a revision.
+#[derive(Debug, Default)]
+pub struct ManifestRemotes {
+    pub remotes: HashMap<String, Remote>,
+    pub default_revision: Option<String>,
+}
+
+impl ManifestRemotes {
+    /// Remotes and default of the other override the ones in self.
+    fn extend(&mut self, other: ManifestRemotes) {
+        self.remotes.extend(other.remotes);
+        if other.default_revision.is_some() {
+            self.default_revision = other.default_revision;
+        }
+    }
+
+    /// Like [`ManifestRemotes::extend`], but the remotes of the other must
+    /// not conflict with the ones in self, see [`ManifestRemotes::add`].
+    fn merge(&mut self, other: ManifestRemotes, lenient: bool) -> Result<(), String> {
+        let mut remotes: Vec<Remote> = other.remotes.into_values().collect();
+        remotes.sort_by(|a, b| a.name.cmp(&b.name));
+        for remote in remotes {
+            self.add(remote, lenient)?;
+        }
+        if other.default_revision.is_some() {
+            self.default_revision = other.default_revision;
+        }
+        Ok(())
+    }
+
+    /// Adds the remote. A remote with the same name but another fetch or
+    /// revision that was added before is an error, since which one wins
+    /// would depend on the order manifests are read in. If lenient it is
+    /// only warned about and the remote added last wins.
+    fn add(&mut self, remote: Remote, lenient: bool) -> Result<(), String> {
+        if let Some(existing) = self.remotes.get(&remote.name) {
+            if existing.fetch != remote.fetch || existing.revision != remote.revision {
+                let message = format!(
+                    "Remote {} is defined in {} as {}, but in {} as {}",
+                    remote.name,
+                    existing.manifest,
+                    existing.describe(),
+                    remote.manifest,
+                    remote.describe()
+                );
+                if !lenient {
+                    return Err(format!(
+                        "{message}, remove one of them or pass --lenient to use the latter"
+                    ));
+                }
+                warn!("{message}, using the latter");
+            }
+        }
+        self.remotes.insert(remote.name.to_owned(), remote);
+        Ok(())
+    }
+}
+
+/// Hosting of a remote, which decides where raw files of its repos are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RemoteKind {
+    /// Raw files are served from the github raw url
+    Github,
+    /// Raw files are at <repo>/-/raw/<branch>/<file>
+    Gitlab,
+    /// Raw files are at <repo>/raw/<branch>/<file>, like gitea does
+    Generic,
+}
+
+impl Remote {
+    fn describe(&self) -> String {
+        format!(
+            "fetch={} revision={}",
+            self.fetch,
+            self.revision.as_deref().unwrap_or("<none>")
+        )
+    }
+
+    /// Fetch url with a relative one like .. resolved against the url
+    /// of the manifests repository the way repo does, as long as it is known.
+    pub fn get_fetch_url(&self) -> String {
+        match self.manifest_url.as_ref() {
+            Some(manifest_url) if is_relative_url(&self.fetch) => {
+                join_url(manifest_url, &self.fetch)
+            }
+            _ => self.fetch.to_owned(),
+        }
+    }
+
+    /// Kind of the remote told from the host of its fetch url, only
+    /// github.com and gitlab.com are recognized. Remotes with a relative
+    /// fetch url are relative to the manifest, which is on github.
+    pub fn kind(&self) -> RemoteKind {
+        let authority = match self.fetch.split_once("://") {
+            Some((_, url)) => url.split('/').next().unwrap_or_default(),
+            None => return RemoteKind::Github,
+        };
+        let host_and_port = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host);
+        let host = host_and_port
+            .split_once(':')
+            .map_or(host_and_port, |(host, _)| host);
+        match host.to_ascii_lowercase().trim_start_matches("www.") {
+            GITHUB_HOST => RemoteKind::Github,
+            GITLAB_HOST => RemoteKind::Gitlab,
+            _ => RemoteKind::Generic,
+        }
+    }
+}
+
+/// Whether the url is neither absolute, has a scheme nor is a scp like host:path.
+fn is_relative_url(url: &str) -> bool {
+    !url.starts_with('/') && !url.contains(':')
+}
+
+/// Resolves the relative url against the base like urljoin does, so the
+/// last path segment of the base is replaced by it.
+fn join_url(base: &str, relative: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let path_start = match base.split_once("://") {
+        Some((scheme, url)) => url.find('/').map(|index| scheme.len() + 3 + index),
+        None => base.find(':').map(|index| index + 1),
+    };
+    let (origin, path) = base.split_at(path_start.unwrap_or(base.len()));
+    let mut segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    segments.pop();
+    for segment in relative.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let origin = origin.trim_end_matches('/');
+    if origin.ends_with(':') {
+        format!("{origin}{}", segments.join("/"))
+    } else {
+        format!("{origin}/{}", segments.join("/"))
+    }
+}
+
+/// Url of the origin of the manifests repository checked out in the
+/// dir, None if it is not a git repository.
+fn get_manifest_url(manifest_dir: &str) -> Option<String> {
+    let dir = Path::new(manifest_dir);
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(dir)
+        .args(["config", "--get", "remote.origin.url"]);
+    // Only the dir itself may be the repository, not one it is in
+    if let Some(parent) = dir.parent() {
+        command.env("GIT_CEILING_DIRECTORIES", parent);
+    }
+    let output = command.output().ok()?;
+    let url = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    (output.status.success() && !url.is_empty()).then_some(url)
 }
 
 fn walk_manifest_dir(dir: &Path) -> Result<Vec<String>, String> {
@@ -57,10 +240,10 @@ fn walk_manifest_dir(dir: &Path) -> Result<Vec<String>, String> {
             manifests.push(path.to_owned());
         }
     }
-    return Ok(manifests);
+    Ok(manifests)
 }
 
-fn get_remotes(manifest: &str) -> Result<Vec<Remote>, String> {
+fn read_manifest(manifest: &str) -> Result<Element, String> {
     let manifest_file = File::open(manifest)
         .map_err(|err| format!("Failed to open manifest file {manifest}: {err}"))?;
     let mut bytes: Vec<u8> = Vec::new();
@@ -68,35 +251,418 @@ fn get_remotes(manifest: &str) -> Result<Vec<Remote>, String> {
     let bytes_read = reader
         .read_to_end(&mut bytes)
         .map_err(|err| format!("Failed to read {manifest}: {err}"))?;
-    let xml_element = Element::parse(&bytes[..bytes_read])
-        .map_err(|err| format!("Failed to parse {manifest}: {err}"))?;
-    let remotes = xml_element
+    parse_xml(&bytes[..bytes_read]).map_err(|err| format!("Failed to parse {manifest}: {err}"))
+}
+
+/// Collects remotes and the default revision of the manifest and of the
+/// manifests it includes, which are relative to include_dir, in the order
+/// repo reads them. Manifests that were already visited are skipped, so
+/// include cycles terminate.
+fn collect_remotes(
+    manifest: &str,
+    include_dir: &str,
+    visited: &mut HashSet<String>,
+    remotes: &mut ManifestRemotes,
+    lenient: bool,
+) -> Result<(), String> {
+    if !visited.insert(manifest.to_owned()) {
+        return Ok(());
+    }
+    let xml_element = read_manifest(manifest)?;
+    for element in xml_element
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+    {
+        match element.name.as_str() {
+            defs::REMOTE_ELEMENT => match parse_remote(element, manifest) {
+                Ok(remote) => remotes.add(remote, lenient)?,
+                Err(warning) => warn!("{warning}"),
+            },
+            defs::DEFAULT_ELEMENT => {
+                if let Some(revision) = element.attributes.get(defs::ATTR_REVISION) {
+                    remotes.default_revision = Some(revision.to_owned());
+                }
+            }
+            defs::INCLUDE_ELEMENT => {
+                if let Some(include) = element.attributes.get(defs::ATTR_NAME) {
+                    collect_remotes(
+                        &format!("{include_dir}/{include}"),
+                        include_dir,
+                        visited,
+                        remotes,
+                        lenient,
+                    )?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_remotes(xml_element: &Element, manifest: &str) -> Vec<Remote> {
+    xml_element
         .children
         .iter()
         .filter_map(|node| node.as_element())
         .filter(|element| element.name == defs::REMOTE_ELEMENT)
-        .map(|remote_element| Remote {
-            name: remote_element.attributes[defs::ATTR_NAME].to_owned(),
-            fetch: remote_element.attributes[defs::ATTR_FETCH].to_owned(),
-            revision: remote_element
-                .attributes
+        .filter_map(
+            |remote_element| match parse_remote(remote_element, manifest) {
+                Ok(remote) => Some(remote),
+                Err(warning) => {
+                    warn!("{warning}");
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// Parses the remote, or returns why it is skipped if it lacks
+/// one of the attributes every remote needs.
+fn parse_remote(remote_element: &Element, manifest: &str) -> Result<Remote, String> {
+    let attributes = &remote_element.attributes;
+    let name = attributes.get(defs::ATTR_NAME);
+    let fetch = attributes.get(defs::ATTR_FETCH);
+    match (name, fetch) {
+        (Some(name), Some(fetch)) => Ok(Remote {
+            name: name.to_owned(),
+            fetch: fetch.to_owned(),
+            revision: attributes
                 .get(defs::ATTR_REVISION)
                 .map(|rev| rev.to_owned()),
+            manifest: manifest.to_owned(),
+            manifest_url: None,
+        }),
+        (name, _) => {
+            let missing = if name.is_none() {
+                defs::ATTR_NAME
+            } else {
+                defs::ATTR_FETCH
+            };
+            Err(format!(
+                "Skipping remote {} in {manifest} without {missing} attribute",
+                name.map_or("<unnamed>", |name| name.as_str())
+            ))
+        }
+    }
+}
+
+/// Names of the remotes that repo knows about, which are the ones defined
+/// in manifest.xml of the manifest root and the manifests it includes, and
+/// in the local manifests that were not generated by roomservice. Returns
+/// None if the manifest root does not have a manifest.xml.
+pub fn get_included_remotes(
+    manifest_root: &str,
+    local_manifest_dir: &str,
+    lenient: bool,
+) -> Result<Option<HashSet<String>>, String> {
+    let root_manifest = format!("{manifest_root}/{ROOT_MANIFEST}");
+    if !Path::new(&root_manifest).exists() {
+        return Ok(None);
+    }
+    let mut included = ManifestRemotes::default();
+    collect_remotes(
+        &root_manifest,
+        &format!("{manifest_root}/{INCLUDE_DIR}"),
+        &mut HashSet::new(),
+        &mut included,
+        lenient,
+    )?;
+    let mut remotes: HashSet<String> = included.remotes.into_keys().collect();
+    let local_manifest_dir = Path::new(local_manifest_dir);
+    if !local_manifest_dir.exists() {
+        return Ok(Some(remotes));
+    }
+    for manifest in walk_manifest_dir(local_manifest_dir)? {
+        let xml_element = read_manifest(&manifest)?;
+        if !manifest::is_generated(&xml_element) {
+            remotes.extend(
+                parse_remotes(&xml_element, &manifest)
+                    .into_iter()
+                    .map(|remote| remote.name),
+            );
+        }
+    }
+    Ok(Some(remotes))
+}
+
+/// Collects remotes from all the manifests in each of the dirs.
+/// Dirs are processed in order, and a remote or default revision
+/// defined in a later dir overrides the one from earlier dirs. Within
+/// a dir, conflicting definitions of a remote are an error unless
+/// lenient, see [`ManifestRemotes::add`].
+pub fn get_all_remotes(manifest_dirs: &[String], lenient: bool) -> Result<ManifestRemotes, String> {
+    let mut all_remotes = ManifestRemotes::default();
+    for manifest_dir in manifest_dirs {
+        let dir_remotes = get_remotes_in_dir(manifest_dir, lenient)?;
+        for name in dir_remotes.remotes.keys() {
+            if all_remotes.remotes.contains_key(name) {
+                info!("Remote {name} is overridden by the one in {manifest_dir}");
+            }
+        }
+        all_remotes.extend(dir_remotes);
+    }
+    Ok(all_remotes)
+}
+
+/// Manifests are parsed in parallel, but merged in the sorted order
+/// of their paths so that the result does not depend on scheduling.
+/// Includes are resolved relative to the dir, like repo does for the
+/// manifests repository.
+fn get_remotes_in_dir(manifest_dir: &str, lenient: bool) -> Result<ManifestRemotes, String> {
+    let mut manifests = walk_manifest_dir(Path::new(manifest_dir))?;
+    manifests.sort();
+    let manifest_remotes = manifests
+        .par_iter()
+        .map(|manifest| {
+            let mut remotes = ManifestRemotes::default();
+            collect_remotes(
+                manifest,
+                manifest_dir,
+                &mut HashSet::new(),
+                &mut remotes,
+                lenient,
+            )?;
+            Ok(remotes)
         })
+        .collect::<Result<Vec<ManifestRemotes>, String>>()?;
+    let mut all_remotes = ManifestRemotes::default();
+    for remotes in manifest_remotes {
+        all_remotes.merge(remotes, lenient)?;
+    }
+    let manifest_url = get_manifest_url(manifest_dir);
+    all_remotes
+        .remotes
+        .values_mut()
+        .for_each(|remote| remote.manifest_url = manifest_url.clone());
+    Ok(all_remotes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, SOURCE_MANIFESTS_DIR};
+
+    fn manifest_dir(root: &tempfile::TempDir) -> String {
+        format!("{}/{SOURCE_MANIFESTS_DIR}", root.path().display())
+    }
+
+    /// Remotes sorted by name, with the manifest they come from.
+    fn describe_remotes(manifest_remotes: &ManifestRemotes) -> Vec<String> {
+        let mut remotes: Vec<String> = manifest_remotes
+            .remotes
+            .values()
+            .map(|remote| format!("{} {} {}", remote.name, remote.describe(), remote.manifest))
+            .collect();
+        remotes.sort();
+        remotes
+    }
+
+    #[test]
+    fn parallel_collection_matches_sequential_collection() {
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let root_path = root.path();
+        testing::write_manifest(
+            root_path,
+            "aosp.xml",
+            r#"<manifest>
+                <remote name="aosp" fetch="https://android.googlesource.com" revision="android-13" />
+                <remote name="github" fetch="https://github.com" revision="A13" />
+                <default revision="android-13" />
+            </manifest>"#,
+        );
+        testing::write_manifest(
+            root_path,
+            "snippets/clo.xml",
+            r#"<manifest>
+                <remote name="clo" fetch="https://git.codelinaro.org" />
+                <include name="snippets/shared.xml" />
+            </manifest>"#,
+        );
+        testing::write_manifest(
+            root_path,
+            "snippets/shared.xml",
+            r#"<manifest>
+                <remote name="aosp" fetch="https://mirror.example.com" />
+                <remote name="shared" fetch="https://shared.example.com" />
+            </manifest>"#,
+        );
+        let dir = manifest_dir(&root);
+
+        let mut manifests = walk_manifest_dir(Path::new(&dir)).unwrap();
+        manifests.sort();
+        let mut sequential = ManifestRemotes::default();
+        for manifest in manifests {
+            collect_remotes(&manifest, &dir, &mut HashSet::new(), &mut sequential, true).unwrap();
+        }
+
+        for _ in 0..10 {
+            let parallel = get_remotes_in_dir(&dir, true).unwrap();
+            assert_eq!(describe_remotes(&parallel), describe_remotes(&sequential));
+            assert_eq!(parallel.default_revision, sequential.default_revision);
+        }
+    }
+
+    #[test]
+    fn later_roots_override_remotes() {
+        let public = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let private = testing::manifest_root(
+            r#"<manifest>
+                <remote name="github" fetch="https://git.example.com/mirror" revision="private" />
+                <remote name="private" fetch="https://git.example.com" />
+            </manifest>"#,
+        );
+
+        let manifest_remotes =
+            get_all_remotes(&[manifest_dir(&public), manifest_dir(&private)], false).unwrap();
+
+        let mut remotes: Vec<(&str, &str, Option<&str>)> = manifest_remotes
+            .remotes
+            .values()
+            .map(|remote| {
+                (
+                    remote.name.as_str(),
+                    remote.fetch.as_str(),
+                    remote.revision.as_deref(),
+                )
+            })
+            .collect();
+        remotes.sort();
+        assert_eq!(
+            remotes,
+            [
+                (
+                    "flamingo-devices",
+                    "https://github.com/FlamingoOS-Devices",
+                    Some("A13")
+                ),
+                ("github", "https://git.example.com/mirror", Some("private")),
+                ("private", "https://git.example.com", None),
+            ]
+        );
+        // The private root has no <default>, so the public one is kept
+        assert_eq!(manifest_remotes.default_revision.as_deref(), Some("A13"));
+    }
+
+    #[test]
+    fn remotes_without_fetch_are_skipped_with_a_warning() {
+        let manifest = r#"<manifest>
+                <remote name="github" fetch="https://github.com" />
+                <remote name="broken" />
+                <remote fetch="https://example.com" />
+            </manifest>"#;
+        let root = testing::manifest_root(manifest);
+        let element = read_manifest(&format!("{}/default.xml", manifest_dir(&root))).unwrap();
+        let remotes: Vec<Result<Remote, String>> = element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .map(|remote_element| parse_remote(remote_element, "default.xml"))
+            .collect();
+
+        assert_eq!(remotes[0].as_ref().unwrap().fetch, "https://github.com");
+        assert_eq!(
+            remotes[1].as_ref().unwrap_err(),
+            "Skipping remote broken in default.xml without fetch attribute"
+        );
+        assert_eq!(
+            remotes[2].as_ref().unwrap_err(),
+            "Skipping remote <unnamed> in default.xml without name attribute"
+        );
+        let names: Vec<String> = parse_remotes(&element, "default.xml")
+            .into_iter()
+            .map(|remote| remote.name)
+            .collect();
+        assert_eq!(names, vec!["github"]);
+    }
+
+    #[test]
+    fn kind_is_only_guessed_for_known_hosts() {
+        let kinds: Vec<RemoteKind> = [
+            "https://github.com/FlamingoOS-Devices",
+            "ssh://git@github.com:22/Flamingo-OS",
+            "https://gitlab.com/flamingo",
+            "https://GitLab.com/",
+            "https://github.example.com/mirror",
+            "https://gitlab.example.com",
+            "https://mygithub.com",
+            "..",
+        ]
+        .iter()
+        .map(|fetch| testing::remote("remote", fetch, None).kind())
         .collect();
-    Ok(remotes)
-}
-
-pub fn get_all_remotes(manifest_dir: &str) -> Result<HashMap<String, Remote>, String> {
-    let manifests = walk_manifest_dir(&Path::new(manifest_dir))?;
-    let mut all_remotes: HashMap<String, Remote> = HashMap::new();
-    for manifest in manifests {
-        let remotes = get_remotes(&manifest)?;
-        all_remotes.extend(
-            remotes
-                .iter()
-                .map(|remote| (remote.name.to_owned(), remote.clone())),
+
+        assert_eq!(
+            kinds,
+            [
+                RemoteKind::Github,
+                RemoteKind::Github,
+                RemoteKind::Gitlab,
+                RemoteKind::Gitlab,
+                RemoteKind::Generic,
+                RemoteKind::Generic,
+                RemoteKind::Generic,
+                RemoteKind::Github,
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_fetch_urls_are_resolved_against_the_manifest_url() {
+        let root = testing::manifest_root(
+            r#"<manifest>
+    <remote name="caf" fetch=".." />
+    <remote name="flamingo" fetch="." />
+    <remote name="aosp" fetch="https://android.googlesource.com" />
+</manifest>"#,
+        );
+        let dir = manifest_dir(&root);
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&[
+            "remote",
+            "add",
+            "origin",
+            "https://github.com/Flamingo-OS/manifest",
+        ]);
+
+        let manifest_remotes = get_all_remotes(&[dir], false).unwrap();
+        let fetch_url = |name: &str| manifest_remotes.remotes[name].get_fetch_url();
+
+        assert_eq!(fetch_url("caf"), "https://github.com/");
+        assert_eq!(fetch_url("flamingo"), "https://github.com/Flamingo-OS");
+        assert_eq!(fetch_url("aosp"), "https://android.googlesource.com");
+
+        let remote = |fetch: &str, manifest_url: Option<&str>| Remote {
+            manifest_url: manifest_url.map(|url| url.to_owned()),
+            ..testing::remote("caf", fetch, None)
+        };
+        assert_eq!(
+            remote("../..", Some("https://git.example.com/a/b/manifest/")).get_fetch_url(),
+            "https://git.example.com/"
+        );
+        assert_eq!(
+            remote(
+                "../platform",
+                Some("ssh://git@example.com:29418/a/manifest")
+            )
+            .get_fetch_url(),
+            "ssh://git@example.com:29418/platform"
+        );
+        assert_eq!(remote("..", None).get_fetch_url(), "..");
+        assert_eq!(
+            remote("/srv/git", Some("https://example.com/manifest")).get_fetch_url(),
+            "/srv/git"
         );
     }
-    return Ok(all_remotes);
 }
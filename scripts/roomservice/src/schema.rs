@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use json::JsonValue;
+
+const KEY_TYPE: &str = "type";
+const KEY_ITEMS: &str = "items";
+const KEY_REQUIRED: &str = "required";
+const KEY_PROPERTIES: &str = "properties";
+
+const DEPS_KEY_NAME: &str = "repository";
+const DEPS_KEY_ACTION: &str = "action";
+const ACTION_REMOVE: &str = "remove";
+
+/// JSON Schema of the dependency file. Only the "type", "items",
+/// "required" and "properties" keywords are understood by [`validate`].
+const DEPENDENCY_SCHEMA: &str = r#"
+{
+    "type": "array",
+    "items": {
+        "type": "object",
+        "required": ["target_path"],
+        "properties": {
+            "repository": { "type": "string" },
+            "target_path": { "type": "string" },
+            "remote": { "type": "string" },
+            "branch": { "type": "string" },
+            "clone-depth": { "type": "string" },
+            "upstream": { "type": "string" },
+            "groups": { "type": "string" },
+            "action": { "type": "string" },
+            "variant": { "type": "string" }
+        }
+    }
+}
+"#;
+
+/// Validates the parsed dependency file against [`DEPENDENCY_SCHEMA`].
+/// Every violation found is returned, prefixed by the JSON pointer
+/// of the offending value. The schema can not express that only removals
+/// may leave out the repository, so that is checked here.
+pub fn validate(value: &JsonValue) -> Vec<String> {
+    let schema = json::parse(DEPENDENCY_SCHEMA).expect("dependency schema is not valid json");
+    let mut violations = Vec::new();
+    validate_value(&schema, value, "", &mut violations);
+    value
+        .members()
+        .enumerate()
+        .filter(|(_, item)| item.is_object() && !item.has_key(DEPS_KEY_NAME))
+        .filter(|(_, item)| item[DEPS_KEY_ACTION].as_str() != Some(ACTION_REMOVE))
+        .for_each(|(index, _)| {
+            violations.push(format!(
+                "/{index}: missing required key \"{DEPS_KEY_NAME}\""
+            ))
+        });
+    violations
+}
+
+fn validate_value(
+    schema: &JsonValue,
+    value: &JsonValue,
+    pointer: &str,
+    violations: &mut Vec<String>,
+) {
+    if let Some(expected_type) = schema[KEY_TYPE].as_str() {
+        let actual_type = get_type(value);
+        if actual_type != expected_type {
+            violations.push(format!(
+                "{}: expected {expected_type}, found {actual_type}",
+                display_pointer(pointer)
+            ));
+            return;
+        }
+    }
+    match value {
+        JsonValue::Array(items) if schema.has_key(KEY_ITEMS) => {
+            items.iter().enumerate().for_each(|(index, item)| {
+                validate_value(
+                    &schema[KEY_ITEMS],
+                    item,
+                    &format!("{pointer}/{index}"),
+                    violations,
+                )
+            });
+        }
+        JsonValue::Object(object) => {
+            schema[KEY_REQUIRED]
+                .members()
+                .filter_map(|key| key.as_str())
+                .filter(|key| object.get(key).is_none())
+                .for_each(|key| {
+                    violations.push(format!(
+                        "{}: missing required key \"{key}\"",
+                        display_pointer(pointer)
+                    ))
+                });
+            schema[KEY_PROPERTIES]
+                .entries()
+                .for_each(|(key, property_schema)| {
+                    if let Some(property) = object.get(key) {
+                        validate_value(
+                            property_schema,
+                            property,
+                            &format!("{pointer}/{}", escape_pointer_token(key)),
+                            violations,
+                        );
+                    }
+                });
+        }
+        _ => {}
+    }
+}
+
+fn get_type(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Null => "null",
+        JsonValue::Short(_) | JsonValue::String(_) => "string",
+        JsonValue::Number(_) => "number",
+        JsonValue::Boolean(_) => "boolean",
+        JsonValue::Object(_) => "object",
+        JsonValue::Array(_) => "array",
+    }
+}
+
+fn display_pointer(pointer: &str) -> &str {
+    if pointer.is_empty() {
+        "/"
+    } else {
+        pointer
+    }
+}
+
+fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_violation_is_reported() {
+        let deps = json::parse(
+            r#"[
+                {"repository": "device_xiaomi_lmi", "branch": 13},
+                "vendor_xiaomi_lmi",
+                {"target_path": "kernel/xiaomi/sm8250", "clone-depth": 1},
+                {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            validate(&deps),
+            [
+                "/0: missing required key \"target_path\"",
+                "/0/branch: expected string, found number",
+                "/1: expected object, found string",
+                "/2/clone-depth: expected string, found number",
+                "/2: missing required key \"repository\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_root_must_be_an_array() {
+        let deps = json::parse(r#"{"repository": "device_xiaomi_lmi"}"#).unwrap();
+        assert_eq!(validate(&deps), ["/: expected array, found object"]);
+    }
+
+    #[test]
+    fn removals_do_not_need_a_repository() {
+        let deps = json::parse(
+            r#"[
+                {"target_path": "hardware/xiaomi", "action": "remove"},
+                {"target_path": "vendor/xiaomi/lmi", "branch": "A13"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(validate(&deps), ["/1: missing required key \"repository\""]);
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::{
+    cache::Cache,
+    dependency::{Defaults, Dependency},
+    remotes::{self, ManifestRemotes, Remote},
+    Args, Resolver, DEFAULT_BRANCH, SOURCE_MANIFESTS_DIR, UTF8_BOM,
+};
+use clap::{CommandFactory, FromArgMatches};
+use reqwest::Client;
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+use tempfile::TempDir;
+use wiremock::{
+    matchers::{method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Remotes of the manifests that most dependencies are on.
+pub const REMOTES_MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+    <remote name="github" fetch="https://github.com" />
+    <remote name="flamingo-devices" fetch="https://github.com/FlamingoOS-Devices" revision="A13" />
+    <default revision="A13" remote="github" />
+</manifest>
+"#;
+
+/// Mock of the GitHub API and of the raw url dependency files are
+/// fetched from. Anything it was not told about responds with 404,
+/// like a repository without a dependency file does.
+pub struct MockHost {
+    pub server: MockServer,
+}
+
+impl MockHost {
+    pub async fn start() -> Self {
+        Self {
+            server: MockServer::start().await,
+        }
+    }
+
+    pub fn uri(&self) -> String {
+        self.server.uri()
+    }
+
+    /// Serves the repositories as the listing of the organization on
+    /// GitHub, all on the first page.
+    pub async fn mock_org_repos(&self, org: &str, repos: &[&str]) {
+        let listing = repos
+            .iter()
+            .map(|name| format!(r#"{{"name": "{name}"}}"#))
+            .collect::<Vec<String>>()
+            .join(", ");
+        self.mock_org_repos_page(org, 1, &format!("[{listing}]"))
+            .await;
+        self.mock_org_repos_page(org, 2, "[]").await;
+    }
+
+    /// Serves the body as a page of the listing of the organization.
+    pub async fn mock_org_repos_page(&self, org: &str, page: u32, body: &str) {
+        Mock::given(method("GET"))
+            .and(path(format!("/orgs/{org}/repos")))
+            .and(query_param("page", page.to_string()))
+            .respond_with(json_response(body))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Serves the json dependency file of the repository (owner/name)
+    /// at branch on the raw url.
+    pub async fn mock_dependency_file(&self, repo: &str, branch: &str, content: &str) {
+        self.mock_file(
+            &format!("/{repo}/{branch}/flamingo.dependencies"),
+            ResponseTemplate::new(200).set_body_string(content),
+        )
+        .await;
+    }
+
+    /// Responds to GET requests for the path with the response.
+    pub async fn mock_file(&self, file_path: &str, response: ResponseTemplate) {
+        Mock::given(method("GET"))
+            .and(path(file_path))
+            .respond_with(response)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Paths of the requests received so far, in order.
+    pub async fn requested_paths(&self) -> Vec<String> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|request| request.url.path().to_owned())
+            .collect()
+    }
+}
+
+pub fn json_response(body: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body.to_owned(), "application/json")
+}
+
+/// Manifest root with the manifest written to its manifests dir,
+/// and to manifest.xml so that it is included.
+pub fn manifest_root(manifest: &str) -> TempDir {
+    let root = TempDir::new().unwrap();
+    write_manifest(root.path(), "default.xml", manifest);
+    fs::write(
+        root.path().join("manifest.xml"),
+        r#"<manifest><include name="default.xml" /></manifest>"#,
+    )
+    .unwrap();
+    root
+}
+
+/// Writes the manifest to the manifests dir of the root, name
+/// may be in a subdir of it.
+pub fn write_manifest(root: &Path, name: &str, manifest: &str) {
+    let file = root.join(SOURCE_MANIFESTS_DIR).join(name);
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(file, manifest.trim_start_matches(UTF8_BOM)).unwrap();
+}
+
+/// Parses the arguments after pointing roomservice at the mock host and
+/// the manifest root, caching nothing and without retrying requests.
+pub fn args(host: &MockHost, root: &TempDir, extra: &[&str]) -> Args {
+    let uri = host.uri();
+    let root = root.path().to_str().unwrap();
+    let mut argv = vec![
+        "roomservice",
+        "--manifest-root",
+        root,
+        "--github-api-url",
+        &uri,
+        "--github-raw-url",
+        &uri,
+        "--gitlab-url",
+        &uri,
+        "--no-cache",
+        "--max-attempts",
+        "1",
+    ];
+    argv.extend_from_slice(extra);
+    let matches = Args::command().try_get_matches_from(argv).unwrap();
+    Args::from_arg_matches(&matches).unwrap()
+}
+
+/// Everything a [`Resolver`] borrows, read from the arguments the same way
+/// main does. The maps and the cache can be filled in before resolving.
+pub struct Context {
+    pub args: Args,
+    pub client: Client,
+    pub remotes: ManifestRemotes,
+    pub cache: Option<Cache>,
+    pub branch_map: HashMap<String, String>,
+    pub locked_revisions: HashMap<String, String>,
+}
+
+impl Context {
+    pub fn new(args: Args) -> Self {
+        let manifest_dirs: Vec<String> = args
+            .manifest_root
+            .iter()
+            .map(|root| format!("{root}/{SOURCE_MANIFESTS_DIR}"))
+            .collect();
+        Self {
+            remotes: remotes::get_all_remotes(&manifest_dirs, args.lenient).unwrap(),
+            client: crate::http::build_client(Duration::from_secs(5), false).unwrap(),
+            args,
+            cache: None,
+            branch_map: HashMap::new(),
+            locked_revisions: HashMap::new(),
+        }
+    }
+
+    pub fn resolver(&self) -> Resolver<'_> {
+        Resolver::new(
+            &self.args,
+            &self.client,
+            &self.remotes,
+            self.cache.as_ref(),
+            &self.branch_map,
+            &self.locked_revisions,
+        )
+        .unwrap()
+    }
+}
+
+/// Dependency on the flamingo-devices remote at the default branch.
+pub fn dependency(name: &str, path: &str) -> Dependency {
+    Dependency {
+        name: format!("FlamingoOS-Devices/{name}"),
+        path: path.to_owned(),
+        remote: remotes::FLAMINGO_DEVICES.to_owned(),
+        branch: DEFAULT_BRANCH.to_owned(),
+        clone_depth: None,
+        clone_filter: None,
+        upstream: None,
+        groups: None,
+        parent: None,
+    }
+}
+
+/// The remotes of [`REMOTES_MANIFEST`].
+pub fn remotes() -> HashMap<String, Remote> {
+    [
+        remote(remotes::GITHUB, "https://github.com", None),
+        remote(
+            remotes::FLAMINGO_DEVICES,
+            "https://github.com/FlamingoOS-Devices",
+            Some(DEFAULT_BRANCH),
+        ),
+    ]
+    .into_iter()
+    .map(|remote| (remote.name.to_owned(), remote))
+    .collect()
+}
+
+pub fn remote(name: &str, fetch: &str, revision: Option<&str>) -> Remote {
+    Remote {
+        name: name.to_owned(),
+        fetch: fetch.to_owned(),
+        revision: revision.map(|revision| revision.to_owned()),
+        manifest: String::from("default.xml"),
+        manifest_url: None,
+    }
+}
+
+/// Defaults of a run without any of the arguments that change them.
+pub fn defaults() -> Defaults<'static> {
+    Defaults {
+        remote: remotes::FLAMINGO_DEVICES,
+        owner: None,
+        github_branch: None,
+        revision: None,
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * Copyright (C) 2022 FlamingoOS Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::{
+    apply_branch_map, apply_locked_revision,
+    dependency::{self, Dependency, RemovedProject},
+    deps_format::DepsFormat,
+    fetch_dependency_file, get_device_dependency, get_device_repo_codename, schema, Args, Resolver,
+};
+use async_recursion::async_recursion;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Component, Path},
+};
+
+/// Everything found while walking the tree.
+#[derive(Default)]
+struct State {
+    /// Repositories and branches whose dependency file was checked
+    visited: HashSet<(String, String)>,
+    /// Repository every path is declared as, with the source declaring it
+    paths: HashMap<String, (String, String)>,
+    /// Problems found, with the source they were found in
+    problems: Vec<(String, String)>,
+}
+
+impl State {
+    fn report(&mut self, source: &str, problem: String) {
+        self.problems.push((source.to_owned(), problem));
+    }
+}
+
+/// Checks the dependency files of the whole tree, starting from the local
+/// file if passed and from the dependency file of the devices otherwise.
+/// Fails if any problem was found, after printing all of them.
+pub async fn run(resolver: &Resolver<'_>, file: Option<&str>, args: &Args) -> Result<(), String> {
+    let problems = find_problems(resolver, file, args).await?;
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    problems
+        .iter()
+        .for_each(|(source, problem)| println!("{source}: {problem}"));
+    Err(format!("Found {} problems", problems.len()))
+}
+
+/// Problems found in the tree, with the source they were found in.
+async fn find_problems(
+    resolver: &Resolver<'_>,
+    file: Option<&str>,
+    args: &Args,
+) -> Result<Vec<(String, String)>, String> {
+    let mut state = State::default();
+    match file {
+        Some(file) => {
+            let content = fs::read_to_string(file)
+                .map_err(|err| format!("Failed to read dependency file {file}: {err}"))?;
+            let format = DepsFormat::from_path(file).unwrap_or(resolver.deps_format);
+            check_dependency_file(resolver, file, &content, format, &mut state).await;
+        }
+        None => {
+            let device_names = match args.device_repo.as_ref() {
+                Some(device_repo) => vec![get_device_repo_codename(device_repo)?],
+                None => args.device_name.clone(),
+            };
+            if device_names.is_empty() {
+                return Err(String::from(
+                    "Pass a device name, --device-repo or --file to validate",
+                ));
+            }
+            for device_name in &device_names {
+                match get_device_dependency(resolver, device_name, args).await {
+                    Ok(dependency) => check_dependency(resolver, &dependency, &mut state).await,
+                    Err(err) => state.report(device_name, err),
+                }
+            }
+        }
+    }
+    Ok(state.problems)
+}
+
+/// Checks the dependency file of the dependency, if it has one.
+#[async_recursion]
+async fn check_dependency(resolver: &Resolver<'_>, dependency: &Dependency, state: &mut State) {
+    let key = (dependency.name.to_owned(), dependency.branch.to_owned());
+    if !state.visited.insert(key) {
+        return;
+    }
+    match fetch_dependency_file(resolver, dependency).await {
+        Ok(Some(content)) => {
+            let format = resolver.deps_format;
+            check_dependency_file(resolver, &dependency.name, &content, format, state).await
+        }
+        Ok(None) => {}
+        Err(err) => state.report(&dependency.name, err),
+    }
+}
+
+#[async_recursion]
+async fn check_dependency_file(
+    resolver: &Resolver<'_>,
+    source: &str,
+    content: &str,
+    format: DepsFormat,
+    state: &mut State,
+) {
+    let deps = match format.parse(content) {
+        Ok(deps) => deps,
+        Err(err) => return state.report(source, format!("not valid {format}: {err}")),
+    };
+    let violations = schema::validate(&deps);
+    if !violations.is_empty() {
+        // Entries cannot be parsed reliably, so only the violations are reported
+        violations
+            .into_iter()
+            .for_each(|violation| state.report(source, violation));
+        return;
+    }
+    let mut file_paths = HashSet::new();
+    for repo in deps.members() {
+        if !Dependency::matches_variant(repo, resolver.variant) {
+            continue;
+        }
+        let path = repo[dependency::DEPS_KEY_PATH].as_str().unwrap_or_default();
+        if let Some(problem) = check_path(path) {
+            state.report(source, problem);
+        }
+        // A removed path is usually declared again right after
+        if Dependency::is_removal(repo) {
+            if let Err(err) = RemovedProject::get(repo) {
+                state.report(source, format!("{path}: {err}"));
+            }
+            continue;
+        }
+        if !file_paths.insert(path.to_owned()) {
+            state.report(source, format!("{path} is declared more than once"));
+            continue;
+        }
+        let mut dependency =
+            match Dependency::get(repo.clone(), resolver.remotes, &resolver.defaults) {
+                Ok(dependency) => dependency,
+                Err(err) => {
+                    state.report(source, format!("{path}: {err}"));
+                    continue;
+                }
+            };
+        apply_branch_map(resolver, &mut dependency);
+        apply_locked_revision(resolver, &mut dependency);
+        match state.paths.get(path) {
+            Some((name, other_source)) if *name != dependency.name => {
+                let problem = format!(
+                    "{path} is declared as {}, but {other_source} declares it as {name}",
+                    dependency.name
+                );
+                state.report(source, problem);
+                continue;
+            }
+            Some(_) => {}
+            None => {
+                state.paths.insert(
+                    path.to_owned(),
+                    (dependency.name.to_owned(), source.to_owned()),
+                );
+            }
+        }
+        check_dependency(resolver, &dependency, state).await;
+    }
+}
+
+/// Problem with the target path, if it is empty or points outside of the source tree.
+fn check_path(path: &str) -> Option<String> {
+    if path.is_empty() {
+        return Some(String::from("target_path is empty"));
+    }
+    let is_outside = Path::new(path)
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    is_outside.then(|| format!("target_path {path} points outside of the source tree"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, Context, MockHost},
+        DEFAULT_BRANCH, ORG,
+    };
+
+    #[tokio::test]
+    async fn every_seeded_problem_is_reported() {
+        let host = MockHost::start().await;
+        let files = [
+            (
+                "device_xiaomi_lmi",
+                r#"[
+                    {"repository": "device_xiaomi_sm8250-common", "target_path": "device/xiaomi/sm8250-common"},
+                    {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"},
+                    {"repository": "vendor_xiaomi_lmi", "target_path": "vendor/xiaomi/lmi"},
+                    {"repository": "hardware_xiaomi", "target_path": "../hardware/xiaomi"},
+                    {"repository": "kernel_xiaomi_sm8250", "target_path": "kernel/xiaomi/sm8250", "remote": "nonexistent"}
+                ]"#,
+            ),
+            (
+                "device_xiaomi_sm8250-common",
+                r#"[{"repository": "vendor_xiaomi_sm8250-common", "target_path": "vendor/xiaomi/lmi"}]"#,
+            ),
+            ("hardware_xiaomi", "not json"),
+        ];
+        for (repo, content) in files {
+            host.mock_dependency_file(&format!("{ORG}/{repo}"), DEFAULT_BRANCH, content)
+                .await;
+        }
+        let root = testing::manifest_root(testing::REMOTES_MANIFEST);
+        let device_repo = format!("{ORG}/device_xiaomi_lmi");
+        let context = Context::new(testing::args(
+            &host,
+            &root,
+            &["--device-repo", &device_repo, "validate"],
+        ));
+
+        let problems = find_problems(&context.resolver(), None, &context.args)
+            .await
+            .unwrap();
+
+        let device = "FlamingoOS-Devices/device_xiaomi_lmi";
+        let problems: Vec<(&str, &str)> = problems
+            .iter()
+            .map(|(source, problem)| (source.as_str(), problem.as_str()))
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                (
+                    device,
+                    "vendor/xiaomi/lmi is declared as FlamingoOS-Devices/vendor_xiaomi_lmi, but FlamingoOS-Devices/device_xiaomi_sm8250-common declares it as FlamingoOS-Devices/vendor_xiaomi_sm8250-common"
+                ),
+                (device, "vendor/xiaomi/lmi is declared more than once"),
+                (
+                    device,
+                    "target_path ../hardware/xiaomi points outside of the source tree"
+                ),
+                (
+                    "FlamingoOS-Devices/hardware_xiaomi",
+                    "not valid JSON: Unexpected character: o at (1:2)"
+                ),
+                (
+                    device,
+                    "kernel/xiaomi/sm8250: No such remote exists with the name nonexistent"
+                ),
+            ]
+        );
+    }
+}